@@ -0,0 +1,20 @@
+/*
+ * debug.h
+ * Purpose: Stand-in for kilolib's debug.h. After debug_init(), printf writes to the bot's debug
+ * serial line, which the simulator captures with the tick and uid of the bot. See src/ffi.rs
+ *
+ */
+#ifndef DEBUG_H
+#define DEBUG_H
+
+#include <stdio.h>
+
+/* Longest line printf can write at once, the rest is cut off */
+#define DEBUG_LINE_LEN 128
+
+void debug_init(void);
+int kilo_debug_printf(const char *format, ...);
+
+#define printf kilo_debug_printf
+
+#endif
@@ -0,0 +1,65 @@
+/*
+ * kilolib.h
+ * Purpose: Stand-in for the kilolib header, for building kilobot programs to run in the simulator
+ *
+ * Compile a program together with kilolib_shim.c into a shared library, e.g.
+ *
+ *      cc -shared -fPIC -I ffi -o blinky.so blinky.c ffi/kilolib_shim.c
+ *
+ * and use the library's path as the program of a scenario. See src/ffi.rs
+ *
+ */
+#ifndef KILOLIB_H
+#define KILOLIB_H
+
+#include <stdint.h>
+#include <stddef.h>
+
+#define RGB(r,g,b) (r&3)|(((g&3)<<2))|((b&3)<<4)
+#define TICKS_PER_SEC 32
+
+typedef enum {
+    NORMAL = 0,
+    GPS,
+} message_type_t;
+
+typedef struct __attribute__((__packed__)) {
+    uint8_t data[9];
+    uint8_t type;
+    uint16_t crc;
+} message_t;
+
+/* The simulator measures distances directly, low_gain holds the distance in mm */
+typedef struct {
+    int16_t low_gain;
+    int16_t high_gain;
+} distance_measurement_t;
+
+typedef void (*message_rx_t)(message_t *, distance_measurement_t *d);
+typedef message_t *(*message_tx_t)(void);
+typedef void (*message_tx_success_t)(void);
+
+extern volatile uint32_t kilo_ticks;
+extern uint16_t kilo_uid;
+extern uint8_t kilo_turn_left;
+extern uint8_t kilo_turn_right;
+extern uint8_t kilo_straight_left;
+extern uint8_t kilo_straight_right;
+extern message_rx_t kilo_message_rx;
+extern message_tx_t kilo_message_tx;
+extern message_tx_success_t kilo_message_tx_success;
+
+void kilo_init(void);
+void kilo_start(void (*setup)(void), void (*loop)(void));
+void delay(uint16_t ms);
+uint8_t rand_hard(void);
+uint8_t rand_soft(void);
+void rand_seed(uint8_t seed);
+int16_t get_ambientlight(void);
+void set_motors(uint8_t left, uint8_t right);
+void spinup_motors(void);
+void set_color(uint8_t color);
+uint8_t estimate_distance(const distance_measurement_t *d);
+uint16_t message_crc(const message_t *msg);
+
+#endif
@@ -0,0 +1,161 @@
+/*
+ * kilolib_shim.c
+ * Purpose: kilolib for programs running in the simulator. Hardware calls are forwarded to the
+ * functions the simulator registers with kilo_shim_init, and the simulator drives the program
+ * through the kilo_shim_ entry points instead of kilo_start looping forever
+ *
+ * Every bot loads its own copy of the library, so these globals and the program's are per bot
+ *
+ */
+#include <stdarg.h>
+#include "kilolib.h"
+#include "debug.h"
+#include "pheromone.h"
+
+/* Functions provided by the simulator, must match Host in src/ffi.rs */
+typedef struct {
+    void (*set_motors)(uint8_t left, uint8_t right);
+    void (*set_color)(uint8_t color);
+    void (*delay)(uint16_t ms);
+    uint16_t (*uid)(void);
+    uint32_t (*ticks)(void);
+    int16_t (*ambientlight)(void);
+    uint8_t (*rand_soft)(void);
+    void (*rand_seed)(uint8_t seed);
+    void (*debug_print)(const char *text);
+    int16_t (*pheromone)(void);
+    void (*deposit_pheromone)(uint16_t amount);
+} kilo_host_t;
+
+static kilo_host_t host;
+static void (*user_setup)(void);
+static void (*user_loop)(void);
+
+static void rx_dummy(message_t *m, distance_measurement_t *d) { (void)m; (void)d; }
+static message_t *tx_dummy(void) { return NULL; }
+static void tx_success_dummy(void) {}
+
+volatile uint32_t kilo_ticks;
+uint16_t kilo_uid;
+/* Simulated bots don't need calibrating, same as src/kilolib.rs */
+uint8_t kilo_turn_left = 255;
+uint8_t kilo_turn_right = 255;
+uint8_t kilo_straight_left = 255;
+uint8_t kilo_straight_right = 255;
+message_rx_t kilo_message_rx = rx_dummy;
+message_tx_t kilo_message_tx = tx_dummy;
+message_tx_success_t kilo_message_tx_success = tx_success_dummy;
+
+static void sync_globals(void)
+{
+    kilo_uid = host.uid();
+    kilo_ticks = host.ticks();
+}
+
+void kilo_init(void)
+{
+    kilo_message_rx = rx_dummy;
+    kilo_message_tx = tx_dummy;
+    kilo_message_tx_success = tx_success_dummy;
+}
+
+/* Returns rather than looping, the simulator calls setup and loop */
+void kilo_start(void (*setup)(void), void (*loop)(void))
+{
+    user_setup = setup;
+    user_loop = loop;
+}
+
+void delay(uint16_t ms)
+{
+    host.delay(ms);
+    kilo_ticks = host.ticks();
+}
+
+uint8_t rand_hard(void) { return host.rand_soft(); }
+uint8_t rand_soft(void) { return host.rand_soft(); }
+void rand_seed(uint8_t seed) { host.rand_seed(seed); }
+int16_t get_ambientlight(void) { return host.ambientlight(); }
+int16_t get_pheromone(void) { return host.pheromone(); }
+void deposit_pheromone(uint16_t amount) { host.deposit_pheromone(amount); }
+void set_motors(uint8_t left, uint8_t right) { host.set_motors(left, right); }
+void set_color(uint8_t color) { host.set_color(color); }
+
+void spinup_motors(void)
+{
+    set_motors(255, 255);
+    delay(15);
+}
+
+/* The serial line is always ready in the simulator */
+void debug_init(void) {}
+
+int kilo_debug_printf(const char *format, ...)
+{
+    char text[DEBUG_LINE_LEN];
+    va_list args;
+    va_start(args, format);
+    int len = vsnprintf(text, sizeof(text), format, args);
+    va_end(args);
+    host.debug_print(text);
+    return len;
+}
+
+uint8_t estimate_distance(const distance_measurement_t *d)
+{
+    return d->low_gain > 255 ? 255 : (uint8_t)d->low_gain;
+}
+
+/* CRC-CCITT of the payload and type, like _crc_ccitt_update in avr-libc */
+uint16_t message_crc(const message_t *msg)
+{
+    const uint8_t *bytes = (const uint8_t *)msg;
+    uint16_t crc = 0xffff;
+    for (int i = 0; i < 10; i++)
+    {
+        uint8_t data = bytes[i] ^ (crc & 0xff);
+        data ^= data << 4;
+        crc = ((((uint16_t)data << 8) | (crc >> 8)) ^ (uint8_t)(data >> 4) ^ ((uint16_t)data << 3));
+    }
+    return crc;
+}
+
+/* Entry points for the simulator */
+
+void kilo_shim_init(const kilo_host_t *h)
+{
+    host = *h;
+}
+
+void kilo_shim_setup(void)
+{
+    sync_globals();
+    if (user_setup)
+        user_setup();
+}
+
+void kilo_shim_loop(void)
+{
+    sync_globals();
+    if (user_loop)
+        user_loop();
+}
+
+message_t *kilo_shim_message_tx(void)
+{
+    sync_globals();
+    return kilo_message_tx();
+}
+
+void kilo_shim_message_tx_success(void)
+{
+    sync_globals();
+    kilo_message_tx_success();
+}
+
+void kilo_shim_message_rx(message_t *msg, distance_measurement_t *d)
+{
+    sync_globals();
+    msg->crc = message_crc(msg);
+    kilo_message_rx(msg, d);
+}
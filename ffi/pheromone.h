@@ -0,0 +1,17 @@
+/*
+ * pheromone.h
+ * Purpose: Virtual pheromone, which only exists in the simulator and has no kilolib equivalent.
+ * See src/board/pheromone_map.rs
+ *
+ */
+#ifndef PHEROMONE_H
+#define PHEROMONE_H
+
+#include <stdint.h>
+
+/* Level of pheromone on the space under the bot, from 0 up to 1023 */
+int16_t get_pheromone(void);
+/* Deposit pheromone on the space under the bot, it can be sensed from the next tick */
+void deposit_pheromone(uint16_t amount);
+
+#endif
@@ -0,0 +1,50 @@
+-- Hop count gradient, the Lua version of src/programs/gradient.rs. Every bot takes the smallest
+-- value it hears plus one and broadcasts that, the seed runs gradient_seed.lua. See
+-- scenarios/lua_gradient.txt
+
+GRADIENT_MAX = 65535
+-- Ticks without hearing a value that supports our own before forgetting it
+ANCHOR_TIMEOUT = 64
+COLOURS = { {255, 0, 0}, {255, 255, 0}, {0, 255, 0}, {0, 255, 255}, {0, 0, 255}, {255, 0, 255} }
+
+store.gradient = GRADIENT_MAX
+local last_anchored = 0
+local received = nil
+
+function loop()
+    if ticks() > last_anchored + ANCHOR_TIMEOUT then
+        store.gradient = GRADIENT_MAX
+    end
+    if received ~= nil then
+        if received + 1 <= store.gradient then
+            store.gradient = received + 1
+            last_anchored = ticks()
+        end
+        received = nil
+    end
+
+    if store.gradient == GRADIENT_MAX then
+        set_led(0, 0, 0)
+    else
+        local c = COLOURS[store.gradient % #COLOURS + 1]
+        set_led(c[1], c[2], c[3])
+    end
+end
+
+function message_tx()
+    if store.gradient == GRADIENT_MAX then
+        return nil
+    end
+    return { store.gradient % 256, store.gradient // 256 }
+end
+
+function on_message(msg, dist, msg_type)
+    -- Only other bots' messages carry a gradient
+    if msg_type ~= 0 then
+        return
+    end
+    local value = msg[1] + msg[2] * 256
+    if received == nil or value < received then
+        received = value
+    end
+end
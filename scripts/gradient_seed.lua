@@ -0,0 +1,11 @@
+-- Seed of the hop count gradient in gradient.lua, broadcasts a value of 0 and shows red
+
+store.gradient = 0
+
+function setup()
+    set_led(255, 0, 0)
+end
+
+function message_tx()
+    return { 0, 0 }
+end
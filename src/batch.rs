@@ -0,0 +1,191 @@
+/*
+ * batch
+ * Purpose: Run a scenario over every combination of a grid of parameters and summarize each run
+ *          as one row of a CSV file
+ *
+ * A parameter is given as key=values, where values is a comma separated list and may contain
+ * integer ranges, e.g. seed=0..9 or bots=10,50,100. Every key from the scenario file can be swept.
+ * A run that fails still gets its row, with empty results and the reason in the error column.
+ *
+ */
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Instant;
+use crate::hal::Hal;
+use crate::board_controller::BoardController;
+use crate::metrics::{SwarmMetrics, METRIC_COLUMNS};
+use crate::scenario;
+use crate::scenario::{Scenario, ScenarioError, SCENARIO_KEYS};
+
+/// Names of the summary columns written after the scenario parameters
+const SUMMARY_COLUMNS: [&str; 5] = ["bots_on_board", "messages_sent", "messages_received", "mean_battery_mv", "wall_time_ms"];
+
+/// Parse a parameter given as key=values
+/// # Arguments
+/// * 'arg' - Parameter to parse, e.g. "seed=0..9" or "tx_period=8,16,32"
+/// # Returns
+/// * (key, every value the parameter takes), or ScenarioError if it couldn't be parsed
+pub fn parse_param(arg: &str) -> Result<(String, Vec<String>), ScenarioError>
+{
+    let (key, values) = arg.split_once('=')
+        .ok_or_else(|| ScenarioError::Parse(format!("expected key=values, got '{}'", arg)))?;
+    let mut expanded = Vec::new();
+    for value in values.split(',').map(|v| v.trim()).filter(|v| !v.is_empty())
+    {
+        match value.split_once("..")
+        {
+            Some((start, end)) => {
+                let range_err = || ScenarioError::Parse(format!("invalid range '{}'", value));
+                let start: i64 = start.parse().map_err(|_| range_err())?;
+                let end: i64 = end.parse().map_err(|_| range_err())?;
+                expanded.extend((start..=end).map(|v| v.to_string()));
+            },
+            None => expanded.push(value.to_string()),
+        }
+    }
+    if !SCENARIO_KEYS.contains(&key) || expanded.is_empty()
+    {
+        return Err(ScenarioError::Parse(format!("invalid parameter '{}'", arg)));
+    }
+    Ok((key.to_string(), expanded))
+}
+
+/// Run every combination of the parameter grid and write one CSV row per run
+/// Runs are spread over worker threads but rows are always written in the same order
+/// # Arguments
+/// * 'base' - Scenario every run starts from
+/// * 'grid' - (key, values) of every swept parameter
+/// * 'threads' - Number of runs to do at once
+/// * 'out' - Where to write the CSV
+pub fn run_batch<W: Write>(base: &Scenario, grid: &[(String, Vec<String>)], threads: usize, out: &mut W) -> Result<(), ScenarioError>
+{
+    // Build every scenario up front so a bad value is reported before hours of runs
+    let mut runs = vec![base.clone()];
+    for (key, values) in grid
+    {
+        let mut next = Vec::with_capacity(runs.len() * values.len());
+        for run in &runs
+        {
+            for value in values
+            {
+                let mut scenario = run.clone();
+                scenario.set(key, value)?;
+                next.push(scenario);
+            }
+        }
+        runs = next;
+    }
+    for run in &runs
+    {
+        let names: BTreeSet<&str> = run.bot_programs()?.into_iter().collect();
+        for name in names
+        {
+            scenario::load_program(name)?;
+        }
+    }
+
+    let mut header: Vec<&str> = vec!["run"];
+    header.extend(SCENARIO_KEYS.iter());
+    header.extend(SUMMARY_COLUMNS.iter());
+    header.extend(METRIC_COLUMNS.iter());
+    header.push("error");
+    writeln!(out, "{}", header.join(",")).map_err(ScenarioError::Io)?;
+
+    let next_run = AtomicUsize::new(0);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| -> Result<(), ScenarioError> {
+        for _ in 0..threads.max(1).min(runs.len())
+        {
+            let sender = sender.clone();
+            let (runs, next_run) = (&runs, &next_run);
+            scope.spawn(move || loop {
+                let i = next_run.fetch_add(1, Ordering::SeqCst);
+                if i >= runs.len() || sender.send((i, run_one(&runs[i]))).is_err()
+                {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        // Hold on to rows that finish early until every row before them has been written
+        let mut finished = BTreeMap::new();
+        let mut next_row = 0;
+        for (i, result) in receiver
+        {
+            finished.insert(i, result);
+            while let Some(result) = finished.remove(&next_row)
+            {
+                let mut row = vec![next_row.to_string()];
+                row.extend(SCENARIO_KEYS.iter().map(|k| csv_field(&runs[next_row].get(k).unwrap())));
+                match result
+                {
+                    Ok(summary) => {
+                        row.extend(summary);
+                        row.push(String::new());
+                    },
+                    Err(e) => {
+                        eprintln!("run {} failed: {}", next_row, e);
+                        row.extend(vec![String::new(); SUMMARY_COLUMNS.len() + METRIC_COLUMNS.len()]);
+                        row.push(csv_field(&e.to_string()));
+                    },
+                }
+                writeln!(out, "{}", row.join(",")).map_err(ScenarioError::Io)?;
+                next_row += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Run a single scenario to completion
+/// # Returns
+/// * The summary columns of the run followed by the swarm metrics of its final state
+fn run_one(scenario: &Scenario) -> Result<Vec<String>, ScenarioError>
+{
+    let start = Instant::now();
+    let mut controller = scenario.build()?;
+    for _ in 0..scenario.ticks
+    {
+        controller.step();
+    }
+    let mut summary = summarize(&controller);
+    summary.push(format!("{:.1}", start.elapsed().as_secs_f64() * 1e3));
+    summary.extend(SwarmMetrics::compute(&controller.board).to_csv_row());
+    Ok(summary)
+}
+
+/// Summarize the final state of a run. Matches SUMMARY_COLUMNS, except for the wall time
+fn summarize(controller: &BoardController) -> Vec<String>
+{
+    let board = &controller.board;
+    let (mut sent, mut received, mut battery) = (0_u64, 0_u64, 0_u64);
+    let indices = board.get_occupied_indices();
+    for &index in &indices
+    {
+        let bot = board.get_bot_at_index(index).ok().unwrap();
+        sent += bot.transceiver().get_tx_count() as u64;
+        received += bot.transceiver().get_rx_count() as u64;
+        battery += bot.get_battery_voltage() as u64;
+    }
+    vec![
+        indices.len().to_string(),
+        sent.to_string(),
+        received.to_string(),
+        format!("{:.1}", battery as f64 / indices.len().max(1) as f64),
+    ]
+}
+
+/// Quote a CSV field if it contains anything that would break the row
+fn csv_field(value: &str) -> String
+{
+    if value.contains(',') || value.contains('"') || value.contains('\n')
+    {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
@@ -0,0 +1,69 @@
+/*
+ * bench
+ * Purpose: Rough benchmarks of how the simulation scales with swarm size. Run with
+ *          cargo run --release -- bench
+ *
+ */
+use std::time::Instant;
+use crate::hal::Hal;
+use crate::board::board_map::BoardMap;
+use crate::scenario::Scenario;
+
+/// Swarm sizes to benchmark
+const SWARM_SIZES: [usize; 4] = [100, 1_000, 5_000, 10_000];
+/// Spaces on the board per bot, i.e. the board is kept at 10% density
+const SPACES_PER_BOT: usize = 10;
+/// Number of ticks to time
+const BENCH_TICKS: u32 = 100;
+
+/// Run the benchmarks and print a table of the results
+pub fn run()
+{
+    println!("{:>7} {:>9} {:>10} {:>10} {:>13} {:>13}",
+             "bots", "board", "setup ms", "tick ms", "index us/q", "scan us/q");
+    for &num_bots in SWARM_SIZES.iter()
+    {
+        let side = ((num_bots * SPACES_PER_BOT) as f64).sqrt().ceil() as usize;
+
+        let start = Instant::now();
+        let scenario = Scenario { width: side, height: side, bots: num_bots, ..Scenario::default() };
+        let mut controller = scenario.build().ok().unwrap();
+        let setup_ms = start.elapsed().as_secs_f64() * 1e3;
+
+        let start = Instant::now();
+        for _ in 0..BENCH_TICKS
+        {
+            controller.step();
+        }
+        let tick_ms = start.elapsed().as_secs_f64() * 1e3 / BENCH_TICKS as f64;
+
+        // Neighbour lookup of every bot through the spatial index
+        let board = &controller.board;
+        let indices = board.get_occupied_indices();
+        let start = Instant::now();
+        let mut found = 0;
+        for &index in &indices
+        {
+            let uid = board.get_bot_at_index(index).ok().unwrap().get_uid();
+            found += board.get_bots_hearing(uid).ok().unwrap().len();
+        }
+        let index_us = start.elapsed().as_secs_f64() * 1e6 / indices.len() as f64;
+
+        // The same lookup done by checking the distance to every other bot
+        let coords: Vec<_> = indices.iter().map(|i| board.get_coord_from_index(i).ok().unwrap()).collect();
+        let radius = board.get_bot_at_index(indices[0]).ok().unwrap().transceiver().get_tx_radius();
+        let start = Instant::now();
+        let mut scanned = 0;
+        for (i, coord) in coords.iter().enumerate()
+        {
+            scanned += coords.iter().enumerate()
+                .filter(|(j, other)| *j != i && coord.distance_to(other) <= radius)
+                .count();
+        }
+        let scan_us = start.elapsed().as_secs_f64() * 1e6 / indices.len() as f64;
+        assert_eq!(found, scanned);
+
+        println!("{:>7} {:>9} {:>10.1} {:>10.3} {:>13.2} {:>13.2}",
+                 num_bots, format!("{}x{}", side, side), setup_ms, tick_ms, index_us, scan_us);
+    }
+}
@@ -1,17 +1,39 @@
 pub(crate) mod board_map;
 pub mod bot_map;
+pub mod environment;
+pub mod pheromone_map;
 pub(crate) mod signal_map;
+pub(crate) mod spatial_index;
 
+use std::any::Any;
 use std::fmt;
+use crate::hal::Hal;
 use crate::board::bot_map::{BotMap, BotLocation};
-use crate::board::signal_map::SignalMap;
+use crate::board::environment::Environment;
+use crate::board::pheromone_map::PheromoneMap;
+use crate::board::signal_map::{SignalMap, SignalSource};
+use crate::board::spatial_index::SpatialIndex;
 use crate::kilobot::Kilobot;
 use crate::board::board_map::BoardMap;
 
+/// Facings of the compass points, in degrees clockwise from north
+#[allow(dead_code)]
 pub const NORTH: u16 = 0;
+#[allow(dead_code)]
 pub const EAST: u16 = 90;
+#[allow(dead_code)]
 pub const SOUTH: u16 = 180;
+#[allow(dead_code)]
 pub const WEST: u16 = 270;
+/// Size of one space on the board in mm. A space holds exactly one kilobot, which is 33mm across
+pub const CELL_SIZE_MM: u16 = 33;
+/// Width and height of a spatial index bucket, in spaces. About the default broadcast radius
+const SPATIAL_BUCKET_SIZE: usize = 4;
+/// Ambient light reading right under the light, the largest value of kilolib's 10 bit
+/// get_ambientlight()
+pub const AMBIENT_LIGHT_MAX: u16 = 1023;
+/// Distance from the light in board units at which the ambient light reading has halved
+const LIGHT_HALF_DISTANCE: f64 = 10.0;
 
 /// Basic error tpe that encompasses errors that can occur related to the board.
 /// Doesn't carry any sort of message
@@ -19,6 +41,8 @@ pub enum LocationError {
     AlreadyOccupied,
     NotOccupied,
     OutOfBounds,
+    /// A bot with the same uid is already on the board
+    DuplicateUid,
 }
 
 /// Struct representing an (x,y) coordinate on a 2D plane
@@ -42,6 +66,7 @@ impl CoordinatePair
     }
 
     /// Return the CoordinatePair as a tuple of u8
+    #[allow(dead_code)]
     pub fn as_u8_tuple(&self) -> (u8, u8)
     {
         (self.x as u8, self.y as u8)
@@ -53,6 +78,23 @@ impl CoordinatePair
         (self.x as f64, self.y as f64)
     }
 
+    /// Return the position of the CoordinatePair on the board in mm, the frame localizing programs
+    /// and GPS messages use
+    pub fn as_mm_tuple(&self) -> (f64, f64)
+    {
+        (self.x as f64 * CELL_SIZE_MM as f64, self.y as f64 * CELL_SIZE_MM as f64)
+    }
+
+    /// Get the straight line distance to another coordinate, in board units
+    /// # Arguments
+    /// * 'other' - Coordinate to measure the distance to
+    pub fn distance_to(&self, other: &CoordinatePair) -> f64
+    {
+        let dx = self.x as f64 - other.x as f64;
+        let dy = self.y as f64 - other.y as f64;
+        (dx * dx + dy * dy).sqrt()
+    }
+
     /// Clone the coordinate pair - just copies the x and y values
     pub fn clone(&self) -> CoordinatePair
     {
@@ -60,12 +102,27 @@ impl CoordinatePair
     }
 }
 
+/// The board the kilobots live on. Every bot on the bot map carries its own signal source on the
+/// signal map, and the two are only ever changed together so they can't get out of sync
+/// # Fields
+/// * 'width' - Width of the board
+/// * 'height' - Height of the board
+/// * 'bot_map' - Location of every bot
+/// * 'signal_map' - Coverage of every bot's transmitter
+/// * 'spatial_index' - Position of every bot by uid, for fast neighbour lookups
+/// * 'light' - Position of the light above the board, if there is one
+/// * 'environment' - Virtual environment projected onto the board, if there is one
+/// * 'pheromone_map' - Level of virtual pheromone on every space
 pub struct Board
 {
     width: usize,
     height: usize,
-    pub bot_map: BotMap,
-    pub signal_map: SignalMap,
+    bot_map: BotMap,
+    signal_map: SignalMap,
+    spatial_index: SpatialIndex,
+    light: Option<CoordinatePair>,
+    environment: Option<Box<dyn Environment>>,
+    pheromone_map: PheromoneMap,
 }
 
 impl Board
@@ -82,40 +139,149 @@ impl Board
     ///         where '*' represents "None"
     pub fn new(width: usize, height: usize) -> Board
     {
-        Board{width, height, bot_map: BotMap::new(width, height), signal_map: SignalMap::new(width, height) }
+        Board{width, height, bot_map: BotMap::new(width, height), signal_map: SignalMap::new(width, height),
+            spatial_index: SpatialIndex::new(width, height, SPATIAL_BUCKET_SIZE), light: None, environment: None,
+            pheromone_map: PheromoneMap::new(width, height) }
     }
 
     /// Returns the length of the Vector representing the board
     pub fn len(&self) -> usize
     {
-        (self.width * self.height) as usize
+        self.width * self.height
+    }
+
+    /// Get an immutable reference to the bot map
+    pub fn bot_map(&self) -> &BotMap
+    {
+        &self.bot_map
+    }
+
+    /// Get an immutable reference to the signal map
+    pub fn signal_map(&self) -> &SignalMap
+    {
+        &self.signal_map
+    }
+
+    /// Get an immutable reference to the pheromone map
+    pub fn pheromone_map(&self) -> &PheromoneMap
+    {
+        &self.pheromone_map
     }
 
-    /// Add new bot to the board at the given index
+    /// Get a mutable reference to the pheromone map, to deposit pheromone or change how it behaves
+    pub fn pheromone_map_mut(&mut self) -> &mut PheromoneMap
+    {
+        &mut self.pheromone_map
+    }
+
+    /// Place a light above the board, or take it away
+    /// # Arguments
+    /// * 'light' - Point on the board right under the light, or None for no light
+    pub fn set_light(&mut self, light: Option<CoordinatePair>)
+    {
+        self.light = light;
+    }
+
+    /// Get the point on the board right under the light, if there is one
+    pub fn get_light(&self) -> Option<&CoordinatePair>
+    {
+        self.light.as_ref()
+    }
+
+    /// Project a virtual environment onto the board, or take it away
+    /// # Arguments
+    /// * 'environment' - The environment, or None for no environment
+    pub fn set_environment(&mut self, environment: Option<Box<dyn Environment>>)
+    {
+        self.environment = environment;
+    }
+
+    /// Get the virtual environment, to exchange messages with the bots
+    pub fn environment_mut(&mut self) -> Option<&mut (dyn Environment + 'static)>
+    {
+        self.environment.as_deref_mut()
+    }
+
+    /// Get the virtual environment as its concrete type, to inspect its state
+    /// # Returns
+    /// * The environment, or None if there is none or it isn't a T
+    pub fn environment_as<T: Environment>(&self) -> Option<&T>
+    {
+        let environment: &dyn Any = self.environment.as_deref()?;
+        environment.downcast_ref()
+    }
+
+    /// Get the ambient light reading of a bot at the given index. The reading halves every
+    /// LIGHT_HALF_DISTANCE spaces away from the light, as if it were hanging above the board
+    /// # Arguments
+    /// * 'index' - Index on the board
+    /// # Returns
+    /// * A reading from 0 to AMBIENT_LIGHT_MAX, always 0 if there is no light
+    pub fn get_ambient_light_at_index(&self, index: usize) -> u16
+    {
+        match (&self.light, self.get_coord_from_index(&index))
+        {
+            (Some(light), Ok(coord)) => {
+                let d = coord.distance_to(light) / LIGHT_HALF_DISTANCE;
+                (AMBIENT_LIGHT_MAX as f64 / (1.0 + d * d)).round() as u16
+            },
+            _ => 0,
+        }
+    }
+
+    /// Add new bot to the board at the given index, along with its signal source
+    /// Uids must be unique on a board
     /// # Arguments
     /// 'bot' - Kilobot to add to the board
     /// 'index' - Index in vector to place the bot
     /// 'facing' - Direction the bot is initially facing, in degrees clockwise from north
     /// # Returns
     /// None - Insert successful
-    /// LocationError if out of bounds or coordinates already occupied
+    /// LocationError if out of bounds, coordinates already occupied or the uid already taken
     pub fn add_new_bot_at_index(&mut self, bot: Kilobot, index: usize, facing: u16) -> Option<LocationError>
     {
-        self.bot_map.add_new_bot_at_index(bot, index, facing)
+        self.add_bot_location_at_index(BotLocation::new(bot, facing), index)
     }
 
-    /// Adds an existing BotLocation to the given index
+    /// Adds an existing BotLocation to the given index, along with its signal source. Nothing is
+    /// changed if it can't be added
     /// # Arguments
     /// * 'bot_loc' - Existing BotLocation object
     /// * 'index' - Index to insert into
     /// # Returns
-    /// Option<LocationError> if coordinates are out of bounds, or there is already a bot at the coordinates
+    /// Option<LocationError> if coordinates are out of bounds, there is already a bot at the
+    /// coordinates, or a bot with the same uid is already on the board
     pub fn add_bot_location_at_index(&mut self, bot_loc: BotLocation, index: usize) -> Option<LocationError>
     {
-        self.bot_map.add_bot_location_at_index(bot_loc, index)
+        let coord = match self.get_coord_from_index(&index)
+        {
+            Ok(c) => c,
+            Err(e) => return Some(e),
+        };
+        let uid = bot_loc.bot().get_uid();
+        // Bots are found by uid, so a second bot with the same uid would hide the first one
+        if self.spatial_index.get(uid).is_some()
+        {
+            return Some(LocationError::DuplicateUid);
+        }
+        let src = SignalSource::new(uid, coord.clone(), bot_loc.bot().transceiver().get_tx_radius());
+        if let Some(e) = self.bot_map.add_bot_location_at_index(bot_loc, index)
+        {
+            return Some(e);
+        }
+        self.spatial_index.insert(uid, &coord);
+        let result = self.signal_map.add_new_source(src);
+        if result.is_some()
+        {
+            // Keep the maps in sync
+            let _ = self.bot_map.remove_bot_location_at_index(index);
+            self.spatial_index.remove(uid);
+        }
+        result
     }
 
     /// Removes the BotLocation at the specified index if a bot is present there and replaces it with None
+    /// The bot's signal source is removed with it
     /// # Arguments
     /// * 'index' - Index of BotLocation to remove
     /// # Returns
@@ -123,7 +289,65 @@ impl Board
     /// * Err(LocationError) if index is out of bounds or there is no bot in the coordinate
     pub fn remove_bot_location_at_index(&mut self, index: usize) -> Result<Box<BotLocation>,LocationError>
     {
-        self.bot_map.remove_bot_location_at_index(index)
+        let bot_loc = self.bot_map.remove_bot_location_at_index(index)?;
+        let coord = self.get_coord_from_index(&index)?;
+        let _ = self.signal_map.remove_source_at_coord(&coord);
+        self.spatial_index.remove(bot_loc.bot().get_uid());
+        Ok(bot_loc)
+    }
+
+    /// Moves a bot and its signal source to a new index. Only the spaces whose signal coverage
+    /// changes are updated
+    /// # Arguments
+    /// * 'src_index' - Index of the bot to move
+    /// * 'dest_index' - Index to move the bot to
+    /// # Returns
+    /// * Option<LocationError> if either index is out of bounds, there is no bot at the source or
+    ///   the destination already has a bot
+    pub fn move_bot_by_index(&mut self, src_index: usize, dest_index: usize) -> Option<LocationError>
+    {
+        let (src_coord, dest_coord) = match (self.get_coord_from_index(&src_index), self.get_coord_from_index(&dest_index))
+        {
+            (Ok(s), Ok(d)) => (s, d),
+            _ => return Some(LocationError::OutOfBounds),
+        };
+        match self.bot_map.index_is_occupied(dest_index)
+        {
+            Ok(true) => Some(LocationError::AlreadyOccupied),
+            Ok(false) => {
+                match self.bot_map.remove_bot_location_at_index(src_index)
+                {
+                    Ok(b) => {
+                        self.spatial_index.move_to(b.bot().get_uid(), &dest_coord);
+                        self.bot_map.add_bot_location_at_index(*b, dest_index);
+                        self.signal_map.move_source_to_coord(&src_coord, &dest_coord)
+                    },
+                    Err(e) => Some(e),
+                }
+            },
+            Err(e) => Some(e),
+        }
+    }
+
+    /// Update the signal source of the bot at the given index if its transmit radius has changed
+    /// since it was placed, e.g. because its program changed it
+    /// # Arguments
+    /// * 'index' - Index of the bot
+    pub fn refresh_signal_radius(&mut self, index: usize)
+    {
+        if let (Ok(coord), Ok(bot)) = (self.get_coord_from_index(&index), self.bot_map.get_bot_at_index(index))
+        {
+            let radius = bot.transceiver().get_tx_radius();
+            let uid = bot.get_uid();
+            if let Ok(src) = self.signal_map.get_source_at_coord(&coord)
+            {
+                if src.radius != radius
+                {
+                    let _ = self.signal_map.remove_source_at_coord(&coord);
+                    self.signal_map.add_new_source(SignalSource::new(uid, coord, radius));
+                }
+            }
+        }
     }
 
     /// Returns an immutable reference to the bot at given coordinates, or LocationError if none
@@ -137,17 +361,119 @@ impl Board
         self.bot_map.get_bot_at_index(index)
     }
 
+    /// Returns a mutable reference to the bot at given coordinates, or LocationError if none
+    /// # Arguments
+    /// * 'index' - Index of location in board array
+    /// # Returns
+    /// * Ok - Mutable reference to a Kilobot
+    /// * Err - LocationError if no bot is found, or out of bounds
+    #[allow(dead_code)]
+    pub fn get_mut_bot_at_index(&mut self, index: usize) -> Result<&mut Kilobot, LocationError>
+    {
+        Ok(self.bot_map.get_mut_bot_location_at_index(index)?.bot_mut())
+    }
+
     /// Gets the BotLocation at the given index
     /// # Arguments
     /// * 'index' - Index to get BotLocation from
     /// # Returns
     /// * Ok - Reference to BotLocation at given index
-    /// LocationError if None or Out of Bounds
+    ///   LocationError if None or Out of Bounds
     pub fn get_bot_location_at_index(&self, index: usize) -> Result<&BotLocation, LocationError>
     {
         self.bot_map.get_bot_location_at_index(index)
     }
 
+    /// Gets a mutable reference to the BotLocation at the given index
+    /// # Arguments
+    /// * 'index' - Index to get BotLocation from
+    /// # Returns
+    /// * Ok - Mutable reference to BotLocation at given index
+    ///   LocationError if None or Out of Bounds
+    pub fn get_mut_bot_location_at_index(&mut self, index: usize) -> Result<&mut BotLocation, LocationError>
+    {
+        self.bot_map.get_mut_bot_location_at_index(index)
+    }
+
+    /// Gets the indices of every space that has a bot in it, in ascending order
+    pub fn get_occupied_indices(&self) -> Vec<usize>
+    {
+        let mut indices: Vec<usize> = self.spatial_index.iter()
+            .map(|(_, coord)| coord.x + coord.y * self.width)
+            .collect();
+        indices.sort_unstable();
+        indices
+    }
+
+    /// Run a function on every bot on the board and collect the results, ordered by index
+    /// With the "parallel" feature enabled and 'parallel' set the bots are processed concurrently.
+    /// The function is only given one bot at a time, so the results are the same either way
+    /// # Arguments
+    /// * 'parallel' - Whether to process the bots concurrently, ignored without the "parallel" feature
+    /// * 'func' - Function taking the index of a bot and the bot itself
+    /// # Returns
+    /// * (index, result) of every bot the function returned a result for
+    pub fn map_bots<R, F>(&mut self, parallel: bool, func: F) -> Vec<(usize, R)>
+        where R: Send, F: Fn(usize, &mut Kilobot) -> Option<R> + Sync + Send
+    {
+        #[cfg(feature = "parallel")]
+        {
+            if parallel
+            {
+                use rayon::prelude::*;
+                return self.bot_map.bots.par_iter_mut().enumerate()
+                    .filter_map(|(i, b)| b.as_mut().and_then(|loc| func(i, loc.bot_mut()).map(|r| (i, r))))
+                    .collect();
+            }
+        }
+        #[cfg(not(feature = "parallel"))]
+        let _ = parallel;
+        self.bot_map.bots.iter_mut().enumerate()
+            .filter_map(|(i, b)| b.as_mut().and_then(|loc| func(i, loc.bot_mut()).map(|r| (i, r))))
+            .collect()
+    }
+
+    /// Run a function on every bot on the board. See map_bots
+    /// # Arguments
+    /// * 'parallel' - Whether to process the bots concurrently, ignored without the "parallel" feature
+    /// * 'func' - Function taking the index of a bot and the bot itself
+    pub fn for_each_bot_mut<F>(&mut self, parallel: bool, func: F) where F: Fn(usize, &mut Kilobot) + Sync + Send
+    {
+        self.map_bots(parallel, |i, bot| -> Option<()> {
+            func(i, bot);
+            None
+        });
+    }
+
+    /// Gets the number of bots on the board
+    pub fn num_bots(&self) -> usize
+    {
+        self.spatial_index.len()
+    }
+
+    /// Gets the index of the bot with the given uid
+    /// # Arguments
+    /// * 'uid' - Uid of the bot to find
+    /// # Returns
+    /// * Ok - Index of the bot
+    /// * LocationError::NotOccupied if no bot on the board has that uid
+    pub fn get_index_of_uid(&self, uid: u16) -> Result<usize, LocationError>
+    {
+        let coord = self.spatial_index.get(uid).ok_or(LocationError::NotOccupied)?;
+        self.get_index_from_coord(coord)
+    }
+
+    /// Gets every bot within a radius of a point
+    /// # Arguments
+    /// * 'center' - Center of the search
+    /// * 'radius' - Search radius in board units
+    /// # Returns
+    /// * (uid, distance) of every bot in range, ordered by uid. Distance is in board units
+    pub fn get_bots_near(&self, center: &CoordinatePair, radius: f64) -> Vec<(u16, f64)>
+    {
+        self.spatial_index.query_radius(center, radius)
+    }
+
     /// Returns whether the given index is occupied by a kilobot
     /// # Arguments
     /// * 'index' - Vector index to check
@@ -159,7 +485,40 @@ impl Board
         self.bot_map.index_is_occupied(index)
     }
 
-    //pub fn get_signals_at_index(&self, index: usize) -> Result<Vec<Signal>>
+    /// Gets every bot that can hear the signal transmitted by the bot with the given uid
+    /// # Arguments
+    /// * 'uid' - Uid of the transmitting bot
+    /// # Returns
+    /// * Ok - (uid, distance) of every other bot inside the signal, ordered by uid. Distance is in board units
+    /// * LocationError::NotOccupied if the bot has no signal source on the board
+    pub fn get_bots_hearing(&self, uid: u16) -> Result<Vec<(u16, f64)>, LocationError>
+    {
+        let coord = self.spatial_index.get(uid).ok_or(LocationError::NotOccupied)?;
+        let src = self.signal_map.get_source_at_coord(coord)?;
+        let mut listeners = self.spatial_index.query_radius(coord, src.radius);
+        listeners.retain(|l| l.0 != uid);
+        Ok(listeners)
+    }
+
+    /// Gets every bot whose signal can be heard by the bot with the given uid
+    /// # Arguments
+    /// * 'uid' - Uid of the listening bot
+    /// # Returns
+    /// * Ok - (uid, distance) of every other bot the listener can hear, ordered by uid. Distance is in board units
+    /// * LocationError::NotOccupied if there is no bot with that uid on the board
+    pub fn get_bots_heard_by(&self, uid: u16) -> Result<Vec<(u16, f64)>, LocationError>
+    {
+        let coord = self.spatial_index.get(uid).ok_or(LocationError::NotOccupied)?;
+        let mut sources = Vec::new();
+        for &src_uid in self.signal_map.get_signals_at_coord(coord)?.sources.iter()
+        {
+            if let (true, Some(src_coord)) = (src_uid != uid, self.spatial_index.get(src_uid))
+            {
+                sources.push((src_uid, src_coord.distance_to(coord)));
+            }
+        }
+        Ok(sources)
+    }
 
 
 }
@@ -187,17 +546,9 @@ impl fmt::Display for Board
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
     {
-        let mut num_bots: u16 = 0;
-        for index in 0..self.len()
-        {
-            if self.bot_map.bots.get(index).unwrap().is_some()
-            {
-                num_bots += 1;
-            }
-        }
         write!(f, "(width:{}, height:{}, number of bots:{})"
                , self.width
                , self.height
-               , num_bots)
+               , self.num_bots())
     }
 }
@@ -1,18 +1,29 @@
+use crate::hal::Hal;
 use crate::kilobot::Kilobot;
 use crate::board::{CoordinatePair, LocationError};
-use std::{mem, fmt};
+use std::fmt;
 use crate::board::board_map::BoardMap;
+use crate::board::CELL_SIZE_MM;
+use crate::kilobot::{MOTOR_MAX_VAL, TICKS_PER_SECOND, FORWARD_SPEED, ROTATION_SPEED};
+
+/// Degrees a bot turns at a time. Movement is restricted to the 8 neighbouring spaces
+pub const TURN_STEP: u16 = 45;
+/// Motor duty needed to move forward one space, one motor value per tick
+const FORWARD_THRESHOLD: u32 = MOTOR_MAX_VAL as u32 * CELL_SIZE_MM as u32 * TICKS_PER_SECOND / FORWARD_SPEED as u32;
+/// Motor duty difference needed to turn TURN_STEP degrees
+const TURN_THRESHOLD: i32 = (MOTOR_MAX_VAL as u32 * TICKS_PER_SECOND * TURN_STEP as u32 / ROTATION_SPEED as u32) as i32;
 
 /// Struct representing the field that Kilobots move on
 /// # Fields
 /// * 'width' - Width of the board
 /// * 'height' - Height of the board
 /// * 'locations' - Packed vector of Option<BotLocation> representing each space on the board, where
-///  any index that is not null has a bot, and any index that is null has no bot
+///   any index that is not null has a bot, and any index that is null has no bot
 pub struct BotMap
 {
     width: usize,
     height: usize,
+    num_bots: usize,
     // TODO: Make private, add getter/setter
     pub bots: Vec<Option<BotLocation>>,         //2D array packed into a Vector
 }
@@ -31,7 +42,7 @@ impl BotMap
     ///         where '*' represents "None"
     pub fn new(width: usize, height: usize) -> BotMap
     {
-        let mut new_map = BotMap{width, height, bots: Vec::with_capacity((width * height).into())};
+        let mut new_map = BotMap{width, height, num_bots: 0, bots: Vec::with_capacity(width * height)};
         for _i in 0..width * height
         {
             new_map.bots.push(None);
@@ -51,12 +62,13 @@ impl BotMap
     {
         if index >= self.len()
         {
-            Some(LocationError::OutOfBounds);
+            return Some(LocationError::OutOfBounds);
         }
         match self.bots[index] {
             Some(_) => Some(LocationError::AlreadyOccupied),
             None => {
-                mem::swap(&mut self.bots[index], &mut Some(BotLocation { bot, facing }));
+                self.bots[index] = Some(BotLocation::new(bot, facing));
+                self.num_bots += 1;
                 None
             }
         }
@@ -75,7 +87,8 @@ impl BotMap
             match self.bots.get(index).unwrap().as_ref() {
                 Some(_) => Some(LocationError::AlreadyOccupied),
                 None => {
-                    mem::swap(&mut self.bots[index], &mut Some(bot_loc));
+                    self.bots[index] = Some(bot_loc);
+                    self.num_bots += 1;
                     None
                 }
             }
@@ -94,11 +107,11 @@ impl BotMap
     {
         if index < self.bots.len()
         {
-            match self.bots.get(index)
+            match self.bots[index].take()
             {
-                Some(_b) => {
-                    let bot = mem::replace(&mut self.bots[index], None);
-                    Ok(Box::new(bot.unwrap()))
+                Some(bot) => {
+                    self.num_bots -= 1;
+                    Ok(Box::new(bot))
                 },
                 None => Err(LocationError::NotOccupied),
             }
@@ -123,7 +136,7 @@ impl BotMap
     /// * 'index' - Index to get BotLocation from
     /// # Returns
     /// * Ok - Reference to BotLocation at given index
-    /// LocationError if None or Out of Bounds
+    ///   LocationError if None or Out of Bounds
     pub fn get_bot_location_at_index(&self, index: usize) -> Result<&BotLocation, LocationError>
     {
         if index < self.bots.len()
@@ -138,6 +151,22 @@ impl BotMap
         else { Err(LocationError::OutOfBounds) }
     }
 
+    /// Gets a mutable reference to the BotLocation at the given index
+    /// # Arguments
+    /// * 'index' - Index to get BotLocation from
+    /// # Returns
+    /// * Ok - Mutable reference to BotLocation at given index
+    ///   LocationError if None or Out of Bounds
+    pub fn get_mut_bot_location_at_index(&mut self, index: usize) -> Result<&mut BotLocation, LocationError>
+    {
+        match self.bots.get_mut(index)
+        {
+            Some(Some(loc)) => Ok(loc),
+            Some(None) => Err(LocationError::NotOccupied),
+            None => Err(LocationError::OutOfBounds),
+        }
+    }
+
     /// Returns whether the given index is occupied by a kilobot
     /// # Arguments
     /// * 'index' - Vector index to check
@@ -203,10 +232,21 @@ pub struct BotLocation
 {
     bot: Kilobot,
     facing: u16,            //Represents the current angle of the bot, where 0 is north
+    forward_progress: u32,  //Motor duty accumulated towards the next step forward
+    turn_progress: i32,     //Motor duty accumulated towards the next turn, positive is clockwise
 }
 
 impl BotLocation
 {
+    /// Create a new BotLocation
+    /// # Arguments
+    /// * 'bot' - Kilobot at this location
+    /// * 'facing' - Direction the bot is facing, in degrees clockwise from north
+    pub fn new(bot: Kilobot, facing: u16) -> BotLocation
+    {
+        BotLocation { bot, facing: facing % 360, forward_progress: 0, turn_progress: 0 }
+    }
+
     /// Return an immutable reference to the bot in the location
     /// Allows accessing the bot functions, but cannot change bot values
     /// # Returns
@@ -238,7 +278,7 @@ impl BotLocation
     /// * 'new_facing' - The new facing of the bot, in degrees clockwise from north
     pub fn set_facing(&mut self, mut new_facing: i16)
     {
-        new_facing = new_facing % 360;
+        new_facing %= 360;
         if new_facing < 0
         {
             self.facing = (new_facing + 360) as u16
@@ -246,24 +286,54 @@ impl BotLocation
             self.facing = new_facing as u16
         }
     }
+
+    /// Accumulate one tick of motion from the bot's motor values, turning the bot if enough
+    /// rotation has built up. Bots turn in TURN_STEP increments and move a whole space at a time
+    /// # Returns
+    /// * true if the bot has built up enough forward motion to move one space
+    pub fn accumulate_motion(&mut self) -> bool
+    {
+        let (left, right) = self.bot.get_motor_values();
+        if left > 0 && right > 0
+        {
+            self.forward_progress += left.min(right) as u32;
+        }
+        let turn = left as i32 - right as i32;
+        if turn == 0 || turn.signum() != self.turn_progress.signum()
+        {
+            self.turn_progress = 0;
+        }
+        self.turn_progress += turn;
+        if self.turn_progress.abs() >= TURN_THRESHOLD
+        {
+            let step = TURN_STEP as i16 * self.turn_progress.signum() as i16;
+            self.set_facing(self.facing as i16 + step);
+            self.turn_progress = 0;
+        }
+        if self.forward_progress >= FORWARD_THRESHOLD
+        {
+            self.forward_progress = 0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Clear any forward motion built up, used when the bot is blocked
+    pub fn clear_forward_progress(&mut self)
+    {
+        self.forward_progress = 0;
+    }
 }
 
 impl fmt::Display for BotMap
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
     {
-        let mut num_bots: u16 = 0;
-        for index in 0..self.bots.len()
-        {
-            if self.bots.get(index).unwrap().is_some()
-            {
-                num_bots += 1;
-            }
-        }
         write!(f, "(width:{}, height:{}, number of bots:{})"
                , self.width
                , self.height
-               , num_bots)
+               , self.num_bots)
     }
 }
 
@@ -0,0 +1,147 @@
+/*
+ * environment
+ * Purpose: A virtual environment projected onto the board, like Kilogrid or ARK, where every space
+ * can exchange IR messages with the bot on top of it
+ *
+ * An Environment decides what each space tells the bot above it and hears everything that bot
+ * sends, which is enough for virtual pheromones, food patches or positioning. Write one in Rust
+ * by implementing Environment, or describe one in a scenario with rules, see RuleEnvironment:
+ *
+ *      environment = gps; patch 2 2 6 6 1; patch 20 12 24 16 2
+ *
+ */
+use std::any::Any;
+use std::collections::HashMap;
+use crate::board::CoordinatePair;
+use crate::kilobot::messages::{GpsFix, Message};
+use crate::kilobot::transceiver::DEFAULT_TX_PERIOD;
+
+/// Message type of the messages a patch sends, the last of the user message types so programs can
+/// tell them apart from other bots' messages
+pub const PATCH_MSG_TYPE: u8 = 127;
+
+/// The environment of the board, see the top of the file
+/// Environments are Any so their state can be inspected from outside, see Board::environment_as
+pub trait Environment: Send + Any
+{
+    /// Called once every tick, before any messages are exchanged, e.g. to let virtual pheromones
+    /// evaporate
+    /// # Arguments
+    /// * 'tick' - The current tick
+    fn update(&mut self, _tick: u64) {}
+
+    /// Called every tick for every space with a bot on it
+    /// # Arguments
+    /// * 'tick' - The current tick
+    /// * 'cell' - The space
+    /// # Returns
+    /// * The message the space sends to the bot above it, or None to stay quiet
+    fn transmit(&mut self, tick: u64, cell: &CoordinatePair) -> Option<Message>;
+
+    /// Called whenever a bot transmits, with the space it is on
+    /// # Arguments
+    /// * 'tick' - The current tick
+    /// * 'cell' - The space
+    /// * 'msg' - The message
+    fn receive(&mut self, _tick: u64, _cell: &CoordinatePair, _msg: &Message) {}
+}
+
+/// A rule of a RuleEnvironment
+pub enum Rule
+{
+    /// Every space sends its position as a GPS message, without a heading since the space can't
+    /// tell which way the bot on it is facing, see GpsFix
+    Gps,
+    /// Every space from (x0, y0) to (x1, y1), both included, sends a PATCH_MSG_TYPE message with
+    /// the value in data[0]
+    Patch { x0: usize, y0: usize, x1: usize, y1: usize, value: u8 },
+}
+
+impl Rule
+{
+    /// Get the message a space sends under this rule
+    /// # Returns
+    /// * The message, or None if the rule doesn't cover the space
+    fn message(&self, cell: &CoordinatePair) -> Option<Message>
+    {
+        match *self
+        {
+            Rule::Gps => {
+                let (x, y) = cell.as_mm_tuple();
+                Some(GpsFix { x: x.min(u16::MAX as f64) as u16, y: y.min(u16::MAX as f64) as u16, heading: None }.to_message())
+            },
+            Rule::Patch { x0, y0, x1, y1, value } if (x0..=x1).contains(&cell.x) && (y0..=y1).contains(&cell.y) =>
+                Some(Message::new(PATCH_MSG_TYPE, [value, 0, 0, 0, 0, 0, 0, 0, 0])),
+            Rule::Patch { .. } => None,
+        }
+    }
+}
+
+/// An environment described by a list of rules, as written in a scenario. Every DEFAULT_TX_PERIOD
+/// ticks each space sends the message of the first rule that covers it, and it counts the messages
+/// it hears
+/// # Fields
+/// * 'rules' - The rules, in order
+/// * 'received' - Number of messages every space has heard, by coordinates
+pub struct RuleEnvironment
+{
+    rules: Vec<Rule>,
+    received: HashMap<(usize, usize), u32>,
+}
+
+impl RuleEnvironment
+{
+    /// Parse the rules of an environment, separated by ';'. A rule is "gps" or
+    /// "patch x0 y0 x1 y1 value", see Rule
+    /// # Arguments
+    /// * 'text' - The rules
+    /// # Returns
+    /// * The environment, or a description of the rule that couldn't be understood
+    pub fn parse(text: &str) -> Result<RuleEnvironment, String>
+    {
+        let mut rules = Vec::new();
+        for rule in text.split(';').map(str::trim).filter(|r| !r.is_empty())
+        {
+            let words: Vec<&str> = rule.split_whitespace().collect();
+            let invalid = || format!("invalid environment rule '{}'", rule);
+            rules.push(match words.as_slice()
+            {
+                ["gps"] => Rule::Gps,
+                ["patch", x0, y0, x1, y1, value] => Rule::Patch {
+                    x0: x0.parse().map_err(|_| invalid())?,
+                    y0: y0.parse().map_err(|_| invalid())?,
+                    x1: x1.parse().map_err(|_| invalid())?,
+                    y1: y1.parse().map_err(|_| invalid())?,
+                    value: value.parse().map_err(|_| invalid())?,
+                },
+                _ => return Err(invalid()),
+            });
+        }
+        Ok(RuleEnvironment { rules, received: HashMap::new() })
+    }
+
+    /// Get the number of messages a space has heard from the bots on top of it
+    /// # Arguments
+    /// * 'cell' - The space
+    pub fn get_received(&self, cell: &CoordinatePair) -> u32
+    {
+        self.received.get(&cell.as_usize_tuple()).copied().unwrap_or(0)
+    }
+}
+
+impl Environment for RuleEnvironment
+{
+    fn transmit(&mut self, tick: u64, cell: &CoordinatePair) -> Option<Message>
+    {
+        if !tick.is_multiple_of(DEFAULT_TX_PERIOD as u64)
+        {
+            return None;
+        }
+        self.rules.iter().find_map(|rule| rule.message(cell))
+    }
+
+    fn receive(&mut self, _tick: u64, cell: &CoordinatePair, _msg: &Message)
+    {
+        *self.received.entry(cell.as_usize_tuple()).or_default() += 1;
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * pheromone_map
+ * Purpose: A virtual pheromone field over the board, for stigmergy experiments. Bots deposit
+ * pheromone on the space under them and sense the level there, see Hal::deposit_pheromone
+ *
+ * Every tick a fraction of the pheromone evaporates, then a fraction of what is left on each space
+ * spreads evenly to its four neighbours. Pheromone that would spread off the edge of the board stays
+ * where it is, so diffusion never loses any.
+ *
+ */
+use crate::board::board_map::BoardMap;
+
+/// Highest level of pheromone a space can hold, the reading of a fully marked space
+pub const PHEROMONE_MAX: u16 = 1023;
+/// Level below which a space counts as clean, so a field that has evaporated stops being updated
+const PHEROMONE_EPSILON: f64 = 0.01;
+
+/// Level of pheromone on every space of the board
+/// # Fields
+/// * 'width' - Width of the board
+/// * 'height' - Height of the board
+/// * 'levels' - Level of every space, from 0 up to PHEROMONE_MAX
+/// * 'evaporation' - Fraction of the pheromone that evaporates every tick, from 0 to 1
+/// * 'diffusion' - Fraction of the pheromone on a space that spreads to its neighbours every tick,
+///   from 0 to 1
+/// * 'clean' - Whether every level is 0, so there is nothing to update
+pub struct PheromoneMap
+{
+    width: usize,
+    height: usize,
+    levels: Vec<f64>,
+    evaporation: f64,
+    diffusion: f64,
+    clean: bool,
+}
+
+impl PheromoneMap
+{
+    /// Create a new PheromoneMap without any pheromone, which neither evaporates nor diffuses
+    /// # Arguments
+    /// * 'width' - How wide the board is
+    /// * 'height' - How tall the board is
+    pub fn new(width: usize, height: usize) -> PheromoneMap
+    {
+        PheromoneMap { width, height, levels: vec![0.0; width * height], evaporation: 0.0, diffusion: 0.0, clean: true }
+    }
+
+    /// Set how fast the pheromone evaporates
+    /// # Arguments
+    /// * 'evaporation' - Fraction that evaporates every tick, from 0 to 1
+    pub fn set_evaporation(&mut self, evaporation: f64)
+    {
+        self.evaporation = evaporation.clamp(0.0, 1.0);
+    }
+
+    /// Set how fast the pheromone diffuses
+    /// # Arguments
+    /// * 'diffusion' - Fraction of every space's pheromone that spreads to its neighbours every tick,
+    ///   from 0 to 1
+    pub fn set_diffusion(&mut self, diffusion: f64)
+    {
+        self.diffusion = diffusion.clamp(0.0, 1.0);
+    }
+
+    /// Add pheromone to a space, up to PHEROMONE_MAX
+    /// # Arguments
+    /// * 'index' - Index of the space
+    /// * 'amount' - Pheromone to add
+    pub fn deposit(&mut self, index: usize, amount: f64)
+    {
+        if let Some(level) = self.levels.get_mut(index)
+        {
+            *level = (*level + amount).min(PHEROMONE_MAX as f64);
+            self.clean &= *level < PHEROMONE_EPSILON;
+        }
+    }
+
+    /// Get the level of pheromone on a space
+    /// # Arguments
+    /// * 'index' - Index of the space
+    /// # Returns
+    /// * The level, from 0 up to PHEROMONE_MAX, or 0 if the index is out of bounds
+    pub fn get_level_at_index(&self, index: usize) -> f64
+    {
+        self.levels.get(index).copied().unwrap_or(0.0)
+    }
+
+    /// Get what a bot senses on a space, its level rounded to a whole number
+    /// # Arguments
+    /// * 'index' - Index of the space
+    pub fn get_reading_at_index(&self, index: usize) -> u16
+    {
+        self.get_level_at_index(index).round() as u16
+    }
+
+    /// Get the highest level of pheromone on any space
+    pub fn get_max_level(&self) -> f64
+    {
+        self.levels.iter().copied().fold(0.0, f64::max)
+    }
+
+    /// Returns whether there is no pheromone anywhere on the board
+    pub fn is_clean(&self) -> bool
+    {
+        self.clean
+    }
+
+    /// Let the pheromone evaporate and diffuse for one tick
+    pub fn update(&mut self)
+    {
+        if self.clean
+        {
+            return;
+        }
+        let keep = 1.0 - self.evaporation;
+        if self.diffusion > 0.0
+        {
+            let mut next = vec![0.0; self.levels.len()];
+            for (index, level) in self.levels.iter().enumerate()
+            {
+                let (x, y) = (index % self.width, index / self.width);
+                let share = level * keep * self.diffusion / 4.0;
+                let mut kept = level * keep;
+                for (dx, dy) in [(-1, 0), (1, 0), (0, -1), (0, 1)].iter()
+                {
+                    let (nx, ny) = (x as isize + dx, y as isize + dy);
+                    if nx >= 0 && ny >= 0 && (nx as usize) < self.width && (ny as usize) < self.height
+                    {
+                        next[nx as usize + ny as usize * self.width] += share;
+                        kept -= share;
+                    }
+                }
+                next[index] += kept;
+            }
+            self.levels = next;
+        } else {
+            self.levels.iter_mut().for_each(|level| *level *= keep);
+        }
+        self.clean = self.levels.iter().all(|level| *level < PHEROMONE_EPSILON);
+        if self.clean
+        {
+            self.levels.iter_mut().for_each(|level| *level = 0.0);
+        }
+    }
+}
+
+impl BoardMap for PheromoneMap
+{
+    fn get_width(&self) -> usize {
+        self.width
+    }
+
+    fn get_height(&self) -> usize {
+        self.height
+    }
+}
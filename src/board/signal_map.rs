@@ -7,7 +7,7 @@
 
 use crate::board::{CoordinatePair, LocationError};
 use crate::board::board_map::BoardMap;
-use std::{mem, fmt};
+use std::fmt;
 use std::cmp::{max, min};
 
 /// Map of all broadcasts and their range on the board
@@ -20,15 +20,20 @@ pub struct SignalMap
 }
 
 /// Represents a space on the board and stores all readable signals at that space
-/// Sources are represented by the coordinate they originate from, since rust doesn't like vectors of references
+/// Sources are represented by the uid of the bot transmitting them, since rust doesn't like vectors of references
 pub struct Signal
 {
-    pub sources: Vec<(u8, u8)>,
+    pub sources: Vec<u16>,
 }
 
 /// Represents a signal source
+/// # Fields
+/// * 'uid' - Uid of the bot transmitting the signal
+/// * 'coord' - Coordinates the signal originates from
+/// * 'radius' - Broadcast radius of the signal in board units
 pub struct SignalSource
 {
+    pub uid: u16,
     pub coord: CoordinatePair,
     pub radius: f64,
 }
@@ -73,7 +78,7 @@ impl SignalMap
                     Some(_) => Some(LocationError::AlreadyOccupied),
                     None => {
                         self.fill_circle(&source, Signal::add_source);
-                        mem::swap(&mut self.sources[index], &mut Some(source));
+                        self.sources[index] = Some(source);
                         None
                     },
                 }
@@ -92,10 +97,9 @@ impl SignalMap
         match self.get_index_from_coord(coord)
         {
             Ok(index) => {
-                match self.sources.get(index)
+                match self.sources[index].take()
                 {
-                    Some(_s) => {
-                        let mut src = mem::replace(&mut self.sources[index], None).unwrap();
+                    Some(src) => {
                         self.fill_circle(&src, Signal::remove_source);
                         Ok(src)
                     },
@@ -126,11 +130,25 @@ impl SignalMap
         }
     }
 
+    /// Get a reference to the SignalSource transmitted by the bot with the given uid
+    /// # Arguments
+    /// * 'uid' - Uid of the transmitting bot
+    /// # Returns
+    /// Reference to the SignalSource, or LocationError::NotOccupied if the bot has no source on the map
+    pub fn get_source_by_uid(&self, uid: u16) -> Result<&SignalSource,LocationError>
+    {
+        self.sources.iter()
+            .filter_map(|s| s.as_ref())
+            .find(|s| s.uid == uid)
+            .ok_or(LocationError::NotOccupied)
+    }
+
     /// Get a mutable reference to the SignalSource at the provided coordinates
     /// # Arguments
     /// * 'coord' - Coordinate on SignalMap to get SignalSource from
     /// # Returns
     /// Mutable reference to a SignalSource at the given coordinate, or LocationError if out of bounds or no source present
+    #[allow(dead_code)]
     pub fn get_mut_source_at_coord(&mut self, coord: &CoordinatePair) -> Result<&mut SignalSource,LocationError>
     {
         match self.get_index_from_coord(coord)
@@ -146,43 +164,61 @@ impl SignalMap
         }
     }
 
-    /// Move a source to a destination CoordinatePair.
-    /// !! Note that src must already be in a valid location on the SignalMap !!
+    /// Move a source to a destination CoordinatePair. Only the spaces that enter or leave the
+    /// signal's coverage are updated, so moving a source a short distance is cheap
     /// # Arguments
-    /// * 'src' - SignalSource to move. Must already be in a valid location on the SignalMap
+    /// * 'src' - Coordinates of the SignalSource to move
     /// * 'dest' - Destination to try to move src to
     /// # Returns
     /// * None if the move was successful, LocationError if any snags were hit
     pub fn move_source_to_coord(&mut self, src: &CoordinatePair, dest: &CoordinatePair) -> Option<LocationError>
     {
-        match self.get_index_from_coord(&src)
+        let src_index = match self.get_index_from_coord(src)
+        {
+            Ok(i) => i,
+            Err(e) => return Some(e),
+        };
+        let dest_index = match self.get_index_from_coord(dest)
         {
-            Ok(_) => {
-                match self.get_index_from_coord(&dest)
+            Ok(i) => i,
+            Err(e) => return Some(e),
+        };
+        if self.sources[dest_index].is_some()
+        {
+            return Some(LocationError::AlreadyOccupied);
+        }
+        let mut source = match self.sources[src_index].take()
+        {
+            Some(s) => s,
+            None => return Some(LocationError::NotOccupied),
+        };
+
+        let old_box = self.get_bounding_box(src, source.radius);
+        let new_box = self.get_bounding_box(dest, source.radius);
+        for y in min(old_box.0, new_box.0)..max(old_box.1, new_box.1)
+        {
+            for x in min(old_box.2, new_box.2)..max(old_box.3, new_box.3)
+            {
+                let target = CoordinatePair {x, y};
+                let was_inside = SignalMap::inside_circle(src, &target, source.radius);
+                let is_inside = SignalMap::inside_circle(dest, &target, source.radius);
+                if was_inside != is_inside
                 {
-                    Ok(dest_index) => {
-                        match self.sources.get_mut(dest_index).unwrap()
+                    if let Ok(i) = self.get_index_from_coord(&target)
+                    {
+                        if is_inside
                         {
-                            Some(_x) => Some(LocationError::AlreadyOccupied),
-                            None => {
-                                match self.remove_source_at_coord(src)
-                                {
-                                    Ok(mut removed_src) => {
-                                        removed_src.coord = dest.clone();
-                                        self.add_new_source(removed_src);
-                                        None
-                                    },
-                                    Err(e) => Some(e),
-                                }
-                            }
+                            Signal::add_source(&mut self.signals[i], &source);
+                        } else {
+                            Signal::remove_source(&mut self.signals[i], &source);
                         }
-                    },
-                    Err(e) => Some(e),
+                    }
                 }
-            },
-            Err(e) => Some(e),
+            }
         }
-
+        source.coord = dest.clone();
+        self.sources[dest_index] = Some(source);
+        None
     }
 
     /// Get the signals detectable at the given coordinate
@@ -210,6 +246,12 @@ impl SignalMap
         }
     }
 
+    /// Count the spaces that can hear at least one signal
+    pub fn get_num_covered_spaces(&self) -> usize
+    {
+        self.signals.iter().filter(|sig| !sig.sources.is_empty()).count()
+    }
+
     /// Helper function to determine whether a square is within a circle
     /// https://www.redblobgames.com/grids/circle-drawing/
     /// # Arguments
@@ -228,18 +270,18 @@ impl SignalMap
         distance_squared <= radius*radius
     }
 
-    /// Helper function to get the bounding box of a circle. !!Does not check if center is out of bounds!!
+    /// Helper function to get the bounding box of a circle, clipped to the edges of the map
     /// # Arguments
     /// * 'center' - The coordinates of the center of the circle
     /// * 'radius' - the radius of the circle
     /// # Returns
-    /// (top, bottom, left, right) - The boundaries of the circle as tuple
+    /// (top, bottom, left, right) - The boundaries of the circle as tuple. Bottom and right are exclusive
     fn get_bounding_box(&self, center: &CoordinatePair, radius: f64) -> (usize,usize,usize,usize)
     {
-        let top: usize = min(0, (center.y as f64 - radius) as usize);
-        let bottom: usize = max(self.height, (center.y as f64 + radius) as usize);
-        let left: usize = min(0, (center.x as f64 - radius) as usize);
-        let right: usize = max(self.width, (center.x as f64 + radius) as usize);
+        let top: usize = (center.y as f64 - radius).ceil().max(0.0) as usize;
+        let bottom: usize = min(self.height, (center.y as f64 + radius).floor() as usize + 1);
+        let left: usize = (center.x as f64 - radius).ceil().max(0.0) as usize;
+        let right: usize = min(self.width, (center.x as f64 + radius).floor() as usize + 1);
 
         (top, bottom, left, right)
     }
@@ -279,7 +321,7 @@ impl SignalMap
             if self.sources[index].is_some()
             {
                 txt = "O".parse().unwrap();
-            } else if self.signals[index].sources.len() > 0
+            } else if !self.signals[index].sources.is_empty()
             {
                 txt = "#".parse().unwrap();
             } else {
@@ -311,20 +353,23 @@ impl SignalSource
 {
     /// Create a new SignalSource
     /// # Arguments
-    /// * 'index' - Array index of the signal source
+    /// * 'uid' - Uid of the bot transmitting the signal
+    /// * 'coord' - Coordinates of the signal source
     /// * 'radius' - Broadcast radius of the signal in board units
     /// # Returns
     /// New, initialized SignalSource object
-    pub fn new(coord: CoordinatePair, radius: f64) -> SignalSource
+    pub fn new(uid: u16, coord: CoordinatePair, radius: f64) -> SignalSource
     {
-        SignalSource{ coord, radius }
+        SignalSource{ uid, coord, radius }
     }
 
+    #[allow(dead_code)]
     pub fn get(&self) -> &SignalSource
     {
         self
     }
 
+    #[allow(dead_code)]
     pub fn get_mut(&mut self) -> &mut SignalSource
     {
         self
@@ -336,23 +381,24 @@ impl Signal
     /// Add a detectable signal source, sort, then remove any duplicates
     /// Sort is needed to ensure that deduping catches all duplicates
     /// # Arguments
-    /// * 'src' - Coordinates of the source
+    /// * 'src' - The source to add
     pub fn add_source(point: &mut Signal, src: &SignalSource)
     {
-        point.sources.push(src.coord.as_u8_tuple());
-        point.sources.sort_by_key(|k| (k.0, k.1));
+        point.sources.push(src.uid);
+        point.sources.sort_unstable();
         point.sources.dedup();
     }
 
-    /// Removes the given signal source origin if present
+    /// Removes the given signal source if present
     /// # Arguments
-    /// * 'src' - Origin coordinates to remove
+    /// * 'src' - The source to remove
     pub fn remove_source(point: &mut Signal, src: &SignalSource)
     {
-        point.sources.retain(|&x| x != src.coord.as_u8_tuple())
+        point.sources.retain(|&x| x != src.uid)
     }
 
     /// Clone a source Signal object
+    #[allow(dead_code)]
     pub fn clone(src: &Signal) -> Signal
     {
         let mut new_sig = Signal{ sources: vec![] };
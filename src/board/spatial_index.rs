@@ -0,0 +1,154 @@
+/*
+ * spatial_index
+ * Purpose: Bucket grid of bot positions, so finding the bots near a point only looks at the
+ * handful of buckets around it instead of every space or every bot on the board
+ *
+ */
+use std::collections::HashMap;
+use crate::board::CoordinatePair;
+
+/// Grid of buckets, each holding the uids of the bots inside a bucket_size x bucket_size square
+/// # Fields
+/// * 'bucket_size' - Width and height of a bucket in board units
+/// * 'cols' - Number of buckets across the board
+/// * 'rows' - Number of buckets down the board
+/// * 'buckets' - Uids of the bots in each bucket, packed row by row
+/// * 'positions' - Coordinates of every bot in the index, by uid
+pub struct SpatialIndex
+{
+    bucket_size: usize,
+    cols: usize,
+    rows: usize,
+    buckets: Vec<Vec<u16>>,
+    positions: HashMap<u16, CoordinatePair>,
+}
+
+impl SpatialIndex
+{
+    /// Create a new, empty SpatialIndex
+    /// # Arguments
+    /// * 'width' - Width of the board
+    /// * 'height' - Height of the board
+    /// * 'bucket_size' - Width and height of a bucket. Works best around the typical query radius
+    pub fn new(width: usize, height: usize, bucket_size: usize) -> SpatialIndex
+    {
+        let bucket_size = bucket_size.max(1);
+        let cols = width.div_ceil(bucket_size);
+        let rows = height.div_ceil(bucket_size);
+        let mut buckets = Vec::with_capacity(cols * rows);
+        buckets.resize_with(cols * rows, Vec::new);
+        SpatialIndex { bucket_size, cols, rows, buckets, positions: HashMap::new() }
+    }
+
+    /// Get the number of bots in the index
+    pub fn len(&self) -> usize
+    {
+        self.positions.len()
+    }
+
+    /// Returns whether the index is empty
+    #[allow(dead_code)]
+    pub fn is_empty(&self) -> bool
+    {
+        self.positions.is_empty()
+    }
+
+    /// Add a bot to the index. A bot that is already in the index is moved instead
+    /// # Arguments
+    /// * 'uid' - Uid of the bot
+    /// * 'coord' - Coordinates of the bot
+    pub fn insert(&mut self, uid: u16, coord: &CoordinatePair)
+    {
+        self.remove(uid);
+        let bucket = self.bucket_of(coord);
+        self.buckets[bucket].push(uid);
+        self.positions.insert(uid, coord.clone());
+    }
+
+    /// Remove a bot from the index
+    /// # Arguments
+    /// * 'uid' - Uid of the bot to remove
+    /// # Returns
+    /// * The coordinates the bot was at, or None if it wasn't in the index
+    pub fn remove(&mut self, uid: u16) -> Option<CoordinatePair>
+    {
+        let coord = self.positions.remove(&uid)?;
+        let bucket = self.bucket_of(&coord);
+        self.buckets[bucket].retain(|&u| u != uid);
+        Some(coord)
+    }
+
+    /// Move a bot that is already in the index. Only touches the buckets if the bot changes bucket
+    /// # Arguments
+    /// * 'uid' - Uid of the bot to move
+    /// * 'dest' - New coordinates of the bot
+    pub fn move_to(&mut self, uid: u16, dest: &CoordinatePair)
+    {
+        let old_bucket = match self.positions.get(&uid)
+        {
+            Some(coord) => self.bucket_of(coord),
+            None => return self.insert(uid, dest),
+        };
+        let new_bucket = self.bucket_of(dest);
+        if old_bucket != new_bucket
+        {
+            self.buckets[old_bucket].retain(|&u| u != uid);
+            self.buckets[new_bucket].push(uid);
+        }
+        self.positions.insert(uid, dest.clone());
+    }
+
+    /// Get the coordinates of a bot
+    /// # Arguments
+    /// * 'uid' - Uid of the bot
+    pub fn get(&self, uid: u16) -> Option<&CoordinatePair>
+    {
+        self.positions.get(&uid)
+    }
+
+    /// Iterate over every bot in the index as (uid, coordinates), in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (u16, &CoordinatePair)>
+    {
+        self.positions.iter().map(|(&uid, coord)| (uid, coord))
+    }
+
+    /// Find every bot within a radius of a point
+    /// # Arguments
+    /// * 'center' - Center of the search
+    /// * 'radius' - Search radius in board units. Bots exactly on the edge are included
+    /// # Returns
+    /// * (uid, distance) of every bot in range, ordered by uid
+    pub fn query_radius(&self, center: &CoordinatePair, radius: f64) -> Vec<(u16, f64)>
+    {
+        let mut found = Vec::new();
+        let reach = radius.max(0.0);
+        let min_col = (center.x as f64 - reach).max(0.0) as usize / self.bucket_size;
+        let min_row = (center.y as f64 - reach).max(0.0) as usize / self.bucket_size;
+        let max_col = ((center.x as f64 + reach) as usize / self.bucket_size).min(self.cols.saturating_sub(1));
+        let max_row = ((center.y as f64 + reach) as usize / self.bucket_size).min(self.rows.saturating_sub(1));
+        for row in min_row..=max_row
+        {
+            for col in min_col..=max_col
+            {
+                for &uid in &self.buckets[col + row * self.cols]
+                {
+                    let dist = center.distance_to(&self.positions[&uid]);
+                    if dist <= radius
+                    {
+                        found.push((uid, dist));
+                    }
+                }
+            }
+        }
+        found.sort_by_key(|f| f.0);
+        found
+    }
+
+    /// Get the bucket that holds a coordinate
+    fn bucket_of(&self, coord: &CoordinatePair) -> usize
+    {
+        let col = (coord.x / self.bucket_size).min(self.cols - 1);
+        let row = (coord.y / self.bucket_size).min(self.rows - 1);
+        col + row * self.cols
+    }
+}
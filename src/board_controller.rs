@@ -1,50 +1,328 @@
-use crate::board::{LocationError, CoordinatePair, Board};
+use std::collections::{HashMap, HashSet, VecDeque};
+use crate::hal::Hal;
+use crate::board::{LocationError, CoordinatePair, Board, CELL_SIZE_MM};
 use crate::board::board_map::BoardMap;
+use crate::kilobot::messages::Message;
+use crate::overhead::Overhead;
+
+/// Debug lines the controller keeps, older ones are dropped
+pub const DEBUG_LOG_LINES: usize = 10_000;
+
+/// A line a bot wrote to its debug serial line, see Hal::debug_print
+/// # Fields
+/// * 'tick' - Tick the line was written in
+/// * 'uid' - Uid of the bot
+/// * 'text' - The line
+pub struct DebugLine
+{
+    pub tick: u64,
+    pub uid: u16,
+    pub text: String,
+}
 
 /// Object responsible for manipulating the board
 /// # Fields
 /// board - Board struct
+/// tick - Number of ticks the simulation has run for
+/// parallel - Whether bot programs run concurrently. Only has an effect with the "parallel" feature
+/// overhead - The overhead controller, which broadcasts to every bot
+/// contention - Whether messages that reach a bot in the same tick collide, see set_contention
+/// collisions - Number of messages lost to collisions
+/// debug_log - The most recent debug lines of the bots, oldest first
+/// debug_uids - Uids of the bots whose debug lines are kept, or None for every bot
 pub struct BoardController
 {
     pub(crate) board: Board,
+    tick: u64,
+    parallel: bool,
+    overhead: Overhead,
+    contention: bool,
+    collisions: u64,
+    debug_log: VecDeque<DebugLine>,
+    debug_uids: Option<HashSet<u16>>,
 }
 
 impl BoardController
 {
-    /// Moves a BotLocation to a new index on the board
+    /// Create a new BoardController
     /// # Arguments
-    /// * 'src_index' - Index of BotLocation to be moved
-    /// * 'dest_index' - Index of board to move BotLocation to
+    /// * 'board' - Board to control
+    pub fn new(board: Board) -> BoardController
+    {
+        BoardController { board, tick: 0, parallel: cfg!(feature = "parallel"), overhead: Overhead::new(), contention: false,
+                          collisions: 0, debug_log: VecDeque::new(), debug_uids: None }
+    }
+
+    /// Choose whether bot programs run concurrently. Results are identical either way, this
+    /// only exists so the two can be compared. Has no effect without the "parallel" feature
+    /// # Arguments
+    /// * 'parallel' - true to run bot programs concurrently
+    #[cfg(feature = "parallel")]
+    pub fn set_parallel(&mut self, parallel: bool)
+    {
+        self.parallel = parallel;
+    }
+
+    /// Choose whether messages collide. With contention, a bot that is reached by more than one
+    /// message in a tick, the overhead controller's included, receives none of them, like two IR
+    /// signals drowning each other out. Without it every message gets through
+    /// # Arguments
+    /// * 'contention' - true for messages to collide
+    pub fn set_contention(&mut self, contention: bool)
+    {
+        self.contention = contention;
+    }
+
+    /// Get the number of messages lost to collisions so far, see set_contention
+    pub fn get_collisions(&self) -> u64
+    {
+        self.collisions
+    }
+
+    /// Choose which bots' debug lines are kept, see debug_log
+    /// # Arguments
+    /// * 'uids' - Uids of the bots, or None for every bot
+    pub fn set_debug_uids(&mut self, uids: Option<HashSet<u16>>)
+    {
+        self.debug_uids = uids;
+    }
+
+    /// Get the debug lines written by the bots, up to the last DEBUG_LOG_LINES, oldest first.
+    /// Lines of the same tick are in order of uid
+    pub fn debug_log(&self) -> &VecDeque<DebugLine>
+    {
+        &self.debug_log
+    }
+
+    /// Take the debug lines written by the bots since the last call, see debug_log
+    pub fn take_debug_log(&mut self) -> Vec<DebugLine>
+    {
+        self.debug_log.drain(..).collect()
+    }
+
+    /// Get the overhead controller
+    pub fn overhead(&self) -> &Overhead
+    {
+        &self.overhead
+    }
+
+    /// Get the overhead controller, to queue messages for it to send
+    pub fn overhead_mut(&mut self) -> &mut Overhead
+    {
+        &mut self.overhead
+    }
+
+    /// Get the number of ticks the simulation has run for
+    pub fn get_tick(&self) -> u64
+    {
+        self.tick
+    }
+
+    /// Advance the simulation by one tick. Each tick happens in three phases:
+    /// 1. Every bot reads its light and pheromone sensors and runs its program
+    /// 2. Bots that are due to transmit broadcast a message to every bot in range
+    /// 3. Bots move according to their motor values
+    ///
+    /// The pheromone the bots deposited lands on the spaces they started the tick on, and at the end
+    /// of the tick the pheromone field evaporates and diffuses
+    /// Bot programs only ever see their own bot, so the first two phases can run on every bot at
+    /// once. Anything that involves more than one bot is merged in a fixed order afterwards
+    pub fn step(&mut self)
+    {
+        let indices = self.board.get_occupied_indices();
+        // Without a light every reading is 0, so there is no need to work them out
+        let light: HashMap<usize, u16> = match self.board.get_light()
+        {
+            Some(_) => indices.iter().map(|&i| (i, self.board.get_ambient_light_at_index(i))).collect(),
+            None => HashMap::new(),
+        };
+        // Likewise for a board without pheromone
+        let pheromone: HashMap<usize, u16> = if self.board.pheromone_map().is_clean()
+        {
+            HashMap::new()
+        } else {
+            indices.iter().map(|&i| (i, self.board.pheromone_map().get_reading_at_index(i))).collect()
+        };
+        self.board.for_each_bot_mut(self.parallel, |index, bot| {
+            bot.set_ambient_light(light.get(&index).copied().unwrap_or(0));
+            bot.set_pheromone(pheromone.get(&index).copied().unwrap_or(0));
+            bot.run_tick();
+        });
+        for &index in &indices
+        {
+            self.board.refresh_signal_radius(index);
+        }
+        for (index, amount) in self.board.map_bots(self.parallel, |_, bot| Some(bot.take_deposit()).filter(|a| *a > 0))
+        {
+            self.board.pheromone_map_mut().deposit(index, amount as f64);
+        }
+        self.deliver_messages();
+        // Indices are visited in ascending order, so a bot can only move into a space that was
+        // occupied at the start of the tick if that space has already been visited. No bot moves twice
+        for &index in &indices
+        {
+            self.apply_motion(index);
+        }
+        self.collect_debug_output();
+        self.board.pheromone_map_mut().update();
+        self.tick += 1;
+    }
+
+    /// Move the lines the bots wrote to their debug serial lines this tick into the debug log
+    fn collect_debug_output(&mut self)
+    {
+        let mut output = self.board.map_bots(self.parallel, |_, bot| {
+            let lines = bot.take_debug_output();
+            if lines.is_empty() { None } else { Some((bot.get_uid(), lines)) }
+        });
+        output.sort_by_key(|(_, (uid, _))| *uid);
+        for (_, (uid, lines)) in output
+        {
+            if self.debug_uids.as_ref().is_some_and(|uids| !uids.contains(&uid))
+            {
+                continue;
+            }
+            for text in lines
+            {
+                if self.debug_log.len() == DEBUG_LOG_LINES
+                {
+                    self.debug_log.pop_front();
+                }
+                self.debug_log.push_back(DebugLine { tick: self.tick, uid, text });
+            }
+        }
+    }
+
+    /// Collect the messages of every bot that is due to transmit and deliver them to every bot
+    /// within the sender's broadcast radius, along with the overhead controller's message, which
+    /// reaches every bot, its GPS fixes and the environment's messages, which only reach the bot
+    /// they are for. Each bot receives the overhead controller's message first, then its GPS fix,
+    /// then the environment's message, then the others in order of sender uid. The environment
+    /// hears every message sent from on top of it
+    fn deliver_messages(&mut self)
+    {
+        let mut outgoing = self.board.map_bots(self.parallel, |_, bot| {
+            let radius = bot.transceiver().get_tx_radius();
+            bot.transmit().map(|msg| (bot.get_uid(), msg, radius))
+        });
+        outgoing.sort_by_key(|o| (o.1).0);
+        let overhead = self.overhead.transmit();
+        let fixes = self.overhead.gps_fixes(self.tick, &self.board);
+        let environment = self.exchange_with_environment(&outgoing);
+        if outgoing.is_empty() && overhead.is_none() && fixes.is_empty() && environment.is_empty()
+        {
+            return;
+        }
+
+        // Work out what every bot hears before handing anything over
+        let mut inboxes: HashMap<usize, Vec<(&Message, u16)>> = HashMap::new();
+        if let Some(msg) = &overhead
+        {
+            // The overhead controller is right above the bots, so there's no distance to speak of
+            for index in self.board.get_occupied_indices()
+            {
+                inboxes.insert(index, vec![(msg, 0)]);
+            }
+        }
+        for (index, msg) in fixes.iter().chain(&environment)
+        {
+            inboxes.entry(*index).or_default().push((msg, 0));
+        }
+        let mut senders: HashSet<usize> = HashSet::new();
+        for (src_index, (src_uid, msg, radius)) in &outgoing
+        {
+            let src_coord = self.board.get_coord_from_index(src_index).ok().unwrap();
+            for (uid, dist) in self.board.get_bots_near(&src_coord, *radius)
+            {
+                if uid != *src_uid
+                {
+                    let index = self.board.get_index_of_uid(uid).ok().unwrap();
+                    inboxes.entry(index).or_default().push((msg, (dist * CELL_SIZE_MM as f64).round() as u16));
+                }
+            }
+            senders.insert(*src_index);
+        }
+        if self.contention
+        {
+            for inbox in inboxes.values_mut().filter(|inbox| inbox.len() > 1)
+            {
+                self.collisions += inbox.len() as u64;
+                inbox.clear();
+            }
+        }
+
+        self.board.for_each_bot_mut(self.parallel, |index, bot| {
+            if let Some(inbox) = inboxes.get(&index)
+            {
+                for (msg, dist) in inbox
+                {
+                    bot.receive((*msg).clone(), *dist);
+                }
+            }
+            if senders.contains(&index)
+            {
+                bot.transmit_success();
+            }
+        });
+    }
+
+    /// Let the environment update, hear the bots' messages and send its own
+    /// # Arguments
+    /// * 'outgoing' - The bots' messages, as (index, (uid, message, radius))
     /// # Returns
-    /// * Option<LocationError> if either coordinate is out of bounds, or if there is no BotLocation
-    /// in the source, or if the destination already has a bot
-    pub fn move_bot_by_index(&mut self, src_index: usize, dest_index: usize) -> Option<LocationError>
+    /// * The environment's messages, by index of the bot they are for
+    fn exchange_with_environment(&mut self, outgoing: &[(usize, (u16, Message, f64))]) -> Vec<(usize, Message)>
     {
-        if src_index < self.board.len() && dest_index < self.board.len()
+        let tick = self.tick;
+        let cells: Vec<(usize, CoordinatePair)> = self.board.get_occupied_indices().into_iter()
+            .map(|index| (index, self.board.get_coord_from_index(&index).ok().unwrap()))
+            .collect();
+        let environment = match self.board.environment_mut()
         {
-            match self.board.bot_map.index_is_occupied(dest_index)
+            Some(environment) => environment,
+            None => return Vec::new(),
+        };
+        environment.update(tick);
+        for (index, (_, msg, _)) in outgoing
+        {
+            if let Ok(i) = cells.binary_search_by_key(index, |c| c.0)
             {
-                Ok(is_occupied) => {
-                    match self.board.bot_map.remove_bot_location_at_index(src_index)
-                    {
-                        Ok(b) => {
-                            if !is_occupied
-                            {
-                                let bot = *b;
-                                self.board.add_bot_location_at_index(bot, dest_index);
-                                None
-                            } else {
-                                Some(LocationError::AlreadyOccupied)
-                            }
-                        }
-                        Err(e) => Some(e),
-                    }
-                },
-                Err(e) => Some(e)
+                environment.receive(tick, &cells[i].1, msg);
             }
-        } else {
-            Some(LocationError::OutOfBounds)
         }
+        cells.iter().filter_map(|(index, cell)| environment.transmit(tick, cell).map(|msg| (*index, msg))).collect()
+    }
+
+    /// Turn and move the bot at the given index according to its motor values
+    /// # Arguments
+    /// * 'index' - Index of the bot to move
+    fn apply_motion(&mut self, index: usize)
+    {
+        let should_move = match self.board.get_mut_bot_location_at_index(index)
+        {
+            Ok(loc) => loc.accumulate_motion(),
+            Err(_) => false,
+        };
+        if should_move && self.move_bot_forward(index).is_some()
+        {
+            // Blocked by the edge of the board or another bot
+            if let Ok(loc) = self.board.get_mut_bot_location_at_index(index)
+            {
+                loc.clear_forward_progress();
+            }
+        }
+    }
+
+    /// Moves a bot and its signal source to a new index on the board
+    /// # Arguments
+    /// * 'src_index' - Index of BotLocation to be moved
+    /// * 'dest_index' - Index of board to move BotLocation to
+    /// # Returns
+    /// * Option<LocationError> if either coordinate is out of bounds, or if there is no BotLocation
+    ///   in the source, or if the destination already has a bot
+    pub fn move_bot_by_index(&mut self, src_index: usize, dest_index: usize) -> Option<LocationError>
+    {
+        self.board.move_bot_by_index(src_index, dest_index)
     }
 
     /// Moves the bot forward relative to its current facing
@@ -52,7 +330,7 @@ impl BoardController
     /// 'index' - Array index of the bot to be moved
     /// # Returns
     /// * Option<LocationError> if the index is out of bounds or doesn't have a bot, or if the
-    /// destination is out of bounds or already has a bot
+    ///   destination is out of bounds or already has a bot
     pub fn move_bot_forward(&mut self, src: usize) -> Option<LocationError>
     {
         match self.board.get_coord_from_index(&src)
@@ -64,21 +342,13 @@ impl BoardController
                         let delta = BoardController::get_forward_coord_delta(bot.get_facing() as f64);
 
                         //Check bounds of board
-                        let dest_x = (src_coord.x as i8) + delta.0;
-                        let dest_y = (src_coord.y as i8) + delta.1;
-                        if dest_x >= 0 && dest_x < self.board.get_width() as i8
+                        let dest_x = src_coord.x as isize + delta.0 as isize;
+                        let dest_y = src_coord.y as isize + delta.1 as isize;
+                        if dest_x >= 0 && dest_x < self.board.get_width() as isize
+                            && dest_y >= 0 && dest_y < self.board.get_height() as isize
                         {
-                            if dest_y >= 0 && dest_y < self.board.get_height() as i8
-                            {
-                                let dest = self.board.get_index_from_coord(&CoordinatePair { x: dest_x as usize, y: dest_y as usize }).ok().unwrap();
-                                //This shouldn't call for a match, but the compiler freaked out if I just returned self.move_bot_by_coord
-                                return match self.move_bot_by_index(src, dest)
-                                {
-                                    Some(e) => Some(e),
-                                    None => None
-                                }
-
-                            }
+                            let dest = self.board.get_index_from_coord(&CoordinatePair { x: dest_x as usize, y: dest_y as usize }).ok().unwrap();
+                            return self.move_bot_by_index(src, dest);
                         }
                         Some(LocationError::OutOfBounds)
 
@@ -96,12 +366,12 @@ impl BoardController
     /// * 'facing' - the direction the bot is facing in degrees clockwise from north
     /// # Returns
     /// * (i8, i8) - The change in coordinates if the bot were to move forward.
-    /// Note that this is the change relative to the bot's current location,
-    /// *not* the final coordinates of the move
+    ///   Note that this is the change relative to the bot's current location,
+    ///   *not* the final coordinates of the move
     pub fn get_forward_coord_delta(facing: f64) -> (i8, i8)
     {
         let delta_x = facing.to_radians().sin().round() as i8;
-        let delta_y = -1 * facing.to_radians().cos().round() as i8;
+        let delta_y = -(facing.to_radians().cos().round() as i8);
         (delta_x,delta_y)
     }
 }
\ No newline at end of file
@@ -0,0 +1,221 @@
+/*
+ * ffi
+ * Purpose: Run unmodified C kilobot programs, compiled to shared libraries against the kilolib
+ * stand-in in ffi/
+ *
+ * Build a program together with the shim, e.g.
+ *
+ *      cc -shared -fPIC -I ffi -o simple_movement.so ffi/examples/simple_movement.c ffi/kilolib_shim.c
+ *
+ * and give the library's path as the program of a scenario. Every bot loads its own copy of the
+ * library so each one has its own globals, like on the real robots. The kilolib calls the program
+ * makes are forwarded to the kilolib module, so they behave the same as in a Rust port, delay()
+ * included. A program that includes debug.h and calls debug_init() prints to the bot's debug
+ * serial line, like on the real robot.
+ *
+ */
+use std::{fmt, fs, io};
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use libloading::Library;
+use crate::kilobot::messages::Message;
+use crate::kilolib::{self, DistanceMeasurement, Kilolib};
+
+/// Number of libraries loaded so far, to give every copy its own file name
+static LOADED: AtomicUsize = AtomicUsize::new(0);
+
+/// message_t of kilolib.h
+#[repr(C)]
+struct CMessage
+{
+    data: [u8; 9],
+    msg_type: u8,
+    crc: u16,
+}
+
+/// distance_measurement_t of kilolib.h, see estimate_distance() in kilolib_shim.c
+#[repr(C)]
+struct CDistance
+{
+    low_gain: i16,
+    high_gain: i16,
+}
+
+/// Functions the shim forwards hardware calls to. Must match kilo_host_t in kilolib_shim.c
+#[repr(C)]
+struct Host
+{
+    set_motors: extern "C" fn(u8, u8),
+    set_color: extern "C" fn(u8),
+    delay: extern "C" fn(u16),
+    uid: extern "C" fn() -> u16,
+    ticks: extern "C" fn() -> u32,
+    ambientlight: extern "C" fn() -> i16,
+    rand_soft: extern "C" fn() -> u8,
+    rand_seed: extern "C" fn(u8),
+    debug_print: extern "C" fn(*const c_char),
+    pheromone: extern "C" fn() -> i16,
+    deposit_pheromone: extern "C" fn(u16),
+}
+
+extern "C" fn host_set_motors(left: u8, right: u8) { kilolib::set_motors(left, right) }
+extern "C" fn host_set_color(color: u8) { kilolib::set_color(color) }
+extern "C" fn host_delay(ms: u16) { kilolib::delay(ms) }
+extern "C" fn host_uid() -> u16 { kilolib::kilo_uid() }
+extern "C" fn host_ticks() -> u32 { kilolib::kilo_ticks() }
+extern "C" fn host_ambientlight() -> i16 { kilolib::get_ambientlight() }
+extern "C" fn host_rand_soft() -> u8 { kilolib::rand_soft() }
+extern "C" fn host_rand_seed(seed: u8) { kilolib::rand_seed(seed) }
+extern "C" fn host_pheromone() -> i16 { kilolib::get_pheromone() }
+extern "C" fn host_deposit_pheromone(amount: u16) { kilolib::deposit_pheromone(amount) }
+extern "C" fn host_debug_print(text: *const c_char)
+{
+    // SAFETY: the shim passes a nul terminated string that lives until the call returns
+    kilolib::debug_print(&unsafe { CStr::from_ptr(text) }.to_string_lossy())
+}
+
+static HOST: Host = Host {
+    set_motors: host_set_motors,
+    set_color: host_set_color,
+    delay: host_delay,
+    uid: host_uid,
+    ticks: host_ticks,
+    ambientlight: host_ambientlight,
+    rand_soft: host_rand_soft,
+    rand_seed: host_rand_seed,
+    debug_print: host_debug_print,
+    pheromone: host_pheromone,
+    deposit_pheromone: host_deposit_pheromone,
+};
+
+/// Errors that can occur while loading a program
+pub enum FfiError
+{
+    /// The library couldn't be copied
+    Io(io::Error),
+    /// The library couldn't be loaded or is missing a function, usually because it wasn't built
+    /// with kilolib_shim.c
+    Load(libloading::Error),
+}
+
+impl fmt::Display for FfiError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            FfiError::Io(e) => write!(f, "{}", e),
+            FfiError::Load(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// One bot's copy of a C program
+/// # Fields
+/// * 'main' - The program's main(), which registers its callbacks
+/// * 'setup' - kilo_shim_setup, calls the program's setup()
+/// * 'loop_' - kilo_shim_loop, calls the program's loop()
+/// * 'message_tx' - kilo_shim_message_tx, calls kilo_message_tx
+/// * 'message_tx_success' - kilo_shim_message_tx_success, calls kilo_message_tx_success
+/// * 'message_rx' - kilo_shim_message_rx, calls kilo_message_rx
+/// * 'main_done' - Whether main() has been called
+/// * '_library' - The loaded library, which the functions above point into
+pub struct CProgram
+{
+    main: unsafe extern "C" fn() -> c_int,
+    setup: unsafe extern "C" fn(),
+    loop_: unsafe extern "C" fn(),
+    message_tx: unsafe extern "C" fn() -> *const CMessage,
+    message_tx_success: unsafe extern "C" fn(),
+    message_rx: unsafe extern "C" fn(*mut CMessage, *mut CDistance),
+    main_done: bool,
+    _library: Library,
+}
+
+impl CProgram
+{
+    fn setup(&mut self)
+    {
+        // SAFETY: every call into the library is made while kilolib has a bot to give the shim,
+        // from a Kilolib callback, and the library is kept loaded for as long as self exists
+        unsafe {
+            if !self.main_done
+            {
+                self.main_done = true;
+                (self.main)();
+            }
+            (self.setup)();
+        }
+    }
+
+    fn run(&mut self)
+    {
+        // SAFETY: see setup
+        unsafe { (self.loop_)() }
+    }
+
+    fn message_tx(&mut self) -> Option<Message>
+    {
+        // SAFETY: see setup. The message is copied out before anything else runs in the library
+        let msg = unsafe { (self.message_tx)().as_ref() }?;
+        Some(Message::new(msg.msg_type, msg.data))
+    }
+
+    fn message_tx_success(&mut self)
+    {
+        // SAFETY: see setup
+        unsafe { (self.message_tx_success)() }
+    }
+
+    fn message_rx(&mut self, msg: &Message, d: &DistanceMeasurement)
+    {
+        let mut c_msg = CMessage { data: *msg.data(), msg_type: msg.msg_type(), crc: 0 };
+        let mut c_dist = CDistance { low_gain: kilolib::estimate_distance(d) as i16, high_gain: 0 };
+        // SAFETY: see setup. Both pointers are only used for the duration of the call
+        unsafe { (self.message_rx)(&mut c_msg, &mut c_dist) }
+    }
+}
+
+/// Load a C program for one bot
+/// # Arguments
+/// * 'path' - Path of a shared library built with kilolib_shim.c
+/// # Returns
+/// * The program, or FfiError if it couldn't be loaded
+pub fn load(path: &str) -> Result<Kilolib<CProgram>, FfiError>
+{
+    // Loading the same file twice would give back the first copy and its globals, so every bot
+    // loads a copy of its own. The copy can be deleted as soon as it is loaded
+    let extension = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("so");
+    let copy = std::env::temp_dir().join(format!("kilobot-{}-{}.{}", std::process::id(),
+                                                 LOADED.fetch_add(1, Ordering::Relaxed), extension));
+    fs::copy(path, &copy).map_err(FfiError::Io)?;
+    // SAFETY: loading a library runs its initializers, which is up to whoever built it
+    let library = unsafe { Library::new(&copy) };
+    let _ = fs::remove_file(&copy);
+    let library = library.map_err(FfiError::Load)?;
+
+    // SAFETY: the symbols are the functions of kilolib_shim.c and the program's main(), with the
+    // signatures given here, and are only used while the library is loaded
+    let program = unsafe {
+        library.get::<unsafe extern "C" fn(*const Host)>(b"kilo_shim_init\0").map_err(FfiError::Load)?(&HOST);
+        CProgram {
+            main: *library.get(b"main\0").map_err(FfiError::Load)?,
+            setup: *library.get(b"kilo_shim_setup\0").map_err(FfiError::Load)?,
+            loop_: *library.get(b"kilo_shim_loop\0").map_err(FfiError::Load)?,
+            message_tx: *library.get(b"kilo_shim_message_tx\0").map_err(FfiError::Load)?,
+            message_tx_success: *library.get(b"kilo_shim_message_tx_success\0").map_err(FfiError::Load)?,
+            message_rx: *library.get(b"kilo_shim_message_rx\0").map_err(FfiError::Load)?,
+            main_done: false,
+            _library: library,
+        }
+    };
+    let mut kilo = kilolib::kilo_init(program);
+    kilo.kilo_message_tx = CProgram::message_tx;
+    kilo.kilo_message_tx_success = CProgram::message_tx_success;
+    kilo.kilo_message_rx = CProgram::message_rx;
+    // The program's globals live in the library, out of sight
+    kilo.state_size = |_| None;
+    Ok(kilolib::kilo_start(kilo, CProgram::setup, CProgram::run))
+}
@@ -0,0 +1,124 @@
+/*
+ * hal
+ * Purpose: Hardware abstraction layer, everything a program can do with the robot it runs on
+ *
+ * Programs only see the robot through the Hal trait, so the same program can run on a simulated
+ * Kilobot or on any other backend that implements Hal, e.g. one that drives a real robot or
+ * forwards the calls to a remote one. Messages aren't sent through the Hal, the backend calls the
+ * program's message callbacks, see kilobot::program.
+ *
+ */
+use crate::kilobot::MOTOR_MAX_VAL;
+use crate::kilobot::rgb::RGB;
+
+/// The hardware of a kilobot, as seen by the program running on it
+pub trait Hal
+{
+    /// Set the duty cycle of the motors. Equivalent to set_motors()
+    /// # Arguments
+    /// * 'left' - Duty cycle of the left motor, 0 is off
+    /// * 'right' - Duty cycle of the right motor, 0 is off
+    fn set_motors(&mut self, left: u8, right: u8);
+
+    /// Get the duty cycle of the motors
+    /// # Returns
+    /// * The motor values formatted as (left, right)
+    fn get_motor_values(&self) -> (u8, u8);
+
+    /// Set the colour of the LED. Equivalent to set_color()
+    /// # Arguments
+    /// * 'r' - Red level
+    /// * 'g' - Green level
+    /// * 'b' - Blue level
+    fn set_led(&mut self, r: u8, g: u8, b: u8);
+
+    /// Get the colour of the LED
+    fn get_led(&self) -> &RGB;
+
+    /// Set the number of ticks between transmission attempts. Equivalent to kilo_tx_period
+    /// # Arguments
+    /// * 'period' - New transmission period in ticks
+    fn set_tx_period(&mut self, period: u32);
+
+    /// Get the number of ticks between transmission attempts
+    fn get_tx_period(&self) -> u32;
+
+    /// Get the ambient light reading, from 0 in the dark up to board::AMBIENT_LIGHT_MAX right under
+    /// the light. Equivalent to get_ambientlight()
+    fn get_ambient_light(&self) -> u16;
+
+    /// Get the battery voltage in mV. Equivalent to get_voltage()
+    fn get_battery_voltage(&self) -> u16;
+
+    /// Get the number of ticks the bot has been running for. Equivalent to kilo_ticks
+    fn get_ticks(&self) -> u32;
+
+    /// Get the uid of the bot. Equivalent to kilo_uid
+    fn get_uid(&self) -> u16;
+
+    /// Get a random byte. Equivalent to rand_soft()
+    fn rand(&mut self) -> u8;
+
+    /// Reseed the random number generator. Equivalent to rand_seed()
+    /// # Arguments
+    /// * 'seed' - New seed of the generator
+    fn seed_rand(&mut self, seed: u32);
+
+    /// Get the level of virtual pheromone on the space under the bot, from 0 up to
+    /// board::pheromone_map::PHEROMONE_MAX. Always 0 on a backend without a pheromone field
+    fn get_pheromone(&self) -> u16
+    {
+        0
+    }
+
+    /// Deposit virtual pheromone on the space under the bot. It lands at the end of the tick, so
+    /// get_pheromone() doesn't see it until the next one
+    /// # Arguments
+    /// * 'amount' - Pheromone to deposit
+    fn deposit_pheromone(&mut self, _amount: u16) {}
+
+    /// Report CPU time used by the program, so a bot with a CPU budget falls behind when the program
+    /// does more work than its processor could. Scripts and WebAssembly modules are metered
+    /// automatically, other programs report their own work
+    /// # Arguments
+    /// * 'cycles' - Processor cycles used
+    fn spend_cycles(&mut self, _cycles: u32) {}
+
+    /// Write to the bot's debug serial line, like printf with kilolib's debug.h. Usually called
+    /// through debug_print!
+    /// # Arguments
+    /// * 'text' - Text to write, one or more lines
+    fn debug_print(&mut self, _text: &str) {}
+
+    /// Turn left on the spot by spinning the right motor
+    fn turn_left(&mut self)
+    {
+        self.set_motors(0, MOTOR_MAX_VAL);
+    }
+
+    /// Turn right on the spot by spinning the left motor
+    fn turn_right(&mut self)
+    {
+        self.set_motors(MOTOR_MAX_VAL, 0);
+    }
+
+    /// Move straight forward
+    fn move_forward(&mut self)
+    {
+        self.set_motors(MOTOR_MAX_VAL, MOTOR_MAX_VAL);
+    }
+
+    /// Stop moving
+    fn stop(&mut self)
+    {
+        self.set_motors(0, 0);
+    }
+}
+
+/// Write a line to a bot's debug serial line, e.g. debug_print!(bot, "gradient {}", value). Takes
+/// the Hal of the bot followed by the arguments of format!
+macro_rules! debug_print
+{
+    ($bot:expr, $($arg:tt)*) => { $bot.debug_print(&format!($($arg)*)) };
+}
+pub(crate) use debug_print;
@@ -1,12 +1,44 @@
+use std::any::Any;
 use std::fmt;
+use std::collections::VecDeque;
+use crate::hal::Hal;
+use crate::kilobot::bootloader::Bootloader;
+use crate::kilobot::messages::{Message, MessageType};
+use crate::kilobot::program::Program;
+use crate::kilobot::transceiver::Transceiver;
+use crate::programs;
+use crate::rng::Rng;
 
-mod rgb;
-mod transceiver;
-mod messages;
+pub mod rgb;
+pub mod transceiver;
+pub mod messages;
+pub mod program;
+pub mod localization;
+pub mod motion;
+pub mod bootloader;
 /// Max PWN frequency of the kilobot
 pub const MOTOR_MAX_VAL: u8 = 255;
 /// Speed that the bot rotates at in degrees/sec
 pub const ROTATION_SPEED: u16 = 45;
+/// Speed that the bot moves forward at in mm/sec
+pub const FORWARD_SPEED: u16 = 10;
+/// Number of ticks per second, matching the kilolib kilo_ticks clock
+pub const TICKS_PER_SECOND: u32 = 32;
+/// Clock speed of the kilobot's ATmega328P in Hz
+pub const CPU_CLOCK_HZ: u32 = 8_000_000;
+/// Processor cycles the kilobot has in one tick, the CPU budget of a real kilobot
+pub const CYCLES_PER_TICK: u32 = CPU_CLOCK_HZ / TICKS_PER_SECOND;
+/// Bytes of SRAM on the kilobot's ATmega328P, shared by kilolib, the stack and the program's state
+pub const SRAM_BYTES: usize = 2048;
+/// Debug lines a bot holds on to until they are collected, older ones are dropped
+const DEBUG_BUFFER_LINES: usize = 64;
+/// Battery voltage of a fully charged kilobot, in mV
+pub const BATTERY_FULL_MV: u16 = 4200;
+/// Battery voltage below which the kilobot shuts down, in mV
+pub const BATTERY_EMPTY_MV: u16 = 3000;
+/// Accumulated motor duty (sum of both motor values per tick) that drains the battery by 1 mV
+/// Both motors at full power drain 1 mV roughly every 9 seconds
+const BATTERY_DRAIN_PER_MV: u32 = 2 * MOTOR_MAX_VAL as u32 * 9 * TICKS_PER_SECOND;
 
 //Struct representing the kilobot
 /*
@@ -21,89 +53,506 @@ pub struct Kilobot
     led: rgb::RGB,
     uid: u16,
     message_received: bool,
-    //battery_voltage: u8,
+    battery_voltage: u16,
+    battery_drain: u32,
+    ticks: u32,
+    ambient_light: u16,
+    pheromone: u16,
+    deposit: u32,
+    transceiver: Transceiver,
+    rng: Rng,
+    program: Option<Box<dyn Program>>,
+    setup_done: bool,
+    bootloader: Option<Bootloader>,
+    uploads: u32,
+    cpu_budget: Option<u32>,
+    cycles: u64,
+    overruns: u32,
+    loop_skipped: bool,
+    pending_rx: Option<(Message, u16)>,
+    memory_limit: Option<usize>,
+    memory_violation: Option<usize>,
+    checked_state_size: Option<usize>,
+    debug_output: VecDeque<String>,
     //sensors: sensors::Sensors,
-    //setup: fn(),
-    //loop: fn(),
 }
 // TODO: Proper documentation comments
 impl Kilobot
 {
-    /*
-    Kilobots can do the following:
-        Turn right
-        Turn left
-        Move forward
-        Use transceiver - Should always be receiving in background
-        Use LED
+    /// Set what the bot's light sensor reads. Done by the simulation before every tick
+    /// # Arguments
+    /// * 'light' - New ambient light reading
+    pub fn set_ambient_light(&mut self, light: u16)
+    {
+        self.ambient_light = light;
+    }
+
+    /// Set what the bot's pheromone sensor reads. Done by the simulation before every tick
+    /// # Arguments
+    /// * 'level' - New pheromone reading
+    pub fn set_pheromone(&mut self, level: u16)
+    {
+        self.pheromone = level;
+    }
 
-        Turns by spinning one motor
-    */
+    /// Take the pheromone the program deposited since the last call, see Hal::deposit_pheromone
+    pub fn take_deposit(&mut self) -> u32
+    {
+        std::mem::take(&mut self.deposit)
+    }
 
-    //Turn the kilobot left
-    pub fn turn_left(&mut self)
+    /// Returns whether a message was received during the last tick
+    #[allow(dead_code)]
+    pub fn message_received(&self) -> bool
     {
-        self.set_motors(0,MOTOR_MAX_VAL);
+        self.message_received
     }
 
-    //Turn the kilobot right
-    pub fn turn_right(&mut self)
+    /// Get an immutable reference to the bot's transceiver
+    pub fn transceiver(&self) -> &Transceiver
     {
-        self.set_motors(MOTOR_MAX_VAL,0);
+        &self.transceiver
     }
 
-    //Move straight forward
-    pub fn move_forward(&mut self)
+    /// Get a mutable reference to the bot's transceiver
+    pub fn transceiver_mut(&mut self) -> &mut Transceiver
     {
-        self.set_motors(MOTOR_MAX_VAL,MOTOR_MAX_VAL);
+        &mut self.transceiver
     }
 
-    //Stop moving
-    pub fn stop(&mut self)
+    /// Load a program onto the bot. Setup will be run again on the next tick
+    /// # Arguments
+    /// * 'program' - The program to run
+    pub fn set_program(&mut self, program: Box<dyn Program>)
     {
-        self.set_motors(0,0);
+        self.program = Some(program);
+        self.setup_done = false;
+        self.pending_rx = None;
+        self.memory_violation = None;
+        self.check_memory();
     }
 
-    //Sets the motors
-    pub fn set_motors(&mut self, left_m: u8, right_m: u8)
+    /// Limit the size of the program's state, see Program::state_size. It is checked when the
+    /// program is loaded and after every callback, and a program that outgrows it crashes: the bot
+    /// stops and the program is gone until a new one is loaded. Programs whose state can't be measured,
+    /// e.g. C programs, aren't held to it, which is why scenarios refuse to run them with a limit
+    /// # Arguments
+    /// * 'limit' - Largest state in bytes, at most SRAM_BYTES for a real kilobot, or None for no limit
+    pub fn set_memory_limit(&mut self, limit: Option<usize>)
     {
-        self.left_motor = left_m;
-        self.right_motor = right_m;
+        self.memory_limit = limit;
+        self.check_memory();
     }
 
-    //Set the color of the LED
-    pub fn set_led(&mut self, r: u8, g: u8, b: u8)
+    /// Get the limit on the size of the program's state, see set_memory_limit
+    pub fn get_memory_limit(&self) -> Option<usize>
     {
-        self.led.set(rgb::RGB{r,g,b});
+        self.memory_limit
+    }
+
+    /// Get the size of the state that made the program crash, see set_memory_limit
+    /// # Returns
+    /// * The size in bytes, or None if the program hasn't outgrown the limit
+    pub fn get_memory_violation(&self) -> Option<usize>
+    {
+        self.memory_violation
+    }
+
+    /// Get the size of the program's state, see Program::state_size
+    /// # Returns
+    /// * The size in bytes, or None if there is no program or its state can't be measured
+    pub fn get_state_size(&self) -> Option<usize>
+    {
+        self.program.as_ref()?.state_size()
+    }
+
+    /// Get the size of the program's state as measured by the last memory check, see set_memory_limit.
+    /// Unlike get_state_size it doesn't measure again, which can be costly
+    /// # Returns
+    /// * The size in bytes, or None if there is no memory limit or the state can't be measured
+    pub fn get_checked_state_size(&self) -> Option<usize>
+    {
+        self.checked_state_size
+    }
+
+    /// Take the lines the program has written to the debug serial line since the last call, see
+    /// Hal::debug_print. Only the last DEBUG_BUFFER_LINES are kept
+    pub fn take_debug_output(&mut self) -> Vec<String>
+    {
+        self.debug_output.drain(..).collect()
+    }
+
+    /// Crash the program if its state has outgrown the memory limit
+    fn check_memory(&mut self)
+    {
+        // Measuring can be costly, e.g. a script's garbage is collected first, so only do it with a limit
+        self.checked_state_size = None;
+        let limit = match self.memory_limit
+        {
+            Some(limit) => limit,
+            None => return,
+        };
+        self.checked_state_size = self.get_state_size();
+        let size = match self.checked_state_size
+        {
+            Some(size) => size,
+            None => return,
+        };
+        if size > limit
+        {
+            self.program = None;
+            self.memory_violation = Some(size);
+            self.stop();
+        }
+    }
+
+    /// Get the bot's program as its concrete type, to inspect its state
+    /// # Returns
+    /// * The program, or None if the bot has no program or it isn't a T
+    pub fn program_as<T: Program>(&self) -> Option<&T>
+    {
+        let program: &dyn Any = self.program.as_deref()?;
+        program.downcast_ref()
+    }
+
+    /// Get the program's estimate of the bot's position, see Program::position_estimate
+    pub fn get_position_estimate(&self) -> Option<(f64, f64)>
+    {
+        self.program.as_ref()?.position_estimate()
+    }
+
+    /// Get the phase of the program's oscillator, see Program::oscillator_phase
+    pub fn get_oscillator_phase(&self) -> Option<f64>
+    {
+        self.program.as_ref()?.oscillator_phase()
+    }
+
+    /// Returns whether the bot is in its bootloader, waiting for a new program instead of running
+    /// its own, see kilobot::bootloader
+    pub fn in_bootloader(&self) -> bool
+    {
+        self.bootloader.is_some()
+    }
+
+    /// Get the bot's bootloader, if it is in it
+    pub fn get_bootloader(&self) -> Option<&Bootloader>
+    {
+        self.bootloader.as_ref()
+    }
+
+    /// Get the number of programs the bot has received over the air
+    pub fn get_uploads(&self) -> u32
+    {
+        self.uploads
+    }
+
+    /// Limit how much work the program can do per tick. Work past the budget, see Hal::spend_cycles,
+    /// spills into the following ticks: the next loop waits until the work is done, nothing is
+    /// transmitted and received messages are held back until the processor is free again. Like
+    /// kilolib's single receive buffer, only the latest message is held back
+    /// # Arguments
+    /// * 'budget' - Processor cycles per tick, CYCLES_PER_TICK for a real kilobot, or None for a
+    ///   processor that is never too slow, which delivers a held back message straight away
+    pub fn set_cpu_budget(&mut self, budget: Option<u32>)
+    {
+        self.cpu_budget = budget;
+        if budget.is_none()
+        {
+            self.cycles = 0;
+            self.loop_skipped = false;
+            self.deliver_pending_rx();
+        }
+    }
+
+    /// Get the processor cycles per tick the program can use, see set_cpu_budget
+    pub fn get_cpu_budget(&self) -> Option<u32>
+    {
+        self.cpu_budget
+    }
+
+    /// Get the number of times the program has done more work in a tick than the CPU budget allows
+    pub fn get_overruns(&self) -> u32
+    {
+        self.overruns
+    }
+
+    /// Returns whether the processor has more work than fits in the current tick
+    fn cpu_busy(&self) -> bool
+    {
+        self.cpu_budget.is_some_and(|budget| self.cycles > budget as u64)
+    }
+
+    /// Returns whether received messages are held back: for as long as the loop is skipped, i.e.
+    /// the loop was skipped this tick or will be next tick
+    fn rx_held(&self) -> bool
+    {
+        self.loop_skipped || self.cpu_busy()
+    }
+
+    /// Returns whether the battery is too low for the bot to run
+    pub fn battery_empty(&self) -> bool
+    {
+        self.battery_voltage <= BATTERY_EMPTY_MV
+    }
+
+    /// Advance the bot by one tick: drain the battery, then run setup (first tick only) and loop
+    /// A bot with an empty battery stops its motors and doesn't run its program
+    pub fn run_tick(&mut self)
+    {
+        self.message_received = false;
+        self.battery_drain += self.left_motor as u32 + self.right_motor as u32;
+        let drained = (self.battery_drain / BATTERY_DRAIN_PER_MV) as u16;
+        self.battery_drain %= BATTERY_DRAIN_PER_MV;
+        self.battery_voltage = self.battery_voltage.saturating_sub(drained);
+        if self.battery_empty()
+        {
+            self.stop();
+            return;
+        }
+        if self.in_bootloader()
+        {
+            self.ticks += 1;
+            return;
+        }
+        if let Some(budget) = self.cpu_budget
+        {
+            // The last tick's share of the work is done, anything left over delays this loop
+            self.cycles = self.cycles.saturating_sub(budget as u64);
+            self.loop_skipped = self.cycles > 0;
+            if self.loop_skipped
+            {
+                self.ticks += 1;
+                return;
+            }
+            self.deliver_pending_rx();
+        }
+        if !self.setup_done
+        {
+            self.setup_done = true;
+            self.with_program(|program, bot| program.setup(bot));
+        }
+        self.with_program(|program, bot| program.run(bot));
+        self.check_memory();
+        self.ticks += 1;
     }
 
-    //Returns the raw motor values formatted as (left_motor, right_motor)
-    pub fn get_motor_values(&self) -> (u8, u8)
+    /// Ask the program for a message if the transceiver is due to transmit
+    /// # Returns
+    /// * The message to broadcast, or None if nothing is sent this tick
+    pub fn transmit(&mut self) -> Option<Message>
+    {
+        if self.battery_empty() || self.in_bootloader() || self.cpu_busy() || !self.transceiver.tx_due()
+        {
+            return None;
+        }
+        self.with_program(|program, bot| program.message_tx(bot)).flatten()
+    }
+
+    /// Notify the bot that its message was transmitted
+    pub fn transmit_success(&mut self)
+    {
+        self.transceiver.record_tx();
+        self.with_program(|program, bot| program.message_tx_success(bot));
+    }
+
+    /// Deliver a message to the bot
+    /// # Arguments
+    /// * 'msg' - The message being delivered
+    /// * 'dist' - Distance to the sender in mm
+    pub fn receive(&mut self, msg: Message, dist: u16)
+    {
+        if self.battery_empty()
+        {
+            return;
+        }
+        self.message_received = true;
+        if msg.msg_type() >= MessageType::BOOT as u8
+        {
+            self.receive_special(&msg);
+        } else if self.rx_held()
+        {
+            self.pending_rx = Some((msg.clone(), dist));
+        } else if !self.in_bootloader()
+        {
+            self.with_program(|program, bot| program.message_rx(bot, &msg, dist));
+            self.check_memory();
+        }
+        self.transceiver.record_rx(msg, dist, self.ticks);
+    }
+
+    /// Deliver the message held back while the processor was busy, if there is one
+    fn deliver_pending_rx(&mut self)
+    {
+        if let Some((msg, dist)) = self.pending_rx.take()
+        {
+            self.with_program(|program, bot| program.message_rx(bot, &msg, dist));
+            self.check_memory();
+        }
+    }
+
+    /// Handle a message meant for the bot itself rather than its program. Only the bootloader
+    /// messages are simulated
+    /// # Arguments
+    /// * 'msg' - The message, of a type from MessageType::BOOT up
+    fn receive_special(&mut self, msg: &Message)
+    {
+        if msg.msg_type() == MessageType::BOOT as u8
+        {
+            if !self.in_bootloader()
+            {
+                self.stop();
+                self.set_led(0, 0, 0);
+                self.pending_rx = None;
+                self.bootloader = Some(Bootloader::new());
+            }
+            return;
+        }
+        let image = match self.bootloader.as_mut().and_then(|b| b.receive(msg))
+        {
+            Some(image) => image,
+            None => return,
+        };
+        // An image that isn't a program the bot can run counts as corrupt, so the bot throws it
+        // away and keeps waiting in the bootloader
+        match programs::from_image(&image)
+        {
+            Some(program) => {
+                self.bootloader = None;
+                self.uploads += 1;
+                self.set_program(program);
+            },
+            None => self.bootloader = Some(Bootloader::new()),
+        }
+    }
+
+    /// Run a function with the bot's program, if it has one. The program is taken out of the bot
+    /// for the duration of the call so the program and the bot can both be borrowed mutably
+    fn with_program<F, R>(&mut self, func: F) -> Option<R> where F: FnOnce(&mut dyn Program, &mut Kilobot) -> R
+    {
+        let mut program = self.program.take()?;
+        let result = func(program.as_mut(), self);
+        self.program = Some(program);
+        Some(result)
+    }
+}
+
+impl Hal for Kilobot
+{
+    fn set_motors(&mut self, left: u8, right: u8)
+    {
+        self.left_motor = left;
+        self.right_motor = right;
+    }
+
+    fn get_motor_values(&self) -> (u8, u8)
     {
         (self.left_motor, self.right_motor)
     }
 
-    //Return the uid of the bot
-    pub fn get_uid(&self) -> u16
+    fn set_led(&mut self, r: u8, g: u8, b: u8)
+    {
+        self.led.set(rgb::RGB{r,g,b});
+    }
+
+    fn get_led(&self) -> &rgb::RGB
+    {
+        self.led.get()
+    }
+
+    fn set_tx_period(&mut self, period: u32)
+    {
+        self.transceiver.set_tx_period(period);
+    }
+
+    fn get_tx_period(&self) -> u32
+    {
+        self.transceiver.get_tx_period()
+    }
+
+    fn get_ambient_light(&self) -> u16
+    {
+        self.ambient_light
+    }
+
+    fn get_pheromone(&self) -> u16
+    {
+        self.pheromone
+    }
+
+    fn deposit_pheromone(&mut self, amount: u16)
+    {
+        self.deposit = self.deposit.saturating_add(amount as u32);
+    }
+
+    fn get_battery_voltage(&self) -> u16
+    {
+        self.battery_voltage
+    }
+
+    fn get_ticks(&self) -> u32
+    {
+        self.ticks
+    }
+
+    fn get_uid(&self) -> u16
     {
         self.uid
     }
 
+    fn rand(&mut self) -> u8
+    {
+        self.rng.next_u8()
+    }
+
+    fn seed_rand(&mut self, seed: u32)
+    {
+        self.rng = Rng::new(seed);
+    }
+
+    fn debug_print(&mut self, text: &str)
+    {
+        for line in text.lines()
+        {
+            if self.debug_output.len() == DEBUG_BUFFER_LINES
+            {
+                self.debug_output.pop_front();
+            }
+            self.debug_output.push_back(line.to_string());
+        }
+    }
+
+    fn spend_cycles(&mut self, cycles: u32)
+    {
+        if let Some(budget) = self.cpu_budget
+        {
+            if self.cycles <= budget as u64 && self.cycles + cycles as u64 > budget as u64
+            {
+                self.overruns += 1;
+            }
+            self.cycles += cycles as u64;
+        }
+    }
 }
 
 impl fmt::Display for Kilobot
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "(UID:{}, Message Received:{}, left motor:{}, right motor:{})"
+        write!(f, "(UID:{}, Message Received:{}, left motor:{}, right motor:{}, battery:{}mV)"
                , self.uid
-               , self.message_received.to_string()
+               , self.message_received
                , self.left_motor
-               , self.right_motor)
+               , self.right_motor
+               , self.battery_voltage)
     }
 }
 
 //Create a new kilobot
 pub fn new_kilobot(uid: u16) -> Kilobot
 {
-    Kilobot {left_motor: 0, right_motor: 0, led: rgb::new_led(0, 0, 0), uid, message_received: false}
+    let mut rng = Rng::new(uid as u32);
+    let transceiver = Transceiver::new(rng.next_u32());
+    Kilobot {left_motor: 0, right_motor: 0, led: rgb::new_led(0, 0, 0), uid, message_received: false,
+        battery_voltage: BATTERY_FULL_MV, battery_drain: 0, ticks: 0, ambient_light: 0, pheromone: 0, deposit: 0, transceiver, rng, program: None,
+        setup_done: false, bootloader: None, uploads: 0, cpu_budget: None, cycles: 0, overruns: 0, loop_skipped: false, pending_rx: None,
+        memory_limit: None, memory_violation: None, checked_state_size: None, debug_output: VecDeque::new()}
 }
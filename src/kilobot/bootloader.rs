@@ -0,0 +1,164 @@
+/*
+ * bootloader
+ * Purpose: The kilobot bootloader, which receives a new program over IR from the overhead
+ * controller
+ *
+ * A BOOT message sends a bot into its bootloader, where it stops running its program and listens
+ * for the image of a new one. The image is broadcast in chunks, each a BOOTPGM_PAGE message with
+ * the page and offset of the chunk followed by its bytes, and a BOOTPGM_SIZE message gives the
+ * length of the image and its CRC. Once every chunk has arrived and the CRC matches, the bot runs
+ * the new program. Until then it stays in the bootloader, so an upload that is cut short or
+ * corrupted can simply be sent again. See image_messages for the layout of the messages.
+ *
+ */
+use std::collections::BTreeMap;
+use crate::kilobot::messages::{Message, MessageType};
+
+/// Bytes in a page of flash, the unit the real bootloader writes in
+pub const PAGE_SIZE: usize = 128;
+/// Bytes of the image carried by one BOOTPGM_PAGE message
+pub const CHUNK_LEN: usize = 7;
+/// Largest image that fits in the 32 KB of flash
+pub const MAX_IMAGE_LEN: usize = 256 * PAGE_SIZE;
+
+/// CRC-CCITT of some bytes, the same CRC as message_crc() in ffi/kilolib_shim.c
+/// # Arguments
+/// * 'bytes' - Bytes to check
+pub fn crc(bytes: &[u8]) -> u16
+{
+    let mut crc: u16 = 0xffff;
+    for &byte in bytes
+    {
+        let mut data = byte ^ (crc & 0xff) as u8;
+        data ^= data << 4;
+        crc = (((data as u16) << 8) | (crc >> 8)) ^ (data >> 4) as u16 ^ ((data as u16) << 3);
+    }
+    crc
+}
+
+/// Split an image into the messages that upload it: a BOOTPGM_SIZE message with the length of the
+/// image in data[0..2] and its CRC in data[2..4], both little endian, then a BOOTPGM_PAGE message
+/// for every chunk with the page in data[0], the offset within the page in data[1] and the bytes
+/// of the chunk after that
+/// # Arguments
+/// * 'image' - The image, at most MAX_IMAGE_LEN bytes
+/// # Returns
+/// * The messages, in the order they should be sent
+pub fn image_messages(image: &[u8]) -> Vec<Message>
+{
+    assert!(image.len() <= MAX_IMAGE_LEN, "image is larger than the flash");
+    let len = (image.len() as u16).to_le_bytes();
+    let crc = crc(image).to_le_bytes();
+    let mut messages = vec![Message::new(MessageType::BOOTPGM_SIZE as u8, [len[0], len[1], crc[0], crc[1], 0, 0, 0, 0, 0])];
+    for (page_num, page) in image.chunks(PAGE_SIZE).enumerate()
+    {
+        for (chunk_num, chunk) in page.chunks(CHUNK_LEN).enumerate()
+        {
+            let mut data = [0; 9];
+            data[0] = page_num as u8;
+            data[1] = (chunk_num * CHUNK_LEN) as u8;
+            data[2..2 + chunk.len()].copy_from_slice(chunk);
+            messages.push(Message::new(MessageType::BOOTPGM_PAGE as u8, data));
+        }
+    }
+    messages
+}
+
+/// State of a bot's bootloader
+/// # Fields
+/// * 'chunks' - Chunks received so far, by their position in the image
+/// * 'size' - Length and CRC of the image, once a BOOTPGM_SIZE message has arrived
+#[derive(Default)]
+pub struct Bootloader
+{
+    chunks: BTreeMap<usize, [u8; CHUNK_LEN]>,
+    size: Option<(usize, u16)>,
+}
+
+impl Bootloader
+{
+    /// Create a bootloader waiting for an image
+    pub fn new() -> Bootloader
+    {
+        Bootloader::default()
+    }
+
+    /// Handle a message sent to the bootloader
+    /// # Arguments
+    /// * 'msg' - The message. Messages other than BOOTPGM_PAGE and BOOTPGM_SIZE are ignored
+    /// # Returns
+    /// * The image, once all of it has arrived and its CRC matches
+    pub fn receive(&mut self, msg: &Message) -> Option<Vec<u8>>
+    {
+        let data = msg.data();
+        match msg.msg_type()
+        {
+            t if t == MessageType::BOOTPGM_PAGE as u8 => {
+                let position = data[0] as usize * PAGE_SIZE + data[1] as usize;
+                let mut chunk = [0; CHUNK_LEN];
+                chunk.copy_from_slice(&data[2..]);
+                self.chunks.insert(position, chunk);
+            },
+            t if t == MessageType::BOOTPGM_SIZE as u8 => {
+                let size = (u16::from_le_bytes([data[0], data[1]]) as usize, u16::from_le_bytes([data[2], data[3]]));
+                if self.size.is_some_and(|s| s != size)
+                {
+                    // A different image, whatever has arrived so far belongs to the old one
+                    self.chunks.clear();
+                }
+                self.size = Some(size);
+            },
+            _ => return None,
+        }
+        self.image()
+    }
+
+    /// Get the number of bytes of the image received so far
+    /// # Returns
+    /// * (bytes received, length of the image), or None if the length isn't known yet
+    pub fn progress(&self) -> Option<(usize, usize)>
+    {
+        let (len, _) = self.size?;
+        let received = Bootloader::chunk_positions(len).filter(|p| self.chunks.contains_key(p))
+            .map(|p| Bootloader::chunk_len(p, len))
+            .sum();
+        Some((received, len))
+    }
+
+    /// Assemble the image if every chunk has arrived. An image with the wrong CRC is thrown away so
+    /// it can be received again
+    fn image(&mut self) -> Option<Vec<u8>>
+    {
+        let (len, crc) = self.size?;
+        let mut image = Vec::with_capacity(len);
+        for position in Bootloader::chunk_positions(len)
+        {
+            image.extend_from_slice(&self.chunks.get(&position)?[..Bootloader::chunk_len(position, len)]);
+        }
+        if self::crc(&image) != crc
+        {
+            self.chunks.clear();
+            return None;
+        }
+        Some(image)
+    }
+
+    /// Get the number of bytes of the image in a chunk, which is less than CHUNK_LEN for the last
+    /// chunk of a page or of the image
+    /// # Arguments
+    /// * 'position' - Position of the chunk
+    /// * 'len' - Length of the image
+    fn chunk_len(position: usize, len: usize) -> usize
+    {
+        let page_end = (position / PAGE_SIZE + 1) * PAGE_SIZE;
+        CHUNK_LEN.min(page_end.min(len) - position)
+    }
+
+    /// Get the position of every chunk of an image
+    /// # Arguments
+    /// * 'len' - Length of the image
+    fn chunk_positions(len: usize) -> impl Iterator<Item = usize>
+    {
+        (0..len).step_by(PAGE_SIZE).flat_map(move |page| (page..(page + PAGE_SIZE).min(len)).step_by(CHUNK_LEN))
+    }
+}
@@ -0,0 +1,204 @@
+/*
+ * localization
+ * Purpose: Estimate a bot's position from the positions its neighbours share and the distances
+ * measured to them, for programs that need a coordinate system
+ *
+ * Give a Localizer every position heard from a localized neighbour, along with the dist passed to
+ * message_rx, and call estimate() whenever a position is needed. Distances measured by a real
+ * kilobot are noisy, so the last few distances to each neighbour are kept and their median is
+ * used, and neighbours whose distance disagrees with the rest are given less weight in the fit.
+ *
+ */
+use std::collections::VecDeque;
+use std::mem;
+
+/// Fewest neighbours with known positions needed for an estimate
+pub const MIN_ANCHORS: usize = 3;
+/// Number of recent distances kept for each neighbour
+const DIST_SAMPLES: usize = 5;
+/// Number of steps used to refine an estimate
+const FIT_STEPS: usize = 200;
+/// Distance error in mm above which a neighbour counts less and less towards the fit
+const HUBER_MM: f64 = 10.0;
+/// Distance error in mm above which a neighbour is treated as an outlier and left out of the
+/// reported error
+const OUTLIER_MM: f64 = 3.0 * HUBER_MM;
+
+/// A position estimate
+/// # Fields
+/// * 'position' - Estimated position in mm, in the frame of the neighbours' positions
+/// * 'error' - Root mean square error in mm of the distances to the neighbours that agree with
+///   the estimate
+/// * 'anchors' - Number of neighbours that agree with the estimate
+#[derive(Clone, Copy, Debug)]
+pub struct Estimate
+{
+    pub position: (f64, f64),
+    pub error: f64,
+    pub anchors: usize,
+}
+
+/// What is known about one neighbour with a known position
+/// # Fields
+/// * 'uid' - Uid of the neighbour
+/// * 'position' - Position the neighbour last shared
+/// * 'dists' - Most recent distances measured to the neighbour, oldest first
+struct Anchor
+{
+    uid: u16,
+    position: (f64, f64),
+    dists: VecDeque<u16>,
+}
+
+impl Anchor
+{
+    /// Median of the recent distances
+    fn dist(&self) -> f64
+    {
+        let mut dists: Vec<u16> = self.dists.iter().copied().collect();
+        dists.sort_unstable();
+        let mid = dists.len() / 2;
+        if dists.len().is_multiple_of(2)
+        {
+            (dists[mid - 1] as f64 + dists[mid] as f64) / 2.0
+        } else {
+            dists[mid] as f64
+        }
+    }
+}
+
+/// Collects neighbour positions and distances and estimates the bot's own position from them
+#[derive(Default)]
+pub struct Localizer
+{
+    anchors: Vec<Anchor>,
+}
+
+impl Localizer
+{
+    /// Create a new Localizer that doesn't know any neighbours
+    pub fn new() -> Localizer
+    {
+        Localizer::default()
+    }
+
+    /// Record a message from a neighbour with a known position
+    /// # Arguments
+    /// * 'uid' - Uid of the neighbour
+    /// * 'position' - Position the neighbour shared, in mm
+    /// * 'dist' - Measured distance to the neighbour in mm
+    pub fn add(&mut self, uid: u16, position: (f64, f64), dist: u16)
+    {
+        match self.anchors.iter_mut().find(|a| a.uid == uid)
+        {
+            Some(anchor) => {
+                // Distances measured to an old position say nothing about the new one
+                if (anchor.position.0 - position.0).abs() > 1.0 || (anchor.position.1 - position.1).abs() > 1.0
+                {
+                    anchor.dists.clear();
+                }
+                anchor.position = position;
+                if anchor.dists.len() == DIST_SAMPLES
+                {
+                    anchor.dists.pop_front();
+                }
+                anchor.dists.push_back(dist);
+            },
+            None => self.anchors.push(Anchor { uid, position, dists: VecDeque::from([dist]) }),
+        }
+    }
+
+    /// Get the number of bytes the known neighbours take up on the heap, see Program::state_size
+    pub fn heap_size(&self) -> usize
+    {
+        self.anchors.iter().map(|a| mem::size_of::<Anchor>() + a.dists.len() * mem::size_of::<u16>()).sum()
+    }
+
+    /// Forget a neighbour, e.g. because it has started moving
+    /// # Arguments
+    /// * 'uid' - Uid of the neighbour
+    pub fn remove(&mut self, uid: u16)
+    {
+        self.anchors.retain(|a| a.uid != uid);
+    }
+
+    /// Forget every neighbour. A bot that moves has to, its distances are out of date
+    pub fn clear(&mut self)
+    {
+        self.anchors.clear();
+    }
+
+    /// Estimate the bot's position
+    /// # Arguments
+    /// * 'guess' - Previous estimate, if there is one
+    /// # Returns
+    /// * The estimate, or None if fewer than MIN_ANCHORS neighbours are known
+    pub fn estimate(&self, guess: Option<(f64, f64)>) -> Option<Estimate>
+    {
+        if self.anchors.len() < MIN_ANCHORS
+        {
+            return None;
+        }
+        let anchors: Vec<((f64, f64), f64)> = self.anchors.iter().map(|a| (a.position, a.dist())).collect();
+        Some(trilaterate(&anchors, guess))
+    }
+}
+
+/// Estimate a position from distances to points with known positions, by gradient descent on a
+/// Huber loss of the distance errors, which grows linearly rather than quadratically for large
+/// errors so one bad distance can't drag the estimate far. The guess is refined first, and if that
+/// doesn't fit every start point on a circle around the anchors is tried and the best fit is kept,
+/// so a bad first guess can't trap the estimate on the wrong side of its neighbours
+/// # Arguments
+/// * 'anchors' - (position, measured distance) of every neighbour with a known position
+/// * 'guess' - Previous estimate, if there is one
+pub fn trilaterate(anchors: &[((f64, f64), f64)], guess: Option<(f64, f64)>) -> Estimate
+{
+    let n = anchors.len() as f64;
+    let refine = |mut p: (f64, f64)| -> Estimate {
+        for _ in 0..FIT_STEPS
+        {
+            let mut step = (0.0, 0.0);
+            for (a, d) in anchors
+            {
+                let (dx, dy) = (p.0 - a.0, p.1 - a.1);
+                let r = (dx * dx + dy * dy).sqrt().max(1e-6);
+                let e = (r - d).clamp(-HUBER_MM, HUBER_MM);
+                step.0 += e * dx / r;
+                step.1 += e * dy / r;
+            }
+            p = (p.0 - step.0 / n, p.1 - step.1 / n);
+        }
+        let inliers: Vec<f64> = anchors.iter()
+            .map(|(a, d)| ((p.0 - a.0).powi(2) + (p.1 - a.1).powi(2)).sqrt() - d)
+            .filter(|e| e.abs() <= OUTLIER_MM)
+            .collect();
+        let error = if inliers.is_empty()
+        {
+            f64::INFINITY
+        } else {
+            (inliers.iter().map(|e| e * e).sum::<f64>() / inliers.len() as f64).sqrt()
+        };
+        Estimate { position: p, error, anchors: inliers.len() }
+    };
+    // Prefer the fit that agrees with the most neighbours, then the one with the lowest error
+    let better = |a: &Estimate, b: &Estimate| (a.anchors, -a.error) > (b.anchors, -b.error);
+
+    if let Some(fit) = guess.map(refine).filter(|fit| fit.anchors == anchors.len() && fit.error <= HUBER_MM)
+    {
+        return fit;
+    }
+    let centre = anchors.iter().fold((0.0, 0.0), |c, a| (c.0 + (a.0).0 / n, c.1 + (a.0).1 / n));
+    let reach = anchors.iter().map(|a| a.1).sum::<f64>() / n;
+    let mut best = Estimate { position: centre, error: f64::INFINITY, anchors: 0 };
+    for i in 0..8
+    {
+        let (s, c) = (i as f64 * std::f64::consts::PI / 4.0).sin_cos();
+        let fit = refine((centre.0 + reach * s, centre.1 + reach * c));
+        if better(&fit, &best)
+        {
+            best = fit;
+        }
+    }
+    best
+}
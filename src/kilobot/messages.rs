@@ -27,11 +27,16 @@
 
 /// Possible message types that can be sent
 /// Note that not all of the actual kilobot message types are currently included
-enum MessageType
+/// The names are those of kilolib's message_type_t
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[allow(dead_code, non_camel_case_types, clippy::upper_case_acronyms)]
+pub enum MessageType
 {
     NORMAL = 0,
     GPS,
     BOOT = 0x80,
+    BOOTPGM_PAGE,
+    BOOTPGM_SIZE,
     RESET,
     SLEEP,
     WAKEUP,
@@ -48,6 +53,7 @@ enum MessageType
 /// (9 bytes), the type (1 byte), and a CRC (2 bytes). Kilobot documentation does not layout the
 /// structure of the payload, so for the moment it is as follows:
 /// (2) src_uid, (7) data, (1) type, (2) crc.
+#[derive(Clone)]
 pub struct Message
 {
     data: [u8; 9],
@@ -57,6 +63,36 @@ pub struct Message
 
 impl Message
 {
+    /// Create a new message and generate its CRC
+    /// # Arguments
+    /// * 'msg_type' - Type of the message. User messages should use a value between 0 and 127
+    /// * 'data' - Payload of the message
+    pub fn new(msg_type: u8, data: [u8; 9]) -> Message
+    {
+        let mut msg = Message { data, msg_type, msg_crc: 0 };
+        Message::generate_crc(&mut msg);
+        msg
+    }
+
+    /// Get the payload of the message
+    pub fn data(&self) -> &[u8; 9]
+    {
+        &self.data
+    }
+
+    /// Get the type of the message
+    pub fn msg_type(&self) -> u8
+    {
+        self.msg_type
+    }
+
+    /// Returns whether the message is a NORMAL one, as bots send each other, rather than e.g. a GPS
+    /// fix or a message from the environment
+    pub fn is_normal(&self) -> bool
+    {
+        self.msg_type == MessageType::NORMAL as u8
+    }
+
     /// (NOT IMPLEMENTED!) Generate a CRC for a message
     /// # Arguments
     /// * 'message' - CRC will be generated based on the data and type of this message
@@ -64,4 +100,47 @@ impl Message
     {
         msg.msg_crc = 0x00;
     }
+}
+
+/// Heading of a GpsFix whose heading isn't known
+const UNKNOWN_HEADING: u16 = u16::MAX;
+
+/// A position fix, as carried by a GPS message
+/// The position goes in data[0..2] and data[2..4] and the heading in data[4..6], little endian
+/// # Fields
+/// * 'x' - Position along the width of the board in mm, see CoordinatePair::as_mm_tuple
+/// * 'y' - Position along the height of the board in mm
+/// * 'heading' - Direction the bot is facing, in degrees clockwise from north, or None if the
+///   sender doesn't know it
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GpsFix
+{
+    pub x: u16,
+    pub y: u16,
+    pub heading: Option<u16>,
+}
+
+impl GpsFix
+{
+    /// Create a GPS message carrying the fix
+    pub fn to_message(self) -> Message
+    {
+        let (x, y) = (self.x.to_le_bytes(), self.y.to_le_bytes());
+        let heading = self.heading.unwrap_or(UNKNOWN_HEADING).to_le_bytes();
+        Message::new(MessageType::GPS as u8, [x[0], x[1], y[0], y[1], heading[0], heading[1], 0, 0, 0])
+    }
+
+    /// Read the fix carried by a message
+    /// # Returns
+    /// * The fix, or None if the message isn't a GPS message
+    pub fn from_message(msg: &Message) -> Option<GpsFix>
+    {
+        if msg.msg_type() != MessageType::GPS as u8
+        {
+            return None;
+        }
+        let read = |i: usize| u16::from_le_bytes([msg.data()[i], msg.data()[i + 1]]);
+        let heading = Some(read(4)).filter(|h| *h != UNKNOWN_HEADING);
+        Some(GpsFix { x: read(0), y: read(2), heading })
+    }
 }
\ No newline at end of file
@@ -0,0 +1,365 @@
+/*
+ * motion
+ * Purpose: Higher level motion behaviours for use inside programs, built on the kilobot's motor
+ * commands
+ *
+ * A kilobot can only drive forward or turn on the spot, and all it can sense is the distance to the
+ * bots it hears, the ambient light and the virtual pheromone under it. Every behaviour here is a run
+ * and tumble random walk: drive forward for a while, turn on the spot for a while, repeat. Disperse,
+ * Aggregate, Phototaxis and Chemotaxis bias the walk by cutting a run short as soon as things get
+ * worse, the way bacteria follow a chemical gradient. Call step() once every tick from Program::run,
+ * and pass every message from Program::message_rx to the behaviours that need them. Only NORMAL
+ * messages count as neighbours, GPS fixes and messages from the environment come from right
+ * under the bot.
+ *
+ */
+use std::ops::RangeInclusive;
+use crate::hal::Hal;
+use crate::kilobot::messages::Message;
+use crate::kilobot::TICKS_PER_SECOND;
+
+/// Run durations of the biased walks, in ticks
+const TAXIS_RUN: RangeInclusive<u32> = 2 * TICKS_PER_SECOND..=8 * TICKS_PER_SECOND;
+/// Tumble durations of the biased walks, in ticks. Turns of 45 up to 180 degrees
+const TAXIS_TUMBLE: RangeInclusive<u32> = TICKS_PER_SECOND..=4 * TICKS_PER_SECOND;
+
+/// Run and tumble random walk. Runs drive straight forward and tumbles turn on the spot, in a
+/// random direction, each for a random number of ticks
+/// # Fields
+/// * 'run' - Range of run durations in ticks
+/// * 'tumble' - Range of tumble durations in ticks
+/// * 'ticks_left' - Ticks until the current run or tumble ends
+/// * 'tumbling' - Whether the bot is tumbling rather than running
+/// * 'left' - Whether the current tumble turns left
+pub struct RandomWalk
+{
+    run: RangeInclusive<u32>,
+    tumble: RangeInclusive<u32>,
+    ticks_left: u32,
+    tumbling: bool,
+    left: bool,
+}
+
+impl RandomWalk
+{
+    /// Create a new RandomWalk. The walk starts with a tumble
+    /// # Arguments
+    /// * 'run' - Range of run durations in ticks
+    /// * 'tumble' - Range of tumble durations in ticks
+    pub fn new(run: RangeInclusive<u32>, tumble: RangeInclusive<u32>) -> RandomWalk
+    {
+        RandomWalk { run, tumble, ticks_left: 0, tumbling: false, left: false }
+    }
+
+    /// Returns whether the bot is tumbling rather than running
+    pub fn is_tumbling(&self) -> bool
+    {
+        self.tumbling
+    }
+
+    /// Advance the walk by one tick and set the motors
+    /// # Arguments
+    /// * 'bot' - The bot that is walking
+    pub fn step(&mut self, bot: &mut dyn Hal)
+    {
+        if self.ticks_left == 0
+        {
+            if self.tumbling
+            {
+                self.start_run(bot);
+            } else {
+                self.start_tumble(bot);
+            }
+        }
+        self.ticks_left -= 1;
+        match (self.tumbling, self.left)
+        {
+            (false, _) => bot.move_forward(),
+            (true, true) => bot.turn_left(),
+            (true, false) => bot.turn_right(),
+        }
+    }
+
+    /// Cut the current run short and tumble. Does nothing if the bot is already tumbling
+    /// # Arguments
+    /// * 'bot' - The bot that is walking
+    pub fn tumble(&mut self, bot: &mut dyn Hal)
+    {
+        if !self.tumbling
+        {
+            self.start_tumble(bot);
+        }
+    }
+
+    fn start_tumble(&mut self, bot: &mut dyn Hal)
+    {
+        self.tumbling = true;
+        self.ticks_left = RandomWalk::random_ticks(bot, &self.tumble);
+        self.left = bot.rand().is_multiple_of(2);
+    }
+
+    fn start_run(&mut self, bot: &mut dyn Hal)
+    {
+        self.tumbling = false;
+        self.ticks_left = RandomWalk::random_ticks(bot, &self.run);
+    }
+
+    /// Pick a duration from a range with the bot's random number generator. Ranges of up to 256
+    /// ticks only use one random byte
+    fn random_ticks(bot: &mut dyn Hal, range: &RangeInclusive<u32>) -> u32
+    {
+        let span = range.end().saturating_sub(*range.start()) + 1;
+        let random = if span <= 256
+        {
+            bot.rand() as u32
+        } else {
+            u32::from_le_bytes([bot.rand(), bot.rand(), bot.rand(), 0])
+        };
+        (range.start() + random % span).max(1)
+    }
+}
+
+/// Random walk that tumbles whenever a reading gets worse, so it tends to go where readings are
+/// higher
+/// # Fields
+/// * 'walk' - The underlying random walk
+/// * 'last' - The previous reading
+struct Climb
+{
+    walk: RandomWalk,
+    last: Option<f64>,
+}
+
+impl Climb
+{
+    fn new() -> Climb
+    {
+        Climb { walk: RandomWalk::new(TAXIS_RUN, TAXIS_TUMBLE), last: None }
+    }
+
+    /// Advance the walk by one tick
+    /// # Arguments
+    /// * 'bot' - The bot that is walking
+    /// * 'reading' - A new reading, or None if there isn't one this tick
+    fn step(&mut self, bot: &mut dyn Hal, reading: Option<f64>)
+    {
+        if let Some(reading) = reading
+        {
+            // Readings taken while turning on the spot don't get better or worse
+            if !self.walk.is_tumbling() && self.last.is_some_and(|last| reading < last)
+            {
+                self.walk.tumble(bot);
+            }
+            self.last = Some(reading);
+        }
+        self.walk.step(bot);
+    }
+
+    /// Forget the previous reading, e.g. after the bot has stood still for a while
+    fn reset(&mut self)
+    {
+        self.last = None;
+    }
+}
+
+/// Nearest distance heard during one transmission period. Every neighbour is heard about once per
+/// period, so that is how often there is a new reading
+/// # Fields
+/// * 'nearest' - Nearest distance heard so far this period
+/// * 'reading' - Nearest distance heard during the last full period, None if nothing was heard
+#[derive(Default)]
+struct NearestNeighbour
+{
+    nearest: Option<u16>,
+    reading: Option<u16>,
+}
+
+impl NearestNeighbour
+{
+    fn heard(&mut self, msg: &Message, dist: u16)
+    {
+        if msg.is_normal()
+        {
+            self.nearest = Some(self.nearest.map_or(dist, |n| n.min(dist)));
+        }
+    }
+
+    /// Returns whether a period has ended this tick, in which case reading is up to date
+    fn update(&mut self, bot: &dyn Hal) -> bool
+    {
+        if !bot.get_ticks().is_multiple_of(bot.get_tx_period().max(1))
+        {
+            return false;
+        }
+        self.reading = self.nearest.take();
+        true
+    }
+}
+
+/// Move away from neighbours until none of them is closer than a target distance
+/// # Fields
+/// * 'climb' - Walk that favours growing distances
+/// * 'nearest' - Distance to the nearest neighbour
+/// * 'target' - Distance in mm to keep from every neighbour
+pub struct Disperse
+{
+    climb: Climb,
+    nearest: NearestNeighbour,
+    target: u16,
+}
+
+impl Disperse
+{
+    /// Create a new Disperse
+    /// # Arguments
+    /// * 'target' - Distance in mm to keep from every neighbour
+    pub fn new(target: u16) -> Disperse
+    {
+        Disperse { climb: Climb::new(), nearest: NearestNeighbour::default(), target }
+    }
+
+    /// Returns whether no neighbour was closer than the target during the last transmission period
+    pub fn is_settled(&self) -> bool
+    {
+        self.nearest.reading.is_none_or(|n| n >= self.target)
+    }
+
+    /// Record the distance of a message from a neighbour. Call from Program::message_rx
+    /// # Arguments
+    /// * 'msg' - The message, ignored unless it is NORMAL
+    /// * 'dist' - Distance to the sender in mm
+    pub fn heard(&mut self, msg: &Message, dist: u16)
+    {
+        self.nearest.heard(msg, dist);
+    }
+
+    /// Advance by one tick, stopping once settled
+    /// # Arguments
+    /// * 'bot' - The bot that is dispersing
+    pub fn step(&mut self, bot: &mut dyn Hal)
+    {
+        let reading = if self.nearest.update(bot) { self.nearest.reading.map(|n| n as f64) } else { None };
+        if self.is_settled()
+        {
+            bot.stop();
+            self.climb.reset();
+        } else {
+            self.climb.step(bot, reading);
+        }
+    }
+}
+
+/// Move towards neighbours until one of them is within a target distance. A bot that can't hear
+/// anyone wanders until it does
+/// # Fields
+/// * 'climb' - Walk that favours shrinking distances
+/// * 'nearest' - Distance to the nearest neighbour
+/// * 'target' - Distance in mm to get within of a neighbour
+pub struct Aggregate
+{
+    climb: Climb,
+    nearest: NearestNeighbour,
+    target: u16,
+}
+
+impl Aggregate
+{
+    /// Create a new Aggregate
+    /// # Arguments
+    /// * 'target' - Distance in mm to get within of a neighbour
+    pub fn new(target: u16) -> Aggregate
+    {
+        Aggregate { climb: Climb::new(), nearest: NearestNeighbour::default(), target }
+    }
+
+    /// Returns whether a neighbour was within the target during the last transmission period
+    pub fn is_settled(&self) -> bool
+    {
+        self.nearest.reading.is_some_and(|n| n <= self.target)
+    }
+
+    /// Record the distance of a message from a neighbour. Call from Program::message_rx
+    /// # Arguments
+    /// * 'msg' - The message, ignored unless it is NORMAL
+    /// * 'dist' - Distance to the sender in mm
+    pub fn heard(&mut self, msg: &Message, dist: u16)
+    {
+        self.nearest.heard(msg, dist);
+    }
+
+    /// Advance by one tick, stopping once settled
+    /// # Arguments
+    /// * 'bot' - The bot that is aggregating
+    pub fn step(&mut self, bot: &mut dyn Hal)
+    {
+        let updated = self.nearest.update(bot);
+        if self.is_settled()
+        {
+            bot.stop();
+            self.climb.reset();
+            return;
+        }
+        match self.nearest.reading
+        {
+            Some(n) if updated => self.climb.step(bot, Some(-(n as f64))),
+            Some(_) => self.climb.step(bot, None),
+            // Alone, nothing to get closer to
+            None => {
+                self.climb.reset();
+                self.climb.step(bot, None);
+            },
+        }
+    }
+}
+
+/// Move towards the light
+/// # Fields
+/// * 'climb' - Walk that favours brighter light
+pub struct Phototaxis
+{
+    climb: Climb,
+}
+
+impl Phototaxis
+{
+    /// Create a new Phototaxis
+    pub fn new() -> Phototaxis
+    {
+        Phototaxis { climb: Climb::new() }
+    }
+
+    /// Advance by one tick
+    /// # Arguments
+    /// * 'bot' - The bot that is following the light
+    pub fn step(&mut self, bot: &mut dyn Hal)
+    {
+        let light = bot.get_ambient_light() as f64;
+        self.climb.step(bot, Some(light));
+    }
+}
+
+/// Move towards stronger virtual pheromone, see Hal::get_pheromone
+/// # Fields
+/// * 'climb' - Walk that favours more pheromone
+pub struct Chemotaxis
+{
+    climb: Climb,
+}
+
+impl Chemotaxis
+{
+    /// Create a new Chemotaxis
+    pub fn new() -> Chemotaxis
+    {
+        Chemotaxis { climb: Climb::new() }
+    }
+
+    /// Advance by one tick
+    /// # Arguments
+    /// * 'bot' - The bot that is following the pheromone
+    pub fn step(&mut self, bot: &mut dyn Hal)
+    {
+        let level = bot.get_pheromone() as f64;
+        self.climb.step(bot, Some(level));
+    }
+}
@@ -0,0 +1,81 @@
+/*
+ * program
+ * Purpose: Define the interface for the code that runs on a kilobot
+ *
+ * A kilolib program registers a setup() and loop() function with kilo_start, and optionally
+ * callbacks for message transmission and reception. A Program bundles those callbacks together
+ * with whatever state the program needs, so each bot gets its own copy of the "globals".
+ *
+ */
+use std::any::Any;
+use std::mem;
+use crate::hal::Hal;
+use crate::kilobot::messages::Message;
+
+/// Code that runs on a kilobot
+/// Every callback is given the Hal of the bot running the program so it can set motors, the LED,
+/// etc. without depending on whether the bot is simulated
+/// Programs are Any so their state can be inspected from outside, see Kilobot::program_as
+pub trait Program: Send + Any
+{
+    /// Called once before the first call to run. Equivalent to kilolib's setup()
+    /// # Arguments
+    /// * 'bot' - The bot running the program
+    fn setup(&mut self, _bot: &mut dyn Hal) {}
+
+    /// Called once every tick. Equivalent to kilolib's loop()
+    /// # Arguments
+    /// * 'bot' - The bot running the program
+    fn run(&mut self, bot: &mut dyn Hal);
+
+    /// Called whenever the bot is ready to transmit. Equivalent to kilo_message_tx
+    /// # Arguments
+    /// * 'bot' - The bot running the program
+    /// # Returns
+    /// * The message to send, or None if no message should be sent
+    fn message_tx(&mut self, _bot: &mut dyn Hal) -> Option<Message>
+    {
+        None
+    }
+
+    /// Called whenever a message is received. Equivalent to kilo_message_rx
+    /// # Arguments
+    /// * 'bot' - The bot running the program
+    /// * 'msg' - The received message
+    /// * 'dist' - Measured distance to the sender in mm
+    fn message_rx(&mut self, _bot: &mut dyn Hal, _msg: &Message, _dist: u16) {}
+
+    /// Called after a message was transmitted. Equivalent to kilo_message_tx_success
+    /// # Arguments
+    /// * 'bot' - The bot running the program
+    fn message_tx_success(&mut self, _bot: &mut dyn Hal) {}
+
+    /// Get the program's estimate of where the bot is, for programs that localize. Only used to
+    /// measure localization against the real positions, see metrics::SwarmMetrics
+    /// # Returns
+    /// * The estimated position in mm in the program's own frame, or None if it has none
+    fn position_estimate(&self) -> Option<(f64, f64)>
+    {
+        None
+    }
+
+    /// Get the phase of the program's oscillator, for programs that synchronize. Only used to
+    /// measure synchronization, see metrics::SwarmMetrics
+    /// # Returns
+    /// * How far through its cycle the oscillator is, from 0 up to 1, or None if it has none
+    fn oscillator_phase(&self) -> Option<f64>
+    {
+        None
+    }
+
+    /// Get the number of bytes the program's state takes up, to check it fits in the SRAM of a real
+    /// kilobot, see Kilobot::set_memory_limit. Programs that keep state on the heap, e.g. in a Vec,
+    /// add it to the size of the struct. Sizes are measured on the host, where pointers, usize and
+    /// f64 are bigger than on the AVR, so they err on the large side
+    /// # Returns
+    /// * Size of the state in bytes, or None if it can't be measured
+    fn state_size(&self) -> Option<usize>
+    {
+        Some(mem::size_of_val(self))
+    }
+}
@@ -1,6 +1,8 @@
+#[allow(dead_code)]
 pub const LED_OFF: (u8, u8, u8) = (0, 0, 0);
 
 /// Struct representing the kilobot LED
+#[allow(clippy::upper_case_acronyms)]
 pub struct RGB
 {
     pub r: u8,
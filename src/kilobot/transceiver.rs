@@ -16,34 +16,146 @@
  * detecting any contention in the channel.
  * ------------------------------------------------------------------
  */
+use std::collections::VecDeque;
 use crate::kilobot::messages::Message;
+use crate::kilobot::TICKS_PER_SECOND;
 
-/// The kilobot's transceiver, which operates using callbacks
+/// Default number of ticks between transmissions. Kilobots try to send a message twice per second
+pub const DEFAULT_TX_PERIOD: u32 = TICKS_PER_SECOND / 2;
+/// Default broadcast radius in board units. The kilobot IR range is roughly three body lengths
+pub const DEFAULT_TX_RADIUS: f64 = 3.0;
+/// Number of received messages kept for inspection
+pub const MESSAGE_HISTORY_LEN: usize = 8;
+
+/// A message that was delivered to the bot
+/// # Fields
+/// * 'msg' - The message that was received
+/// * 'dist' - Measured distance from the source in mm
+/// * 'tick' - Tick of the receiving bot when the message arrived
+pub struct ReceivedMessage
+{
+    pub msg: Message,
+    pub dist: u16,
+    pub tick: u32,
+}
+
+/// The kilobot's transceiver. The callbacks themselves belong to the bot's Program, the
+/// transceiver keeps track of when the bot may transmit and what it has heard
 /// # Fields
-/// * 'message_received' - 0 if no message received, 1 if message received. Type is u8 to reflect
-/// actual kilobot code
-/// * 'message_tx' - Callback function that is called whenever a message is ready to be transmitted. Returns
-/// a message object to be sent, or null if no message should be sent
-/// * 'message_rx' - Callback function that is called whenever a message is received. Takes a message
-/// object and the measured distance from the source
+/// * 'tx_period' - Number of ticks between transmission attempts, equivalent to kilo_tx_period
+/// * 'tx_timer' - Ticks remaining until the next transmission attempt
+/// * 'tx_radius' - Broadcast radius of the transceiver in board units
+/// * 'last_messages' - Most recently received messages, newest last
+/// * 'tx_count' - Number of messages successfully transmitted
+/// * 'rx_count' - Number of messages received
 /// # Notes
 /// * There is no 'ack' response, a message is transmitted only if there is no contention
 pub struct Transceiver
 {
-    message_received: u8,
-    message_tx: fn() -> Option<Message>,
-    message_rx: fn(msg: Message, dist: u16),
-    message_tx_success: fn(),
-    message_rx_success: fn(),
+    tx_period: u32,
+    tx_timer: u32,
+    tx_radius: f64,
+    last_messages: VecDeque<ReceivedMessage>,
+    tx_count: u32,
+    rx_count: u32,
 }
 
 impl Transceiver
 {
-    /// Sets the callback function to be run when a message is ready to be transmitted
+    /// Create a new Transceiver with the default period and radius
+    /// # Arguments
+    /// * 'tx_offset' - Ticks until the first transmission attempt. Real kilobots are not
+    ///   synchronized, so bots should not all start transmitting on the same tick
+    pub fn new(tx_offset: u32) -> Transceiver
+    {
+        Transceiver {
+            tx_period: DEFAULT_TX_PERIOD,
+            tx_timer: tx_offset % DEFAULT_TX_PERIOD,
+            tx_radius: DEFAULT_TX_RADIUS,
+            last_messages: VecDeque::with_capacity(MESSAGE_HISTORY_LEN),
+            tx_count: 0,
+            rx_count: 0,
+        }
+    }
+
+    /// Count down to the next transmission
+    /// # Returns
+    /// * true if the bot should attempt to transmit this tick
+    pub fn tx_due(&mut self) -> bool
+    {
+        if self.tx_timer == 0
+        {
+            self.tx_timer = self.tx_period.max(1) - 1;
+            true
+        } else {
+            self.tx_timer -= 1;
+            false
+        }
+    }
+
+    /// Record a received message, dropping the oldest one if the history is full
+    /// # Arguments
+    /// * 'msg' - The received message
+    /// * 'dist' - Measured distance from the source in mm
+    /// * 'tick' - Tick the message was received on
+    pub fn record_rx(&mut self, msg: Message, dist: u16, tick: u32)
+    {
+        if self.last_messages.len() == MESSAGE_HISTORY_LEN
+        {
+            self.last_messages.pop_front();
+        }
+        self.last_messages.push_back(ReceivedMessage { msg, dist, tick });
+        self.rx_count += 1;
+    }
+
+    /// Record a successful transmission
+    pub fn record_tx(&mut self)
+    {
+        self.tx_count += 1;
+    }
+
+    /// Set the number of ticks between transmissions
     /// # Arguments
-    /// * 'cb' - Function called to check if a message is ready
-    pub fn set_tx_callback(&mut self, cb : fn() -> Option<Message>)
+    /// * 'period' - New period in ticks. A period of 0 is treated as 1
+    pub fn set_tx_period(&mut self, period: u32)
     {
-        self.message_tx = cb
+        self.tx_period = period.max(1);
+        self.tx_timer = self.tx_timer.min(self.tx_period - 1);
     }
-}
\ No newline at end of file
+
+    /// Get the number of ticks between transmissions
+    pub fn get_tx_period(&self) -> u32
+    {
+        self.tx_period
+    }
+
+    /// Set the broadcast radius in board units
+    pub fn set_tx_radius(&mut self, radius: f64)
+    {
+        self.tx_radius = radius;
+    }
+
+    /// Get the broadcast radius in board units
+    pub fn get_tx_radius(&self) -> f64
+    {
+        self.tx_radius
+    }
+
+    /// Get the most recently received messages, oldest first
+    pub fn last_messages(&self) -> &VecDeque<ReceivedMessage>
+    {
+        &self.last_messages
+    }
+
+    /// Get the number of messages transmitted
+    pub fn get_tx_count(&self) -> u32
+    {
+        self.tx_count
+    }
+
+    /// Get the number of messages received
+    pub fn get_rx_count(&self) -> u32
+    {
+        self.rx_count
+    }
+}
@@ -0,0 +1,436 @@
+/*
+ * kilolib
+ * Purpose: The kilolib.h API on top of the Hal, so C kilobot programs can be ported line by line
+ *
+ * A C program keeps its state in globals, registers its callbacks with kilo_init and kilo_start,
+ * and calls free functions like set_motors() and kilo_ticks to drive the robot. A port keeps the
+ * same shape: the globals become a struct that every callback is given, kilo_init takes their
+ * initial values, and the functions below act on whichever bot is running the callback. For
+ * example the kilolib blinky example becomes
+ *
+ *      #[derive(Default)]
+ *      struct Globals {}
+ *
+ *      fn setup(_g: &mut Globals) {}
+ *
+ *      fn loop_(_g: &mut Globals)
+ *      {
+ *          set_color(RGB(1, 0, 0));
+ *          delay(500);
+ *          set_color(RGB(0, 0, 0));
+ *          delay(500);
+ *      }
+ *
+ *      let mut kilo = kilo_init(Globals::default());
+ *      kilo.kilo_message_rx = message_rx;
+ *      let program = kilo_start(kilo, setup, loop_);
+ *
+ * Differences from the real thing:
+ * - kilo_uid, kilo_ticks and the motor calibration values are functions rather than globals
+ * - delay() doesn't block. The time a call to loop() spends in delays is played back over the
+ *   following ticks, which is when loop() is next called, with the motors and LED set the way they
+ *   were at each point of the call. Changes that last less than a tick, like spinup_motors(), are
+ *   only seen if they happen at the start of a tick
+ * - rand_hard() comes from the same seeded generator as rand_soft(), so runs are reproducible
+ *
+ */
+#![allow(non_snake_case)]
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use crate::hal::Hal;
+use crate::kilobot::{MOTOR_MAX_VAL, TICKS_PER_SECOND};
+use crate::kilobot::messages::Message;
+use crate::kilobot::program::Program;
+
+/// Brightness of each of the 4 levels of an LED channel
+const COLOR_LEVELS: [u8; 4] = [0, 85, 170, 255];
+
+/// The motors and LED of a bot at one point in time
+#[derive(Clone, Copy)]
+struct Outputs
+{
+    motors: (u8, u8),
+    led: (u8, u8, u8),
+}
+
+impl Outputs
+{
+    fn of(bot: &dyn Hal) -> Outputs
+    {
+        let led = bot.get_led();
+        Outputs { motors: bot.get_motor_values(), led: (led.r, led.g, led.b) }
+    }
+
+    fn apply(&self, bot: &mut dyn Hal)
+    {
+        bot.set_motors(self.motors.0, self.motors.1);
+        bot.set_led(self.led.0, self.led.1, self.led.2);
+    }
+}
+
+/// What the kilolib functions act on during a callback
+/// # Fields
+/// * 'bot' - The bot running the callback
+/// * 'elapsed_ms' - Time spent in delay() so far
+/// * 'timeline' - (time in ms, outputs) at the start of every delay()
+struct Context
+{
+    bot: *mut dyn Hal,
+    elapsed_ms: u32,
+    timeline: Vec<(u32, Outputs)>,
+}
+
+thread_local! {
+    /// The callback running on this thread, if any
+    static CURRENT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Clears CURRENT when a callback finishes, even if it panics, so the bot pointer never outlives it
+struct CurrentGuard;
+
+impl Drop for CurrentGuard
+{
+    fn drop(&mut self)
+    {
+        CURRENT.with(|current| current.borrow_mut().take());
+    }
+}
+
+/// Run a callback with the kilolib functions acting on a bot
+/// # Arguments
+/// * 'bot' - The bot running the callback
+/// * 'callback' - The callback
+/// # Returns
+/// * What the callback returned, and the context with the time it spent in delay()
+fn call<R>(bot: &mut dyn Hal, callback: impl FnOnce() -> R) -> (R, Context)
+{
+    // SAFETY: only the lifetime of the trait object changes. The pointer is cleared before call()
+    // returns, see with_context
+    let bot: *mut (dyn Hal + 'static) = unsafe { std::mem::transmute(bot as *mut dyn Hal) };
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        assert!(current.is_none(), "kilolib callbacks can't be nested");
+        *current = Some(Context { bot, elapsed_ms: 0, timeline: Vec::new() });
+    });
+    let guard = CurrentGuard;
+    let result = callback();
+    let context = CURRENT.with(|current| current.borrow_mut().take()).unwrap();
+    drop(guard);
+    (result, context)
+}
+
+/// Run a function on the context of the callback running on this thread
+/// # Panics
+/// * If no kilolib callback is running, the functions have no bot to act on
+fn with_context<R>(func: impl FnOnce(&mut Context, &mut dyn Hal) -> R) -> R
+{
+    CURRENT.with(|current| {
+        let mut current = current.borrow_mut();
+        let context = current.as_mut().expect("kilolib function called outside a kilolib callback");
+        // SAFETY: the pointer was made from the &mut dyn Hal given to call(), which doesn't touch
+        // the bot again until the callback has returned, and CurrentGuard clears it by then
+        let bot = unsafe { &mut *context.bot };
+        func(context, bot)
+    })
+}
+
+/// Returns whether a kilolib callback is running on this thread, so program hosts can refuse calls
+/// that would have no bot to act on instead of panicking, see with_context
+#[cfg(feature = "wasm")]
+pub fn in_callback() -> bool
+{
+    CURRENT.with(|current| current.borrow().is_some())
+}
+
+/// Run a function on the bot of the callback running on this thread, for program hosts that need
+/// more of the bot than kilolib offers, see scripting.rs and wasm.rs. The function can't call back
+/// into kilolib
+/// # Arguments
+/// * 'func' - Function to run on the bot
+#[cfg(any(feature = "scripting", feature = "wasm"))]
+pub fn with_bot<R>(func: impl FnOnce(&mut dyn Hal) -> R) -> R
+{
+    with_context(|_, bot| func(bot))
+}
+
+/// A distance measurement, passed to kilo_message_rx. Read it with estimate_distance()
+pub struct DistanceMeasurement
+{
+    dist: u16,
+}
+
+/// A kilolib program: its globals and the callbacks registered with kilo_init and kilo_start
+/// # Fields
+/// * 'kilo_message_rx' - Called with every message received
+/// * 'kilo_message_tx' - Called when the bot is ready to transmit, returns the message to send
+/// * 'kilo_message_tx_success' - Called after a message was sent
+/// * 'state_size' - Gets the size of the globals in bytes, see Program::state_size. Hosts whose
+///   globals aren't the program's real state, like scripts, measure the real state instead, or return
+///   None if they can't
+/// * 'globals' - The program's state
+/// * 'setup' - Called once before the first loop
+/// * 'loop_' - Called over and over
+/// * 'started' - Whether setup has been called
+/// * 'pending' - Outputs for the ticks the last call to loop spent in delay(), ending with the
+///   outputs it returned with
+pub struct Kilolib<S>
+{
+    pub kilo_message_rx: fn(&mut S, &Message, &DistanceMeasurement),
+    pub kilo_message_tx: fn(&mut S) -> Option<Message>,
+    pub kilo_message_tx_success: fn(&mut S),
+    pub state_size: fn(&S) -> Option<usize>,
+    globals: S,
+    setup: fn(&mut S),
+    loop_: fn(&mut S),
+    started: bool,
+    pending: VecDeque<Outputs>,
+}
+
+/// Initialize a program. Equivalent to kilo_init()
+/// # Arguments
+/// * 'globals' - Initial values of the program's globals
+/// # Returns
+/// * The program, with callbacks that do nothing. Set kilo_message_rx etc. before kilo_start
+pub fn kilo_init<S>(globals: S) -> Kilolib<S>
+{
+    Kilolib {
+        kilo_message_rx: |_, _, _| {},
+        kilo_message_tx: |_| None,
+        kilo_message_tx_success: |_| {},
+        state_size: |globals| Some(std::mem::size_of_val(globals)),
+        globals,
+        setup: |_| {},
+        loop_: |_| {},
+        started: false,
+        pending: VecDeque::new(),
+    }
+}
+
+/// Register setup and loop. Equivalent to kilo_start(), except it returns the program so it can be
+/// given to a bot rather than running it forever
+/// # Arguments
+/// * 'kilo' - Program from kilo_init
+/// * 'setup' - Called once before the first loop
+/// * 'loop_' - Called over and over, loop is a keyword in Rust
+pub fn kilo_start<S>(mut kilo: Kilolib<S>, setup: fn(&mut S), loop_: fn(&mut S)) -> Kilolib<S>
+{
+    kilo.setup = setup;
+    kilo.loop_ = loop_;
+    kilo
+}
+
+impl<S> Kilolib<S>
+{
+    /// Get the program's globals, to inspect its state
+    pub fn globals(&self) -> &S
+    {
+        &self.globals
+    }
+}
+
+impl<S: Send + 'static> Program for Kilolib<S>
+{
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        // Still inside the delays of the last loop
+        if let Some(outputs) = self.pending.pop_front()
+        {
+            outputs.apply(bot);
+            if !self.pending.is_empty()
+            {
+                return;
+            }
+        }
+
+        let (started, setup, loop_, globals) = (self.started, self.setup, self.loop_, &mut self.globals);
+        let (_, context) = call(bot, || {
+            if !started
+            {
+                setup(globals);
+            }
+            loop_(globals);
+        });
+        self.started = true;
+
+        // Each tick the loop spent in delay() gets the outputs it had at the start of that tick
+        let tick_ms = 1000.0 / TICKS_PER_SECOND as f64;
+        let ticks = ((context.elapsed_ms as f64 / tick_ms).ceil() as u32).max(1);
+        let returned = Outputs::of(bot);
+        for tick in 0..ticks
+        {
+            let outputs = context.timeline.iter()
+                .rev()
+                .find(|(start, _)| *start as f64 <= tick as f64 * tick_ms)
+                .map_or(returned, |(_, outputs)| *outputs);
+            if tick == 0
+            {
+                outputs.apply(bot);
+            } else {
+                self.pending.push_back(outputs);
+            }
+        }
+        self.pending.push_back(returned);
+    }
+
+    fn message_tx(&mut self, bot: &mut dyn Hal) -> Option<Message>
+    {
+        let (tx, globals) = (self.kilo_message_tx, &mut self.globals);
+        call(bot, || tx(globals)).0
+    }
+
+    fn message_rx(&mut self, bot: &mut dyn Hal, msg: &Message, dist: u16)
+    {
+        let (rx, globals) = (self.kilo_message_rx, &mut self.globals);
+        call(bot, || rx(globals, msg, &DistanceMeasurement { dist }));
+    }
+
+    fn message_tx_success(&mut self, bot: &mut dyn Hal)
+    {
+        let (tx_success, globals) = (self.kilo_message_tx_success, &mut self.globals);
+        call(bot, || tx_success(globals));
+    }
+
+    fn state_size(&self) -> Option<usize>
+    {
+        (self.state_size)(&self.globals)
+    }
+}
+
+/// Pack an LED color for set_color(). Each channel goes from 0 (off) to 3 (full brightness)
+pub fn RGB(r: u8, g: u8, b: u8) -> u8
+{
+    (r & 3) | ((g & 3) << 2) | ((b & 3) << 4)
+}
+
+/// Set the LED
+/// # Arguments
+/// * 'color' - Color made with RGB()
+pub fn set_color(color: u8)
+{
+    with_context(|_, bot| bot.set_led(COLOR_LEVELS[(color & 3) as usize], COLOR_LEVELS[((color >> 2) & 3) as usize],
+                                      COLOR_LEVELS[((color >> 4) & 3) as usize]));
+}
+
+/// Set the motors. Only the left motor turns the bot left and only the right motor turns it right,
+/// the opposite of Hal::set_motors
+/// # Arguments
+/// * 'left' - Duty cycle of the left motor
+/// * 'right' - Duty cycle of the right motor
+pub fn set_motors(left: u8, right: u8)
+{
+    with_context(|_, bot| bot.set_motors(right, left));
+}
+
+/// Turn both motors on at full power for 15 ms to get them going
+pub fn spinup_motors()
+{
+    set_motors(255, 255);
+    delay(15);
+}
+
+/// Wait for some time, see the notes at the top of the file
+/// # Arguments
+/// * 'ms' - Time to wait in ms
+pub fn delay(ms: u16)
+{
+    with_context(|context, bot| {
+        context.timeline.push((context.elapsed_ms, Outputs::of(bot)));
+        context.elapsed_ms += ms as u32;
+    });
+}
+
+/// Write to the bot's debug serial line, see Hal::debug_print. What printf does once a program has
+/// called debug_init() from kilolib's debug.h
+/// # Arguments
+/// * 'text' - Text to write
+pub fn debug_print(text: &str)
+{
+    with_context(|_, bot| bot.debug_print(text));
+}
+
+/// Get the bot's uid. kilo_uid in kilolib
+pub fn kilo_uid() -> u16
+{
+    with_context(|_, bot| bot.get_uid())
+}
+
+/// Get the number of ticks since the bot started, including the time the current loop has spent in
+/// delay(). kilo_ticks in kilolib
+pub fn kilo_ticks() -> u32
+{
+    with_context(|context, bot| bot.get_ticks() + context.elapsed_ms * TICKS_PER_SECOND / 1000)
+}
+
+/// Left motor duty cycle that turns the bot left. kilo_turn_left in kilolib, simulated bots don't
+/// need calibrating
+pub fn kilo_turn_left() -> u8
+{
+    MOTOR_MAX_VAL
+}
+
+/// Right motor duty cycle that turns the bot right. kilo_turn_right in kilolib
+pub fn kilo_turn_right() -> u8
+{
+    MOTOR_MAX_VAL
+}
+
+/// Left motor duty cycle for driving straight. kilo_straight_left in kilolib
+pub fn kilo_straight_left() -> u8
+{
+    MOTOR_MAX_VAL
+}
+
+/// Right motor duty cycle for driving straight. kilo_straight_right in kilolib
+pub fn kilo_straight_right() -> u8
+{
+    MOTOR_MAX_VAL
+}
+
+/// Get the distance to the sender of a message in mm
+/// # Arguments
+/// * 'd' - Measurement passed to kilo_message_rx
+pub fn estimate_distance(d: &DistanceMeasurement) -> u8
+{
+    d.dist.min(u8::MAX as u16) as u8
+}
+
+/// Get the ambient light, from 0 up to 1023
+pub fn get_ambientlight() -> i16
+{
+    with_context(|_, bot| bot.get_ambient_light() as i16)
+}
+
+/// Get the level of virtual pheromone under the bot, from 0 up to 1023. Not part of kilolib, see
+/// Hal::get_pheromone
+pub fn get_pheromone() -> i16
+{
+    with_context(|_, bot| bot.get_pheromone() as i16)
+}
+
+/// Deposit virtual pheromone under the bot. Not part of kilolib, see Hal::deposit_pheromone
+/// # Arguments
+/// * 'amount' - Pheromone to deposit
+pub fn deposit_pheromone(amount: u16)
+{
+    with_context(|_, bot| bot.deposit_pheromone(amount));
+}
+
+/// Get a random byte
+pub fn rand_soft() -> u8
+{
+    with_context(|_, bot| bot.rand())
+}
+
+/// Get a random byte. The simulator has no noise source, see the notes at the top of the file
+pub fn rand_hard() -> u8
+{
+    rand_soft()
+}
+
+/// Reseed the generator used by rand_soft()
+/// # Arguments
+/// * 'seed' - New seed
+pub fn rand_seed(seed: u8)
+{
+    with_context(|_, bot| bot.seed_rand(seed as u32));
+}
@@ -2,24 +2,192 @@
 use crate::board_controller::BoardController;
 use crate::board::{Board, bot_map, signal_map, CoordinatePair};
 use crate::board::signal_map::SignalSource;
+use crate::board::board_map::BoardMap;
+use crate::scenario::{Scenario, ScenarioError};
+use crate::hal::Hal;
 
 mod hal;
+mod batch;
+mod bench;
 mod board_controller;
 mod kilobot;
+mod kilolib;
 mod board;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod metrics;
+mod overhead;
+mod programs;
+mod rng;
+mod scenario;
+#[cfg(feature = "scripting")]
+mod scripting;
+#[cfg(feature = "wasm")]
+mod wasm;
+mod tui;
 
 pub const PI :f64 = std::f64::consts::PI;
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let result = match args.get(1).map(|s| s.as_str())
+    {
+        Some("bench") => {
+            bench::run();
+            Ok(())
+        },
+        Some("tui") => run_tui(&args[2..]),
+        Some("batch") => run_batch(&args[2..]),
+        Some("metrics") => run_metrics(&args[2..]),
+        _ => {
+            run_tests();
+            Ok(())
+        },
+    };
+    if let Err(e) = result
+    {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Load a scenario from an optional file followed by key=value overrides
+/// # Arguments
+/// * 'args' - e.g. ["swarm.txt", "bots=80"]. Without a file the default scenario is used
+fn load_scenario(args: &[String]) -> Result<Scenario, ScenarioError>
+{
+    let mut scenario = Scenario::default();
+    for (i, arg) in args.iter().enumerate()
+    {
+        match arg.split_once('=')
+        {
+            Some((key, value)) => scenario.set(key, value)?,
+            None if i == 0 => scenario = Scenario::from_file(arg)?,
+            None => return Err(ScenarioError::Parse(format!("unexpected argument '{}'", arg))),
+        }
+    }
+    Ok(scenario)
+}
+
+/// kilobot tui [scenario file] [key=value]...
+/// The side panel shows the debug lines of the bot under the cursor, so there is no --debug-uid
+fn run_tui(args: &[String]) -> Result<(), String>
+{
+    if args.iter().any(|arg| arg == "--debug-uid")
+    {
+        return Err("--debug-uid only works with metrics, the tui shows the debug lines of the bot under the cursor".to_string());
+    }
+    let mut controller = load_scenario(args).and_then(|s| s.build()).map_err(|e| e.to_string())?;
+    tui::run(&mut controller).map_err(|e| format!("terminal error: {}", e))
+}
+
+/// kilobot metrics [scenario file] [key=value]... [every=n] [--debug-uid uid]...
+/// Writes the swarm metrics as CSV every n ticks, once a simulated second by default, and the debug
+/// lines of the given bots to stderr
+fn run_metrics(args: &[String]) -> Result<(), String>
+{
+    let mut every = kilobot::TICKS_PER_SECOND as u64;
+    let mut debug_uids = std::collections::HashSet::new();
+    let mut scenario_args = Vec::new();
+    let mut rest = args.iter();
+    while let Some(arg) = rest.next()
+    {
+        if arg == "--debug-uid"
+        {
+            let value = rest.next().ok_or("--debug-uid needs a uid")?;
+            debug_uids.insert(value.parse::<u16>().map_err(|_| format!("invalid uid '{}'", value))?);
+            continue;
+        }
+        match arg.strip_prefix("every=")
+        {
+            Some(value) => every = value.parse().map_err(|_| format!("invalid value '{}' for every", value))?,
+            None => scenario_args.push(arg.clone()),
+        }
+    }
+    let scenario = load_scenario(&scenario_args).map_err(|e| e.to_string())?;
+    let mut controller = scenario.build().map_err(|e| e.to_string())?;
+    let mut stderr = std::io::stderr();
+    let debug: Option<&mut dyn std::io::Write> = if debug_uids.is_empty()
+    {
+        None
+    } else {
+        controller.set_debug_uids(Some(debug_uids));
+        Some(&mut stderr)
+    };
+    metrics::write_series(&mut controller, scenario.ticks, every, &mut std::io::stdout(), debug)
+        .map_err(|e| e.to_string())
+}
+
+/// kilobot batch <scenario file> [--param key=values]... [--threads n] [--out file]
+/// Debug lines aren't kept, so there is no --debug-uid
+fn run_batch(args: &[String]) -> Result<(), String>
+{
+    let usage = "usage: kilobot batch <scenario file> [--param key=values]... [--threads n] [--out file]";
+    let scenario = Scenario::from_file(args.first().ok_or(usage)?).map_err(|e| e.to_string())?;
+    let mut grid = Vec::new();
+    let mut threads = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut out_path = None;
+    let mut rest = args[1..].iter();
+    while let Some(flag) = rest.next()
+    {
+        let value = rest.next().ok_or(usage)?;
+        match flag.as_str()
+        {
+            "--param" => grid.push(batch::parse_param(value).map_err(|e| e.to_string())?),
+            "--threads" => threads = value.parse().map_err(|_| usage)?,
+            "--out" => out_path = Some(value),
+            "--debug-uid" => return Err("--debug-uid only works with metrics, batch runs don't keep debug lines".to_string()),
+            _ => return Err(usage.to_string()),
+        }
+    }
+    let result = match out_path
+    {
+        Some(path) => {
+            let mut file = std::fs::File::create(path).map_err(|e| e.to_string())?;
+            batch::run_batch(&scenario, &grid, threads, &mut file)
+        },
+        None => batch::run_batch(&scenario, &grid, threads, &mut std::io::stdout()),
+    };
+    result.map_err(|e| e.to_string())
+}
+
+fn run_tests()
+{
     let mut new_bot = kilobot::new_kilobot(0);
     test_bot(&mut new_bot);
-    //let new_board = Board::new(5, 5);
-    //test_board(&mut new_board);
-    //let new_board_controller = &mut BoardController{ board: new_board};
-    //test_board_controller(new_board_controller);
-    //test_math();
+    let mut new_bot_map = bot_map::BotMap::new(5, 5);
+    test_bot_map(&mut new_bot_map);
+    let new_board_controller = &mut BoardController::new(Board::new(5, 5));
+    test_board_controller(new_board_controller);
+    test_math();
     let mut sig_map = signal_map::SignalMap::new(5,5);
     test_signal_map(&mut sig_map);
+    test_signal_queries();
+    test_source_follows_bot();
+    test_scenario();
+    test_metrics();
+    test_gradient();
+    test_firefly();
+    test_localization();
+    test_self_assembly();
+    test_motion();
+    test_kilolib();
+    test_hal();
+    test_upload();
+    test_cpu_budget();
+    test_memory_limit();
+    test_debug_print();
+    test_environment();
+    test_gps();
+    test_pheromone();
+    #[cfg(feature = "parallel")]
+    test_parallel_matches_serial();
+    #[cfg(feature = "ffi")]
+    test_ffi();
+    #[cfg(feature = "scripting")]
+    test_scripting();
+    #[cfg(feature = "wasm")]
+    test_wasm();
 
 }
 
@@ -42,15 +210,72 @@ fn test_bot(bot: &mut kilobot::Kilobot)
     assert_eq!(bot.get_motor_values(), (kilobot::MOTOR_MAX_VAL, kilobot::MOTOR_MAX_VAL));
 }
 
+/// A Hal that isn't a simulated Kilobot, standing in for a real or remote robot
+struct TestHal
+{
+    motors: (u8, u8),
+    led: kilobot::rgb::RGB,
+    tx_period: u32,
+    ticks: u32,
+    uid: u16,
+    rng: rng::Rng,
+}
+
+impl Hal for TestHal
+{
+    fn set_motors(&mut self, left: u8, right: u8) { self.motors = (left, right); }
+    fn get_motor_values(&self) -> (u8, u8) { self.motors }
+    fn set_led(&mut self, r: u8, g: u8, b: u8) { self.led = kilobot::rgb::new_led(r, g, b); }
+    fn get_led(&self) -> &kilobot::rgb::RGB { &self.led }
+    fn set_tx_period(&mut self, period: u32) { self.tx_period = period; }
+    fn get_tx_period(&self) -> u32 { self.tx_period }
+    fn get_ambient_light(&self) -> u16 { 0 }
+    fn get_battery_voltage(&self) -> u16 { kilobot::BATTERY_FULL_MV }
+    fn get_ticks(&self) -> u32 { self.ticks }
+    fn get_uid(&self) -> u16 { self.uid }
+    fn rand(&mut self) -> u8 { self.rng.next_u8() }
+    fn seed_rand(&mut self, seed: u32) { self.rng = rng::Rng::new(seed); }
+}
+
+fn test_hal()
+{
+    let new_hal = |uid| TestHal { motors: (0, 0), led: kilobot::rgb::new_led(0, 0, 0), tx_period: 16, ticks: 0, uid,
+                                  rng: rng::Rng::new(uid as u32) };
+
+    // Programs run the same on any Hal
+    let mut seed = new_hal(programs::gradient::SEED_UID);
+    let mut program = programs::from_name("gradient").unwrap();
+    program.setup(&mut seed);
+    program.run(&mut seed);
+    let msg = program.message_tx(&mut seed).unwrap();
+    assert_eq!((seed.get_led().r, seed.get_led().g, seed.get_led().b), (255, 0, 0));
+
+    let mut other = new_hal(5);
+    let mut program = programs::from_name("gradient").unwrap();
+    program.setup(&mut other);
+    program.message_rx(&mut other, &msg, 40);
+    program.run(&mut other);
+    assert_eq!((other.get_led().r, other.get_led().g, other.get_led().b), (255, 255, 0));
+    assert_eq!(program.message_tx(&mut other).unwrap().data()[0], 1);
+
+    // kilolib programs too
+    let mut hal = new_hal(1);
+    let mut program = programs::from_name("simple_movement").unwrap();
+    program.setup(&mut hal);
+    program.run(&mut hal);
+    assert_eq!(hal.get_motor_values(), (kilobot::MOTOR_MAX_VAL, kilobot::MOTOR_MAX_VAL));
+    assert_eq!(hal.get_led().g, 85);
+}
+
 fn test_bot_map(bot_map: &mut bot_map::BotMap)
 {
     bot_map.add_new_bot_at_index(kilobot::new_kilobot(1), 0, 0);
     bot_map.add_new_bot_at_index(kilobot::new_kilobot(2), 0, 0);
     bot_map.add_new_bot_at_index(kilobot::new_kilobot(3), 12, 0);
-    bot_map.remove_bot_location_at_index(0);
+    bot_map.remove_bot_location_at_index(0).ok().unwrap();
     println!("Board: {}", bot_map);
     bot_map.print_board();
-    bot_map.remove_bot_location_at_index(12);
+    bot_map.remove_bot_location_at_index(12).ok().unwrap();
     println!("Board: {}", bot_map);
     bot_map.print_board();
     bot_map.add_new_bot_at_index(kilobot::new_kilobot(1), 6, 0);
@@ -60,35 +285,35 @@ fn test_bot_map(bot_map: &mut bot_map::BotMap)
 
 fn test_board_controller(board_controller: &mut board_controller::BoardController)
 {
-    test_bot_map(&mut board_controller.board.bot_map);
+    board_controller.board.add_new_bot_at_index(kilobot::new_kilobot(1), 6, 0);
     board_controller.move_bot_by_index(6,0);
-    println!("Board: {}", board_controller.board.bot_map);
-    board_controller.board.bot_map.print_board();
-    board_controller.board.bot_map.add_new_bot_at_index(kilobot::new_kilobot(3), 12, 180);
+    println!("Board: {}", board_controller.board.bot_map());
+    board_controller.board.bot_map().print_board();
+    board_controller.board.add_new_bot_at_index(kilobot::new_kilobot(3), 12, 180);
     board_controller.move_bot_by_index(0,4);
     board_controller.move_bot_by_index(12,13);
     board_controller.move_bot_by_index(13,18);
     board_controller.move_bot_by_index(4,9);
     println!("Board: {}", board_controller.board);
-    board_controller.board.bot_map.print_board();
-    board_controller.board.bot_map.add_new_bot_at_index(kilobot::new_kilobot(4), 6, 135);
+    board_controller.board.bot_map().print_board();
+    board_controller.board.add_new_bot_at_index(kilobot::new_kilobot(4), 6, 135);
     board_controller.move_bot_forward(6);
     board_controller.move_bot_forward(18);
     println!("Board: {}", board_controller.board);
-    board_controller.board.bot_map.print_board();
+    board_controller.board.bot_map().print_board();
     board_controller.move_bot_forward(23);
     println!("Board: {}", board_controller.board);
-    board_controller.board.bot_map.print_board();
+    board_controller.board.bot_map().print_board();
 
 }
 
 fn test_signal_map(sig_map: &mut signal_map::SignalMap)
 {
     println!("{}", sig_map);
-    let mut new_src = SignalSource::new(CoordinatePair::new(0,1),1.5);
+    let mut new_src = SignalSource::new(1, CoordinatePair::new(0,1),1.5);
     sig_map.add_new_source(new_src);
-    sig_map.remove_source_at_coord(&CoordinatePair::new(0,1));
-    new_src = SignalSource::new(CoordinatePair::new(2,2),2.0);
+    sig_map.remove_source_at_coord(&CoordinatePair::new(0,1)).ok().unwrap();
+    new_src = SignalSource::new(2, CoordinatePair::new(2,2),2.0);
     sig_map.add_new_source(new_src);
     sig_map.print_signal_map_to_console();
     let c = sig_map.get_source_at_coord(&CoordinatePair::new(2,2)).ok().unwrap().coord.clone();
@@ -98,5 +323,1294 @@ fn test_signal_map(sig_map: &mut signal_map::SignalMap)
 
 }
 
+fn test_signal_queries()
+{
+    let mut board = Board::new(6, 6);
+    for (uid, index) in [(1_u16, 0_usize), (2, 2), (300, 14)].iter()
+    {
+        let mut bot = kilobot::new_kilobot(*uid);
+        bot.transceiver_mut().set_tx_radius(2.0);
+        board.add_new_bot_at_index(bot, *index, 0);
+    }
+    // Bot 1 at (0,0), bot 2 at (2,0), bot 300 at (2,2)
+    let hearing: Vec<u16> = board.get_bots_hearing(2).ok().unwrap().iter().map(|l| l.0).collect();
+    assert_eq!(hearing, vec![1, 300]);
+    let heard = board.get_bots_heard_by(1).ok().unwrap();
+    assert_eq!(heard.len(), 1);
+    assert_eq!(heard[0].0, 2);
+    assert_eq!(heard[0].1, 2.0);
+    assert!(board.get_bots_heard_by(7).is_err());
+}
+
+fn test_source_follows_bot()
+{
+    let mut controller = BoardController::new(Board::new(8, 8));
+    controller.board.add_new_bot_at_index(kilobot::new_kilobot(1), 0, bot_map::TURN_STEP * 3);
+    controller.board.add_new_bot_at_index(kilobot::new_kilobot(2), 7, 0);
+    assert!(controller.board.get_bots_heard_by(2).ok().unwrap().is_empty());
+    // Walk bot 1 diagonally towards the middle of the board, then next to bot 2
+    for _ in 0..3
+    {
+        let index = controller.board.get_index_of_uid(1).ok().unwrap();
+        assert!(controller.move_bot_forward(index).is_none());
+    }
+    assert!(controller.move_bot_by_index(27, 6).is_none());
+    let heard = controller.board.get_bots_heard_by(2).ok().unwrap();
+    assert_eq!(heard.len(), 1);
+    assert_eq!(heard[0].0, 1);
+
+    // Coverage after moving must match a map where the source was added in place
+    let mut fresh = signal_map::SignalMap::new(8, 8);
+    fresh.add_new_source(SignalSource::new(1, CoordinatePair::new(6, 0), controller.board.signal_map()
+        .get_source_by_uid(1).ok().unwrap().radius));
+    fresh.add_new_source(SignalSource::new(2, CoordinatePair::new(7, 0), controller.board.signal_map()
+        .get_source_by_uid(2).ok().unwrap().radius));
+    for index in 0..fresh.len()
+    {
+        let coord = fresh.get_coord_from_index(&index).ok().unwrap();
+        assert_eq!(fresh.get_signals_at_coord(&coord).ok().unwrap().sources,
+                   controller.board.signal_map().get_signals_at_coord(&coord).ok().unwrap().sources);
+    }
+    assert!(controller.board.remove_bot_location_at_index(6).is_ok());
+    assert!(controller.board.get_bots_hearing(1).is_err());
+
+    // Uids are unique, a second bot 2 is turned away without touching the first one
+    let duplicate = controller.board.add_new_bot_at_index(kilobot::new_kilobot(2), 20, 0);
+    assert!(matches!(duplicate, Some(board::LocationError::DuplicateUid)));
+    assert_eq!((controller.board.num_bots(), controller.board.get_occupied_indices()), (1, vec![7]));
+    assert!(controller.board.get_index_of_uid(2).ok() == Some(7) && !controller.board.index_has_bot(20).ok().unwrap());
+}
+
+fn test_scenario()
+{
+    let scenario = Scenario::parse("# comment\nwidth = 12\nbots=20 # trailing comment\n\nseed = 3").ok().unwrap();
+    assert_eq!((scenario.width, scenario.height, scenario.bots, scenario.seed), (12, 20, 20, 3));
+    assert!(Scenario::parse("bots = many").is_err());
+    assert!(Scenario::parse("colour = red").is_err());
+    // Every bot needs its own uid
+    assert!(Scenario::parse("bots = 70000").is_err());
+    assert!(Scenario::parse("tx_radius = -1").is_err() && Scenario::parse("tx_radius = NaN").is_err());
+    // An upload is a file or a program name, a mistyped path isn't sent as an image
+    assert!(matches!(Scenario::parse("upload = scenarios/missing.wasm").ok().unwrap().build(), Err(ScenarioError::Io(_))));
+    assert!(Scenario::parse("upload = gradient").ok().unwrap().build().is_ok());
+    assert!(Scenario { width: 300, height: 300, bots: 70_000, ..Scenario::default() }.build().is_err());
+    let controller = scenario.build().ok().unwrap();
+    assert_eq!(controller.board.num_bots(), 20);
+
+    // Program groups, the last one may leave out its count and takes the remaining bots
+    let groups = |program: &str| Scenario { bots: 4, program: program.to_string(), ..Scenario::default() };
+    assert_eq!(groups("gradient:1 wander").bot_programs().ok().unwrap(), vec!["gradient", "wander", "wander", "wander"]);
+    assert_eq!(groups("gradient:2 wander:2").bot_programs().ok().unwrap(), vec!["gradient", "gradient", "wander", "wander"]);
+    assert!(groups("gradient wander").bot_programs().is_err());
+    assert!(groups("gradient:x wander").bot_programs().is_err());
+    assert!(groups("gradient:2 wander:1").bot_programs().is_err());
+    assert!(groups("gradient:1 nothing").build().is_err());
+
+    let grid = vec![batch::parse_param("seed=1..3").ok().unwrap(), batch::parse_param("bots=5,10").ok().unwrap()];
+    assert_eq!(grid[0].1, vec!["1", "2", "3"]);
+    let quick = Scenario { ticks: 50, ..scenario };
+    let mut csv: Vec<u8> = Vec::new();
+    assert!(batch::run_batch(&quick, &grid, 4, &mut csv).is_ok());
+    let csv = String::from_utf8(csv).unwrap();
+    let rows: Vec<&str> = csv.lines().collect();
+    assert_eq!(rows.len(), 7);
+    assert!(rows[0].starts_with("run,width,height,bots,seed"));
+    assert!(rows[6].starts_with("5,12,20,10,3,"));
+    assert!(rows[0].ends_with(",error") && rows[6].ends_with(','));
 
+    // A run that fails still gets its row, with the reason why
+    let grid = vec![batch::parse_param("bots=10,500").ok().unwrap()];
+    let mut csv: Vec<u8> = Vec::new();
+    assert!(batch::run_batch(&quick, &grid, 2, &mut csv).is_ok());
+    let csv = String::from_utf8(csv).unwrap();
+    let rows: Vec<&str> = csv.lines().collect();
+    assert_eq!(rows.len(), 3);
+    assert!(rows.iter().all(|row| row.split(',').count() == rows[0].split(',').count()));
+    assert!(rows[2].starts_with("1,12,20,500,") && rows[2].ends_with(",more bots than spaces on the board"));
+}
+
+fn test_metrics()
+{
+    // Two pairs of bots out of range of each other, all facing east
+    let mut board = Board::new(10, 10);
+    for (uid, index) in [(0, 0), (1, 2), (2, 88), (3, 99)]
+    {
+        board.add_new_bot_at_index(kilobot::new_kilobot(uid), index, 90);
+    }
+    let m = metrics::SwarmMetrics::compute(&board);
+    assert_eq!((m.components, m.largest_cluster), (2, 2));
+    assert!((m.polarization - 1.0).abs() < 1e-9);
+    // Nearest neighbours are 2, 2, sqrt(2) and sqrt(2) apart
+    assert!((m.mean_nn_distance.unwrap() - (2.0 + 2.0_f64.sqrt()) / 2.0).abs() < 1e-9);
+    assert!(m.coverage > 0.0 && m.coverage < 1.0);
+
+    // Facing opposite ways cancels out
+    for index in [2, 88, 99]
+    {
+        assert!(board.remove_bot_location_at_index(index).is_ok());
+    }
+    board.add_new_bot_at_index(kilobot::new_kilobot(1), 2, 270);
+    let m = metrics::SwarmMetrics::compute(&board);
+    assert!(m.polarization < 1e-9);
+    assert_eq!((m.components, m.largest_cluster), (1, 2));
+
+    let mut csv: Vec<u8> = Vec::new();
+    let mut controller = Scenario { ticks: 64, ..Scenario::default() }.build().ok().unwrap();
+    assert!(metrics::write_series(&mut controller, 64, 32, &mut csv, None).is_ok());
+    let csv = String::from_utf8(csv).unwrap();
+    assert_eq!(csv.lines().count(), 4);
+    assert!(csv.lines().last().unwrap().starts_with("64,"));
+}
+
+fn test_gradient()
+{
+    let scenario = Scenario { width: 30, height: 30, bots: 91, placement: "hex".to_string(),
+                              program: "gradient".to_string(), ..Scenario::default() };
+    let mut controller = scenario.build().ok().unwrap();
+    for _ in 0..10 * kilobot::TICKS_PER_SECOND
+    {
+        controller.step();
+    }
+
+    // Breadth first search of the communication graph from the seed gives the expected hop counts
+    let board = &controller.board;
+    let mut hops = std::collections::HashMap::new();
+    let mut queue = std::collections::VecDeque::new();
+    hops.insert(programs::gradient::SEED_UID, 0);
+    queue.push_back(programs::gradient::SEED_UID);
+    while let Some(uid) = queue.pop_front()
+    {
+        let next = hops[&uid] + 1;
+        for (neighbour, _) in board.get_bots_hearing(uid).ok().unwrap()
+        {
+            if let std::collections::hash_map::Entry::Vacant(entry) = hops.entry(neighbour)
+            {
+                entry.insert(next);
+                queue.push_back(neighbour);
+            }
+        }
+    }
+    assert_eq!(hops.len(), 91);
+    assert!(*hops.values().max().unwrap() >= 5);
+    for index in board.get_occupied_indices()
+    {
+        let bot = board.get_bot_at_index(index).ok().unwrap();
+        let gradient = bot.program_as::<programs::gradient::Gradient>().unwrap();
+        assert_eq!(gradient.get_value(), hops[&bot.get_uid()]);
+    }
+    // The seed is in the middle of the swarm
+    let centre = board.get_index_from_coord(&CoordinatePair::new(15, 15)).ok().unwrap();
+    assert_eq!(board.get_bot_at_index(centre).ok().unwrap().get_uid(), programs::gradient::SEED_UID);
+}
+
+fn test_firefly()
+{
+    let mut controller = Scenario::from_file("scenarios/firefly.txt").ok().unwrap().build().ok().unwrap();
+    let spread = |controller: &BoardController| metrics::SwarmMetrics::compute(&controller.board).phase_spread.unwrap();
+    controller.step();
+    assert!(spread(&controller) > 0.5);
+    for _ in 0..20 * kilobot::TICKS_PER_SECOND
+    {
+        controller.step();
+    }
+    assert!(spread(&controller) < 0.01);
+
+    assert!(metrics::phase_spread(&[]).is_none());
+    assert!(metrics::phase_spread(&[0.25, 0.25]).unwrap() < 1e-9);
+    assert!((metrics::phase_spread(&[0.0, 0.5]).unwrap() - 1.0).abs() < 1e-9);
+}
+
+fn test_motion()
+{
+    let scenario = Scenario::parse("width = 30\nheight = 30\nbots = 30\nprogram = phototaxis\nlight = 5 5").ok().unwrap();
+    assert_eq!(scenario.get("light").unwrap(), "5 5");
+    assert!(Scenario::parse("light = 5").is_err());
+    let run = |controller: &mut BoardController, seconds: u32| {
+        for _ in 0..seconds * kilobot::TICKS_PER_SECOND
+        {
+            controller.step();
+        }
+    };
+
+    // Phototaxis brings the swarm closer to the light
+    let mut controller = scenario.build().ok().unwrap();
+    let light_dist = |controller: &BoardController| {
+        let board = &controller.board;
+        let indices = board.get_occupied_indices();
+        indices.iter().map(|i| {
+            let coord = board.get_coord_from_index(i).ok().unwrap();
+            (coord.x as f64 - 5.0).hypot(coord.y as f64 - 5.0)
+        }).sum::<f64>() / indices.len() as f64
+    };
+    let start = light_dist(&controller);
+    run(&mut controller, 240);
+    assert!(light_dist(&controller) < start - 3.0);
+
+    // Disperse spreads out a packed swarm and aggregate gathers a scattered one
+    let nn = |controller: &BoardController| metrics::SwarmMetrics::compute(&controller.board).mean_nn_distance.unwrap();
+    let mut controller = Scenario::parse("width = 40\nheight = 40\nbots = 30\nprogram = disperse\nplacement = half_disc").ok().unwrap().build().ok().unwrap();
+    assert!(nn(&controller) < 1.5);
+    run(&mut controller, 180);
+    assert!(nn(&controller) > 2.5);
+
+    let mut controller = Scenario::parse("width = 30\nheight = 30\nbots = 30\nprogram = aggregate").ok().unwrap().build().ok().unwrap();
+    let start = nn(&controller);
+    run(&mut controller, 300);
+    assert!(nn(&controller) < start - 1.0);
+}
+
+fn test_kilolib()
+{
+    use kilolib::*;
+    // The delays of simple_movement play out over the following ticks
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(Box::new(programs::simple_movement::new()));
+    let mut outputs = Vec::new();
+    for _ in 0..211
+    {
+        bot.run_tick();
+        let led = bot.get_led();
+        outputs.push((bot.get_motor_values(), (led.r, led.g, led.b)));
+    }
+    assert_eq!(outputs[32], ((255, 255), (0, 85, 0)));
+    // kilolib's left motor turns left, which is the right motor of Kilobot::set_motors
+    assert_eq!(outputs[96], ((0, 255), (85, 0, 0)));
+    assert_eq!(outputs[160], ((255, 0), (0, 0, 85)));
+    assert_eq!(outputs[200], ((0, 0), (0, 0, 0)));
+    assert_eq!(outputs[210], ((255, 255), (0, 85, 0)));
+
+    #[derive(Default)]
+    struct Globals
+    {
+        uid: u16,
+        waited: u32,
+        heard: u16,
+        dist: u8,
+        light: i16,
+        random: (u8, u8),
+    }
+    fn setup(g: &mut Globals)
+    {
+        g.uid = kilo_uid();
+        rand_seed(1);
+        g.random = (rand_soft(), rand_hard());
+        g.light = get_ambientlight();
+    }
+    fn loop_(g: &mut Globals)
+    {
+        let start = kilo_ticks();
+        delay(1000);
+        g.waited = kilo_ticks() - start;
+    }
+    fn message_tx(g: &mut Globals) -> Option<kilobot::messages::Message>
+    {
+        let uid = g.uid.to_le_bytes();
+        Some(kilobot::messages::Message::new(0, [uid[0], uid[1], 0, 0, 0, 0, 0, 0, 0]))
+    }
+    fn message_rx(g: &mut Globals, msg: &kilobot::messages::Message, d: &DistanceMeasurement)
+    {
+        g.heard = u16::from_le_bytes([msg.data()[0], msg.data()[1]]);
+        g.dist = estimate_distance(d);
+    }
+    let mut board = Board::new(5, 5);
+    for (uid, index) in [(1, 12), (2, 13)]
+    {
+        let mut kilo = kilo_init(Globals::default());
+        kilo.kilo_message_tx = message_tx;
+        kilo.kilo_message_rx = message_rx;
+        let mut bot = kilobot::new_kilobot(uid);
+        bot.set_program(Box::new(kilo_start(kilo, setup, loop_)));
+        board.add_new_bot_at_index(bot, index, 0);
+    }
+    let mut controller = BoardController::new(board);
+    for _ in 0..2 * kilobot::TICKS_PER_SECOND
+    {
+        controller.step();
+    }
+    let globals = controller.board.get_bot_at_index(12).ok().unwrap().program_as::<Kilolib<Globals>>().unwrap().globals();
+    assert_eq!((globals.uid, globals.waited, globals.heard, globals.dist, globals.light), (1, 32, 2, 33, 0));
+    // Both bots seeded their generators the same
+    let other = controller.board.get_bot_at_index(13).ok().unwrap().program_as::<Kilolib<Globals>>().unwrap().globals();
+    assert_eq!(globals.random, other.random);
+}
+
+#[cfg(feature = "ffi")]
+fn test_ffi()
+{
+    // Build a C program against the shim
+    let build = |name: &str, source: &str| -> String {
+        let dir = std::env::temp_dir();
+        let source_path = dir.join(format!("{}.c", name));
+        std::fs::write(&source_path, source).unwrap();
+        let library = dir.join(format!("{}{}", name, std::env::consts::DLL_SUFFIX));
+        let status = std::process::Command::new("cc")
+            .args(["-shared", "-fPIC", "-I", "ffi", "-o"])
+            .arg(&library).arg(&source_path).arg("ffi/kilolib_shim.c")
+            .status().unwrap();
+        assert!(status.success());
+        library.to_str().unwrap().to_string()
+    };
+    let outputs = |bot: &kilobot::Kilobot| {
+        let led = bot.get_led();
+        (bot.get_motor_values(), (led.r, led.g, led.b))
+    };
+
+    // The C example behaves exactly like its Rust port
+    let library = build("simple_movement", &std::fs::read_to_string("ffi/examples/simple_movement.c").unwrap());
+    let mut c_bot = kilobot::new_kilobot(0);
+    c_bot.set_program(programs::from_name(&library).unwrap());
+    let mut rust_bot = kilobot::new_kilobot(0);
+    rust_bot.set_program(programs::from_name("simple_movement").unwrap());
+    for _ in 0..500
+    {
+        c_bot.run_tick();
+        rust_bot.run_tick();
+        assert_eq!(outputs(&c_bot), outputs(&rust_bot));
+    }
+
+    // Every bot has its own globals
+    let library = build("ffi_counter", "#include <kilolib.h>
+        uint8_t loops;
+        uint8_t heard, dist;
+        message_t msg;
+        void setup() { msg.type = NORMAL; msg.data[0] = kilo_uid; msg.crc = message_crc(&msg); }
+        void loop() { loops++; set_color(loops & 63); set_motors(dist, heard); }
+        message_t *tx() { return &msg; }
+        void rx(message_t *m, distance_measurement_t *d) { heard = m->data[0]; dist = estimate_distance(d); }
+        int main() { kilo_init(); kilo_message_tx = tx; kilo_message_rx = rx; kilo_start(setup, loop); return 0; }");
+    let mut board = Board::new(5, 5);
+    for (uid, index) in [(1, 12), (2, 13)]
+    {
+        let mut bot = kilobot::new_kilobot(uid);
+        bot.set_program(programs::from_name(&library).unwrap());
+        board.add_new_bot_at_index(bot, index, 0);
+    }
+    let mut controller = BoardController::new(board);
+    for _ in 0..kilobot::TICKS_PER_SECOND
+    {
+        controller.step();
+    }
+    controller.board.get_mut_bot_at_index(13).ok().unwrap().run_tick();
+    let (motors, led) = outputs(controller.board.get_bot_at_index(12).ok().unwrap());
+    assert_eq!(motors, (2, 33));
+    assert_eq!((led.0 / 85) | (led.1 / 85) << 2 | (led.2 / 85) << 4, 32);
+    let (motors, led) = outputs(controller.board.get_bot_at_index(13).ok().unwrap());
+    assert_eq!(motors, (1, 33));
+    assert_eq!((led.0 / 85) | (led.1 / 85) << 2 | (led.2 / 85) << 4, 33);
+
+    // printf goes to the debug serial line once debug_init() has been called
+    let library = build("ffi_debug", "#define DEBUG
+        #include <kilolib.h>
+        #include <debug.h>
+        void setup() { debug_init(); }
+        void loop() { printf(\"uid %d at %lu\\n\", kilo_uid, (unsigned long)kilo_ticks); }
+        int main() { kilo_init(); kilo_start(setup, loop); return 0; }");
+    let mut bot = kilobot::new_kilobot(7);
+    bot.set_program(programs::from_name(&library).unwrap());
+    bot.run_tick();
+    bot.run_tick();
+    assert_eq!(bot.take_debug_output(), vec!["uid 7 at 0", "uid 7 at 1"]);
+
+    // Virtual pheromone comes from its own header
+    let library = build("ffi_pheromone", "#define DEBUG
+        #include <kilolib.h>
+        #include <debug.h>
+        #include <pheromone.h>
+        void loop() { printf(\"%d\", get_pheromone()); deposit_pheromone(5); }
+        int main() { kilo_init(); kilo_start(0, loop); return 0; }");
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(&library).unwrap());
+    bot.set_pheromone(42);
+    bot.run_tick();
+    assert_eq!((bot.take_debug_output(), bot.take_deposit()), (vec!["42".to_string()], 5));
+
+    // The globals of a C program can't be measured, so scenarios don't run them with a memory limit
+    let scenario = Scenario { program: library, memory_limit: Some(kilobot::SRAM_BYTES), ..Scenario::default() };
+    assert!(matches!(scenario.build(), Err(ScenarioError::StateUnmeasured(_))));
+}
+
+#[cfg(feature = "scripting")]
+fn test_scripting()
+{
+    use kilolib::Kilolib;
+    use scripting::Script;
+    let run = |program: &str, seconds: u32| {
+        let scenario = Scenario { width: 30, height: 30, bots: 91, placement: "hex".to_string(),
+                                  program: program.to_string(), ..Scenario::default() };
+        let mut controller = scenario.build().ok().unwrap();
+        for _ in 0..seconds * kilobot::TICKS_PER_SECOND
+        {
+            controller.step();
+        }
+        controller
+    };
+
+    // The Lua gradient settles on the same values as the Rust one
+    let lua = run("scripts/gradient_seed.lua:1 scripts/gradient.lua", 10);
+    let rust = run("gradient", 10);
+    for uid in 0..91
+    {
+        let bot = lua.board.get_bot_at_index(lua.board.get_index_of_uid(uid).ok().unwrap()).ok().unwrap();
+        let script = bot.program_as::<Kilolib<Script>>().unwrap().globals();
+        assert!(script.error().is_none());
+        let bot = rust.board.get_bot_at_index(rust.board.get_index_of_uid(uid).ok().unwrap()).ok().unwrap();
+        let expected = bot.program_as::<programs::gradient::Gradient>().unwrap().get_value();
+        assert_eq!(script.get_stored("gradient"), Some(expected.to_string()));
+    }
+
+    // A script that never returns is stopped along with its bot, without holding up the others
+    let path = std::env::temp_dir().join("kilobot_runaway.lua");
+    std::fs::write(&path, "store.loops = 0\nfunction loop()\n move_forward()\n store.loops = store.loops + 1\n \
+                           if store.loops == 3 then while true do end end\nend").unwrap();
+    let program = format!("{}:1 wander", path.to_str().unwrap());
+    let controller = run(&program, 1);
+    let bot = controller.board.get_bot_at_index(controller.board.get_index_of_uid(0).ok().unwrap()).ok().unwrap();
+    let script = bot.program_as::<Kilolib<Script>>().unwrap().globals();
+    assert!(script.error().unwrap().contains("ran for too long"));
+    assert_eq!(script.get_stored("loops").as_deref(), Some("3"));
+    assert_eq!(bot.get_motor_values(), (0, 0));
+
+    // Instructions run are charged to a bot with a CPU budget, so a heavy loop makes it fall behind
+    std::fs::write(&path, "store.loops = 0\nfunction loop()\n for i = 1, 20000 do end\n store.loops = store.loops + 1\nend").unwrap();
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(path.to_str().unwrap()).unwrap());
+    bot.set_cpu_budget(Some(kilobot::CYCLES_PER_TICK));
+    for _ in 0..32
+    {
+        bot.run_tick();
+    }
+    let script = bot.program_as::<Kilolib<Script>>().unwrap().globals();
+    assert!(script.error().is_none());
+    let loops: u32 = script.get_stored("loops").unwrap().parse().unwrap();
+    assert!((2..16).contains(&loops));
+    assert_eq!(bot.get_overruns(), loops);
+    assert!(bot.get_state_size().is_some());
+
+    // print writes to the bot's debug serial line
+    std::fs::write(&path, "function loop() print('tick', ticks(), nil) end").unwrap();
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(path.to_str().unwrap()).unwrap());
+    bot.run_tick();
+    assert_eq!(bot.take_debug_output(), vec!["tick\t0\tnil"]);
+
+    // on_message gets the type of the message, so scripts can tell bots from GPS fixes
+    std::fs::write(&path, "store.msg_type = 0\nfunction on_message(msg, dist, msg_type) store.msg_type = msg_type end").unwrap();
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(path.to_str().unwrap()).unwrap());
+    bot.run_tick();
+    bot.receive(kilobot::messages::GpsFix { x: 1, y: 2, heading: None }.to_message(), 0);
+    assert_eq!(bot.program_as::<Kilolib<Script>>().unwrap().globals().get_stored("msg_type").unwrap(), "1");
+
+    // Scripts sense and deposit virtual pheromone
+    std::fs::write(&path, "function loop() deposit_pheromone(pheromone() + 1) end").unwrap();
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(path.to_str().unwrap()).unwrap());
+    bot.set_pheromone(41);
+    bot.run_tick();
+    assert_eq!(bot.take_deposit(), 42);
+
+    // Scripts that don't compile are caught when they are loaded
+    std::fs::write(&path, "function loop(").unwrap();
+    assert!(programs::from_name(path.to_str().unwrap()).is_err());
+    // and scenarios say why instead of calling them unknown
+    let scenario = Scenario { program: path.to_str().unwrap().to_string(), ..Scenario::default() };
+    assert!(matches!(scenario.build(), Err(ScenarioError::ProgramLoad(_, msg)) if msg.contains("syntax error")));
+
+    // Scripts are held to the memory limit by what they add to their Lua state
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_memory_limit(Some(kilobot::SRAM_BYTES));
+    bot.set_program(programs::from_name("scripts/gradient_seed.lua").unwrap());
+    bot.run_tick();
+    assert!(bot.get_state_size().unwrap() < kilobot::SRAM_BYTES);
+    std::fs::write(&path, "store.seen = {}\nfunction loop() for i = 1, 16 do table.insert(store.seen, ticks()) end end").unwrap();
+    bot.set_program(programs::from_name(path.to_str().unwrap()).unwrap());
+    for _ in 0..kilobot::TICKS_PER_SECOND
+    {
+        bot.run_tick();
+    }
+    assert!(bot.get_memory_violation().is_some());
+}
 
+fn test_upload()
+{
+    use kilobot::bootloader::{self, Bootloader};
+    use kilobot::messages::{Message, MessageType};
+    use metrics::SwarmMetrics;
+
+    // The bootloader waits for every chunk, and throws away an image with the wrong CRC
+    let image = vec![7u8; 300];
+    let messages = bootloader::image_messages(&image);
+    assert_eq!(messages.len(), 1 + 19 + 19 + 7);
+    let mut boot = Bootloader::new();
+    assert!(messages[..messages.len() - 1].iter().all(|msg| boot.receive(msg).is_none()));
+    assert_eq!(boot.progress(), Some((298, 300)));
+    assert_eq!(boot.receive(&messages[messages.len() - 1]), Some(image.clone()));
+    let mut corrupt = messages.clone();
+    let mut data = *corrupt[0].data();
+    data[2] ^= 1;
+    corrupt[0] = Message::new(MessageType::BOOTPGM_SIZE as u8, data);
+    let mut boot = Bootloader::new();
+    assert!(corrupt.iter().all(|msg| boot.receive(msg).is_none()));
+    assert_eq!(boot.progress(), Some((0, 300)));
+
+    // BOOT stops the bot and keeps it quiet until a program arrives
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name("wander").unwrap());
+    bot.receive(Message::new(MessageType::BOOT as u8, [0; 9]), 0);
+    assert!(bot.in_bootloader());
+    assert_eq!(bot.get_motor_values(), (0, 0));
+    assert!(bot.transmit().is_none());
+    for msg in bootloader::image_messages(b"gradient")
+    {
+        bot.receive(msg, 0);
+    }
+    assert!(!bot.in_bootloader());
+    assert_eq!(bot.get_uploads(), 1);
+    assert!(bot.program_as::<programs::gradient::Gradient>().is_some());
+
+    // The whole swarm picks up the new program, and an unknown one leaves the bots waiting
+    let scenario = Scenario { width: 20, height: 20, bots: 20, placement: "hex".to_string(), upload: Some("gradient".to_string()),
+                              ..Scenario::default() };
+    let mut controller = scenario.build().ok().unwrap();
+    while !controller.overhead().is_idle()
+    {
+        controller.step();
+    }
+    assert_eq!(controller.overhead().get_sent(), (overhead::BOOT_REPEATS + 3) as u64);
+    let metrics = SwarmMetrics::compute(&controller.board);
+    assert_eq!((metrics.uploaded, metrics.in_bootloader), (20, 0));
+    let image = |name: &str, bytes: Vec<u8>| {
+        let path = std::env::temp_dir().join(name);
+        std::fs::write(&path, bytes).unwrap();
+        Some(path.to_str().unwrap().to_string())
+    };
+    let mut controller = Scenario { upload: image("upload_nothing.bin", b"nothing".to_vec()), ..scenario.clone() }.build().ok().unwrap();
+    for _ in 0..100
+    {
+        controller.step();
+    }
+    assert_eq!(SwarmMetrics::compute(&controller.board).in_bootloader, 20);
+    assert!(matches!(Scenario { upload: image("upload_large.bin", vec![0; bootloader::MAX_IMAGE_LEN + 1]), ..scenario.clone() }.build(),
+                     Err(ScenarioError::ImageTooLarge(_))));
+
+    // With contention, a dense swarm that talks every tick drowns out the overhead controller
+    let mut controller = Scenario { tx_period: 1, contention: true, ..scenario }.build().ok().unwrap();
+    for _ in 0..100
+    {
+        controller.step();
+    }
+    assert!(controller.get_collisions() > 0);
+    assert_eq!(SwarmMetrics::compute(&controller.board).uploaded, 0);
+}
+
+fn test_cpu_budget()
+{
+    use kilobot::messages::Message;
+    use kilobot::program::Program;
+
+    // Does a fixed amount of work every loop and counts its callbacks
+    struct Busy { cycles: u32, loops: u32, received: Vec<(u32, u16)> }
+    impl Program for Busy
+    {
+        fn run(&mut self, bot: &mut dyn Hal)
+        {
+            bot.spend_cycles(self.cycles);
+            self.loops += 1;
+        }
+        fn message_tx(&mut self, _bot: &mut dyn Hal) -> Option<Message>
+        {
+            Some(Message::new(0, [0; 9]))
+        }
+        fn message_rx(&mut self, _bot: &mut dyn Hal, _msg: &Message, dist: u16)
+        {
+            self.received.push((self.loops, dist));
+        }
+    }
+    let busy_bot = |cycles: u32, budget: Option<u32>| {
+        let mut bot = kilobot::new_kilobot(0);
+        bot.set_program(Box::new(Busy { cycles, loops: 0, received: Vec::new() }));
+        bot.set_cpu_budget(budget);
+        bot.set_tx_period(1);
+        bot
+    };
+
+    // Without a budget, or within it, the loop runs every tick
+    for (cycles, budget) in [(kilobot::CYCLES_PER_TICK * 10, None), (kilobot::CYCLES_PER_TICK, Some(kilobot::CYCLES_PER_TICK))]
+    {
+        let mut bot = busy_bot(cycles, budget);
+        for _ in 0..10
+        {
+            bot.run_tick();
+        }
+        assert_eq!((bot.program_as::<Busy>().unwrap().loops, bot.get_overruns()), (10, 0));
+    }
+
+    // A loop that needs 1.5 ticks of work runs every other tick, and while the processor is busy
+    // the bot doesn't transmit and messages wait for the next loop
+    let mut bot = busy_bot(kilobot::CYCLES_PER_TICK * 3 / 2, Some(kilobot::CYCLES_PER_TICK));
+    bot.run_tick();
+    assert!(bot.transmit().is_none());
+    bot.receive(Message::new(0, [0; 9]), 40);
+    assert!(bot.program_as::<Busy>().unwrap().received.is_empty());
+    bot.run_tick();
+    assert!(bot.transmit().is_some());
+    for _ in 0..8
+    {
+        bot.run_tick();
+    }
+    let busy = bot.program_as::<Busy>().unwrap();
+    assert_eq!((busy.loops, bot.get_overruns(), bot.get_ticks()), (5, 5, 10));
+    assert_eq!(busy.received, vec![(1, 40)]);
+
+    // Only the latest message is held back, it goes with its program, and it is delivered as soon
+    // as the budget is lifted
+    let mut bot = busy_bot(kilobot::CYCLES_PER_TICK * 3 / 2, Some(kilobot::CYCLES_PER_TICK));
+    bot.run_tick();
+    bot.receive(Message::new(0, [0; 9]), 40);
+    bot.receive(Message::new(0, [0; 9]), 50);
+    bot.run_tick();
+    bot.run_tick();
+    assert_eq!(bot.program_as::<Busy>().unwrap().received, vec![(1, 50)]);
+    bot.receive(Message::new(0, [0; 9]), 60);
+    assert_eq!(bot.program_as::<Busy>().unwrap().received.len(), 1);
+    bot.set_program(Box::new(Busy { cycles: 0, loops: 0, received: Vec::new() }));
+    bot.run_tick();
+    assert!(bot.program_as::<Busy>().unwrap().received.is_empty());
+    let mut bot = busy_bot(kilobot::CYCLES_PER_TICK * 3 / 2, Some(kilobot::CYCLES_PER_TICK));
+    bot.run_tick();
+    bot.receive(Message::new(0, [0; 9]), 70);
+    bot.set_cpu_budget(None);
+    assert_eq!(bot.program_as::<Busy>().unwrap().received, vec![(1, 70)]);
+
+    // A message that arrives on a skipped tick whose work fits in the budget is still held back, so
+    // it replaces the one before it instead of overtaking it
+    let mut bot = busy_bot(kilobot::CYCLES_PER_TICK * 3 / 2, Some(kilobot::CYCLES_PER_TICK));
+    bot.run_tick();
+    bot.receive(Message::new(0, [0; 9]), 40);
+    bot.run_tick();
+    bot.receive(Message::new(0, [0; 9]), 50);
+    assert!(bot.program_as::<Busy>().unwrap().received.is_empty());
+    bot.run_tick();
+    assert_eq!(bot.program_as::<Busy>().unwrap().received, vec![(1, 50)]);
+
+    let scenario = Scenario::parse("cpu_budget = kilobot").ok().unwrap();
+    assert_eq!(scenario.cpu_budget, Some(250_000));
+    assert!(Scenario::parse("cpu_budget = fast").is_err());
+}
+
+fn test_memory_limit()
+{
+    use kilobot::program::Program;
+
+    // Keeps a byte for every loop, like a log that is never trimmed
+    #[derive(Default)]
+    struct Hoarder { log: Vec<u8> }
+    impl Program for Hoarder
+    {
+        fn run(&mut self, bot: &mut dyn Hal)
+        {
+            bot.move_forward();
+            self.log.push(0);
+        }
+        fn state_size(&self) -> Option<usize>
+        {
+            Some(std::mem::size_of_val(self) + self.log.len())
+        }
+    }
+    struct Big { _table: [u8; 4096] }
+    impl Program for Big
+    {
+        fn run(&mut self, _bot: &mut dyn Hal) {}
+    }
+
+    // The built in programs fit
+    for name in ["gradient", "firefly", "wander", "simple_movement", "disperse"]
+    {
+        assert!(programs::from_name(name).unwrap().state_size().unwrap() < kilobot::SRAM_BYTES);
+    }
+
+    // Too big from the start is caught as soon as the program is loaded
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_memory_limit(Some(kilobot::SRAM_BYTES));
+    bot.set_program(Box::new(Big { _table: [0; 4096] }));
+    assert_eq!(bot.get_memory_violation(), Some(4096));
+    assert!(bot.get_state_size().is_none());
+    bot.set_program(Box::new(Hoarder::default()));
+    assert!(bot.get_memory_violation().is_none());
+
+    // Growing past the limit crashes the program and stops the bot
+    let limit = std::mem::size_of::<Hoarder>() + 10;
+    bot.set_memory_limit(Some(limit));
+    for _ in 0..10
+    {
+        bot.run_tick();
+    }
+    assert!(bot.get_memory_violation().is_none());
+    assert_eq!(bot.get_checked_state_size(), Some(limit));
+    assert_eq!(bot.get_motor_values(), (kilobot::MOTOR_MAX_VAL, kilobot::MOTOR_MAX_VAL));
+    bot.run_tick();
+    assert_eq!(bot.get_memory_violation(), Some(limit + 1));
+    assert_eq!(bot.get_motor_values(), (0, 0));
+    assert!(bot.program_as::<Hoarder>().is_none());
+
+    let scenario = Scenario::parse("memory_limit = 1\nprogram = firefly").ok().unwrap();
+    assert!(matches!(scenario.build(), Err(ScenarioError::StateTooLarge(name, _)) if name == "firefly"));
+    assert_eq!(Scenario::parse("memory_limit = kilobot").ok().unwrap().memory_limit, Some(2048));
+}
+
+fn test_debug_print()
+{
+    // Every line is captured with its tick and uid, lines of the same tick in uid order
+    let scenario = Scenario { width: 20, height: 20, bots: 19, placement: "hex".to_string(), program: "gradient".to_string(),
+                              ..Scenario::default() };
+    let mut controller = scenario.build().ok().unwrap();
+    for _ in 0..kilobot::TICKS_PER_SECOND
+    {
+        controller.step();
+    }
+    let log = controller.debug_log();
+    assert!(log.iter().any(|l| l.uid == 3 && l.text == "gradient 1"));
+    assert!(log.iter().zip(log.iter().skip(1)).all(|(a, b)| (a.tick, a.uid) < (b.tick, b.uid)));
+    let first = log.iter().find(|l| l.uid == 3).unwrap();
+    let bot = controller.board.get_bot_at_index(controller.board.get_index_of_uid(3).ok().unwrap()).ok().unwrap();
+    assert_eq!(first.text, format!("gradient {}", bot.program_as::<programs::gradient::Gradient>().unwrap().get_value()));
+
+    // Only the chosen bots are kept, and the CLI prints them as they happen
+    let mut controller = scenario.build().ok().unwrap();
+    controller.set_debug_uids(Some(std::iter::once(12).collect()));
+    let (mut csv, mut debug): (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
+    assert!(metrics::write_series(&mut controller, 64, 32, &mut csv, Some(&mut debug)).is_ok());
+    let debug = String::from_utf8(debug).unwrap();
+    assert!(!debug.is_empty() && debug.lines().all(|l| l.contains(" bot 12: gradient ")));
+    assert!(controller.debug_log().is_empty());
+
+    // kilolib programs print too, and a bot only holds on to its most recent lines
+    fn setup(_: &mut ()) {}
+    fn loop_(_: &mut ())
+    {
+        kilolib::debug_print(&format!("tick {}\nsecond line", kilolib::kilo_ticks()));
+    }
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(Box::new(kilolib::kilo_start(kilolib::kilo_init(()), setup, loop_)));
+    bot.run_tick();
+    assert_eq!(bot.take_debug_output(), vec!["tick 0", "second line"]);
+    for _ in 0..100
+    {
+        bot.run_tick();
+    }
+    let output = bot.take_debug_output();
+    assert_eq!((output.len(), output[0].as_str()), (64, "tick 69"));
+}
+
+fn test_environment()
+{
+    use kilobot::program::Program;
+    use kilobot::messages::Message;
+    use kilobot::messages::GpsFix;
+    use board::environment::{Environment, RuleEnvironment, PATCH_MSG_TYPE};
+
+    // Remembers the messages it gets from the space it is on, and keeps talking
+    #[derive(Default)]
+    struct Listener { gps: Option<(u16, u16)>, patch: Option<u8> }
+    impl Program for Listener
+    {
+        fn run(&mut self, _bot: &mut dyn Hal) {}
+        fn message_tx(&mut self, _bot: &mut dyn Hal) -> Option<Message>
+        {
+            Some(Message::new(1, [0; 9]))
+        }
+        fn message_rx(&mut self, _bot: &mut dyn Hal, msg: &Message, dist: u16)
+        {
+            if dist == 0 && msg.msg_type() == PATCH_MSG_TYPE
+            {
+                self.patch = Some(msg.data()[0]);
+            }
+            self.gps = GpsFix::from_message(msg).map(|fix| (fix.x, fix.y)).or(self.gps);
+        }
+    }
+    // Only listens, to check what the board hears
+    #[derive(Default)]
+    struct Recorder { heard: Vec<(usize, usize)> }
+    impl Environment for Recorder
+    {
+        fn transmit(&mut self, _tick: u64, _cell: &CoordinatePair) -> Option<Message>
+        {
+            None
+        }
+        fn receive(&mut self, _tick: u64, cell: &CoordinatePair, _msg: &Message)
+        {
+            self.heard.push(cell.as_usize_tuple());
+        }
+    }
+
+    let new_controller = |environment: Box<dyn Environment>| {
+        let mut controller = BoardController::new(Board::new(12, 12));
+        for (uid, index) in [(1, 0), (2, 3 + 3 * 12), (3, 143)].iter()
+        {
+            let mut bot = kilobot::new_kilobot(*uid);
+            bot.set_program(Box::new(Listener::default()));
+            controller.board.add_new_bot_at_index(bot, *index, 0);
+        }
+        controller.board.set_environment(Some(environment));
+        for _ in 0..kilobot::transceiver::DEFAULT_TX_PERIOD * 2
+        {
+            controller.step();
+        }
+        controller
+    };
+    let listener = |controller: &BoardController, index: usize| {
+        let bot = controller.board.get_bot_at_index(index).ok().unwrap();
+        let listener = bot.program_as::<Listener>().unwrap();
+        (listener.gps, listener.patch)
+    };
+
+    // The first rule that covers a space decides what it sends, and every space counts what it hears
+    let controller = new_controller(Box::new(RuleEnvironment::parse("patch 2 2 4 4 7; gps").ok().unwrap()));
+    assert_eq!(listener(&controller, 0), (Some((0, 0)), None));
+    assert_eq!(listener(&controller, 39), (None, Some(7)));
+    assert_eq!(listener(&controller, 143), (Some((363, 363)), None));
+    let rules = controller.board.environment_as::<RuleEnvironment>().unwrap();
+    assert!(rules.get_received(&CoordinatePair::new(3, 3)) > 0);
+    assert_eq!(rules.get_received(&CoordinatePair::new(5, 5)), 0);
+
+    // Environments written in Rust hear every bot on the space it is on
+    let controller = new_controller(Box::<Recorder>::default());
+    let heard = &controller.board.environment_as::<Recorder>().unwrap().heard;
+    assert!([(0, 0), (3, 3), (11, 11)].iter().all(|cell| heard.contains(cell)));
+    assert_eq!(listener(&controller, 39), (None, None));
+
+    // Rules are checked when a scenario is read
+    assert!(Scenario::parse("environment = gps; patch 2 2 6 6 1").is_ok());
+    assert!(Scenario::parse("environment = patch 2 2").is_err());
+    assert!(Scenario::parse("environment = none").ok().unwrap().environment.is_none());
+    let scenario = Scenario::parse("bots = 5\nenvironment = gps").ok().unwrap();
+    assert!(scenario.build().ok().unwrap().board.environment_as::<RuleEnvironment>().is_some());
+
+    // Programs only count other bots' messages, so the environment doesn't change what they do
+    let scenarios = ["width = 20\nheight = 20\nbots = 30\nprogram = gradient",
+                     "width = 20\nheight = 20\nbots = 30\nprogram = aggregate",
+                     "width = 40\nheight = 40\nbots = 40\nplacement = half_disc\nprogram = self_assembly\ntx_radius = 4"];
+    for settings in scenarios.iter()
+    {
+        let without = run_scenario(settings, 600);
+        let with = run_scenario(&format!("{}\nenvironment = patch 0 0 9 39 3; gps", settings), 600);
+        assert_eq!(swarm_state(&with), swarm_state(&without));
+    }
+}
+
+fn test_gps()
+{
+    use kilobot::program::Program;
+    use kilobot::messages::{GpsFix, Message};
+
+    // Keeps every fix it gets and stays put
+    #[derive(Default)]
+    struct Tracker { fixes: Vec<GpsFix> }
+    impl Program for Tracker
+    {
+        fn run(&mut self, _bot: &mut dyn Hal) {}
+        fn message_rx(&mut self, _bot: &mut dyn Hal, msg: &Message, _dist: u16)
+        {
+            self.fixes.extend(GpsFix::from_message(msg));
+        }
+    }
+    let run = |settings: &str, ticks: u64| {
+        let mut controller = Scenario::parse(&format!("bots = 30\n{}", settings)).ok().unwrap().build().ok().unwrap();
+        for index in controller.board.get_occupied_indices()
+        {
+            controller.board.get_mut_bot_at_index(index).ok().unwrap().set_program(Box::<Tracker>::default());
+        }
+        for _ in 0..ticks
+        {
+            controller.step();
+        }
+        controller
+    };
+    // Every fix of every bot, with its true position and facing
+    let fixes = |controller: &BoardController| {
+        let mut fixes = Vec::new();
+        for index in controller.board.get_occupied_indices()
+        {
+            let (x, y) = controller.board.get_coord_from_index(&index).ok().unwrap().as_mm_tuple();
+            let facing = controller.board.get_bot_location_at_index(index).ok().unwrap().get_facing();
+            let bot = controller.board.get_bot_at_index(index).ok().unwrap();
+            for fix in &bot.program_as::<Tracker>().unwrap().fixes
+            {
+                fixes.push((*fix, (x as u16, y as u16, facing)));
+            }
+        }
+        fixes
+    };
+
+    // Without noise every bot learns exactly where it is, once every period
+    let controller = run("gps = 8", 16);
+    let exact = fixes(&controller);
+    assert_eq!((exact.len(), controller.overhead().get_fixes()), (60, 60));
+    assert!(exact.iter().all(|(fix, (x, y, facing))| (fix.x, fix.y, fix.heading) == (*x, *y, Some(*facing))));
+
+    // Noise spreads the fixes around the true values, and dropouts lose some of them
+    let controller = run("gps = 1\ngps_noise = 20\ngps_heading_noise = 10\ngps_dropout = 0.25", 100);
+    let noisy = fixes(&controller);
+    assert!((2000..2500).contains(&noisy.len()));
+    let error = noisy.iter().map(|(fix, (x, y, _))| (fix.x as f64 - *x as f64).abs() + (fix.y as f64 - *y as f64).abs())
+        .sum::<f64>() / noisy.len() as f64;
+    assert!(error > 10.0 && error < 50.0);
+    assert!(noisy.iter().all(|(fix, _)| fix.heading.unwrap() < 360));
+    assert!(noisy.iter().any(|(fix, (_, _, facing))| fix.heading != Some(*facing)));
+    assert!(Scenario::parse("gps = 0").is_err());
+    assert!(Scenario::parse("gps_dropout = 1.5").is_err());
+    assert!(Scenario::parse("gps = off").ok().unwrap().build().ok().unwrap().overhead().get_gps().is_none());
+
+    // Programs only count other bots' messages, so fixes don't change what they do
+    for program in ["gradient", "disperse"].iter()
+    {
+        let without = run_scenario(&format!("bots = 30\nprogram = {}", program), 300);
+        let with = run_scenario(&format!("bots = 30\nprogram = {}\ngps = 1", program), 300);
+        assert!(with.overhead().get_fixes() > 0);
+        assert_eq!(swarm_state(&with), swarm_state(&without));
+    }
+}
+
+/// Build a scenario and run it
+/// # Arguments
+/// * 'settings' - The scenario, as the lines of a scenario file
+/// * 'ticks' - Ticks to run it for
+fn run_scenario(settings: &str, ticks: u64) -> BoardController
+{
+    let mut controller = Scenario::parse(settings).ok().unwrap().build().ok().unwrap();
+    for _ in 0..ticks
+    {
+        controller.step();
+    }
+    controller
+}
+
+/// Get where every bot is, which way it faces and the colour of its LED
+fn swarm_state(controller: &BoardController) -> Vec<(usize, u16, (u8, u8, u8))>
+{
+    controller.board.get_occupied_indices().into_iter().map(|index| {
+        let facing = controller.board.get_bot_location_at_index(index).ok().unwrap().get_facing();
+        let led = controller.board.get_bot_at_index(index).ok().unwrap().get_led();
+        (index, facing, (led.r, led.g, led.b))
+    }).collect()
+}
+
+fn test_pheromone()
+{
+    use board::pheromone_map::{PheromoneMap, PHEROMONE_MAX};
+    let total = |map: &PheromoneMap| (0..25).map(|i| map.get_level_at_index(i)).sum::<f64>();
+
+    // Diffusion spreads pheromone to the four neighbours without losing any, even at the edges
+    let mut map = PheromoneMap::new(5, 5);
+    assert!(map.is_clean());
+    map.set_diffusion(0.2);
+    map.deposit(12, 100.0);
+    map.deposit(0, 100.0);
+    map.update();
+    assert_eq!((map.get_reading_at_index(12), map.get_reading_at_index(13), map.get_reading_at_index(18)), (80, 5, 0));
+    assert_eq!((map.get_reading_at_index(0), map.get_reading_at_index(1)), (90, 5));
+    assert!((total(&map) - 200.0).abs() < 1e-9);
+    // Evaporation takes it away until the board is clean again
+    map.set_evaporation(0.5);
+    map.update();
+    assert!((total(&map) - 100.0).abs() < 1e-9);
+    for _ in 0..20
+    {
+        map.update();
+    }
+    assert!(map.is_clean() && total(&map) == 0.0);
+    map.deposit(3, 5000.0);
+    assert_eq!(map.get_reading_at_index(3), PHEROMONE_MAX);
+
+    // Bots deposit on the space they start the tick on and sense it from the next tick
+    #[derive(Default)]
+    struct Globals { sensed: Vec<i16> }
+    fn setup(_: &mut Globals) {}
+    fn loop_(g: &mut Globals)
+    {
+        g.sensed.push(kilolib::get_pheromone());
+        kilolib::deposit_pheromone(10);
+    }
+    let mut controller = BoardController::new(Board::new(5, 5));
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(Box::new(kilolib::kilo_start(kilolib::kilo_init(Globals::default()), setup, loop_)));
+    controller.board.add_new_bot_at_index(bot, 12, 0);
+    controller.board.pheromone_map_mut().set_evaporation(0.5);
+    for _ in 0..3
+    {
+        controller.step();
+    }
+    let bot = controller.board.get_bot_at_index(12).ok().unwrap();
+    assert_eq!(bot.program_as::<kilolib::Kilolib<Globals>>().unwrap().globals().sensed, vec![0, 5, 8]);
+    assert_eq!(controller.board.pheromone_map().get_reading_at_index(12), 9);
+
+    // Trail layers mark the board as they go
+    let mut controller = Scenario::from_file("scenarios/trail.txt").ok().unwrap().build().ok().unwrap();
+    for _ in 0..640
+    {
+        controller.step();
+    }
+    let field = controller.board.pheromone_map();
+    // Trails stand out from the faint haze that diffuses away from them
+    let trails = (0..controller.board.len()).filter(|&i| field.get_level_at_index(i) > field.get_max_level() / 10.0).count();
+    assert!(trails > 30 && trails < controller.board.len() / 2);
+    assert!(controller.board.get_occupied_indices().iter().any(|&i| controller.board.get_bot_at_index(i).ok().unwrap().get_led().r > 0));
+    assert!(Scenario::parse("evaporation = 1.5").is_err());
+    assert!(Scenario::parse("diffusion = 0.1").is_ok());
+}
+
+#[cfg(feature = "wasm")]
+fn test_wasm()
+{
+    use kilolib::Kilolib;
+    use wasm::WasmProgram;
+    let write = |name: &str, source: &str| -> String {
+        let path = std::env::temp_dir().join(format!("{}.wat", name));
+        std::fs::write(&path, source).unwrap();
+        path.to_str().unwrap().to_string()
+    };
+    let outputs = |bot: &kilobot::Kilobot| {
+        let led = bot.get_led();
+        (bot.get_motor_values(), (led.r, led.g, led.b))
+    };
+
+    // The example behaves exactly like its Rust port
+    let mut wasm_bot = kilobot::new_kilobot(0);
+    wasm_bot.set_program(programs::from_name("wasm/examples/simple_movement.wat").unwrap());
+    let mut rust_bot = kilobot::new_kilobot(0);
+    rust_bot.set_program(programs::from_name("simple_movement").unwrap());
+    for _ in 0..500
+    {
+        wasm_bot.run_tick();
+        rust_bot.run_tick();
+        assert_eq!(outputs(&wasm_bot), outputs(&rust_bot));
+    }
+
+    // Every bot has its own memory
+    let module = write("wasm_counter", r#"(module
+        (import "env" "set_motors" (func $set_motors (param i32 i32)))
+        (import "env" "set_color" (func $set_color (param i32)))
+        (import "env" "kilo_uid" (func $kilo_uid (result i32)))
+        (memory (export "memory") 1)
+        ;; 0: loops, 1: heard, 2: dist, 16: message to send, 32: message received
+        (func (export "setup") (i32.store8 (i32.const 16) (call $kilo_uid)))
+        (func (export "loop")
+            (i32.store8 (i32.const 0) (i32.add (i32.load8_u (i32.const 0)) (i32.const 1)))
+            (call $set_color (i32.and (i32.load8_u (i32.const 0)) (i32.const 63)))
+            (call $set_motors (i32.load8_u (i32.const 2)) (i32.load8_u (i32.const 1))))
+        (func (export "message_tx") (result i32) (i32.const 16))
+        (func (export "message_rx_buffer") (result i32) (i32.const 32))
+        (func (export "message_rx") (param $msg i32) (param $dist i32)
+            (i32.store8 (i32.const 1) (i32.load8_u (local.get $msg)))
+            (i32.store8 (i32.const 2) (local.get $dist))))"#);
+    let mut board = Board::new(5, 5);
+    for (uid, index) in [(1, 12), (2, 13)]
+    {
+        let mut bot = kilobot::new_kilobot(uid);
+        bot.set_program(programs::from_name(&module).unwrap());
+        board.add_new_bot_at_index(bot, index, 0);
+    }
+    let mut controller = BoardController::new(board);
+    for _ in 0..kilobot::TICKS_PER_SECOND
+    {
+        controller.step();
+    }
+    controller.board.get_mut_bot_at_index(13).ok().unwrap().run_tick();
+    let (motors, led) = outputs(controller.board.get_bot_at_index(12).ok().unwrap());
+    assert_eq!(motors, (2, 33));
+    assert_eq!((led.0 / 85) | (led.1 / 85) << 2 | (led.2 / 85) << 4, 32);
+    let (motors, led) = outputs(controller.board.get_bot_at_index(13).ok().unwrap());
+    assert_eq!(motors, (1, 33));
+    assert_eq!((led.0 / 85) | (led.1 / 85) << 2 | (led.2 / 85) << 4, 33);
+
+    // A module that never returns runs out of fuel and is stopped along with its bot, and memory
+    // can't grow past the limit
+    let module = write("wasm_runaway", &format!(r#"(module
+        (import "env" "set_motors" (func $set_motors (param i32 i32)))
+        (import "env" "set_color" (func $set_color (param i32)))
+        (import "env" "kilo_ticks" (func $kilo_ticks (result i32)))
+        (memory 1)
+        (func (export "loop")
+            (call $set_motors (i32.const 255) (i32.const 255))
+            (if (i32.eq (memory.grow (i32.const {})) (i32.const -1)) (then (call $set_color (i32.const 63))))
+            (if (i32.eq (call $kilo_ticks) (i32.const 3)) (then (loop $forever (br $forever))))))"#,
+        wasm::MAX_MEMORY_BYTES / 65_536));
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(&module).unwrap());
+    for _ in 0..3
+    {
+        bot.run_tick();
+        assert_eq!(outputs(&bot), ((255, 255), (255, 255, 255)));
+        assert!(bot.program_as::<Kilolib<WasmProgram>>().unwrap().globals().error().is_none());
+    }
+    for _ in 0..3
+    {
+        bot.run_tick();
+        assert_eq!(bot.get_motor_values(), (0, 0));
+    }
+    assert!(bot.program_as::<Kilolib<WasmProgram>>().unwrap().globals().error().unwrap().contains("fuel"));
+
+    // Fuel used is charged to a bot with a CPU budget, so a heavy loop makes it fall behind
+    let module = write("wasm_heavy", r#"(module (func (export "loop") (local $i i32)
+        (local.set $i (i32.const 100000))
+        (loop $busy (br_if $busy (local.tee $i (i32.sub (local.get $i) (i32.const 1)))))))"#);
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(&module).unwrap());
+    bot.set_cpu_budget(Some(kilobot::CYCLES_PER_TICK));
+    for _ in 0..32
+    {
+        bot.run_tick();
+    }
+    assert!(bot.program_as::<Kilolib<WasmProgram>>().unwrap().globals().error().is_none());
+    assert!((2..16).contains(&bot.get_overruns()));
+
+    // debug_print writes to the bot's debug serial line
+    let module = write("wasm_debug", r#"(module (import "env" "debug_print" (func $debug_print (param i32 i32)))
+        (memory (export "memory") 1) (data (i32.const 16) "hello")
+        (func (export "loop") (call $debug_print (i32.const 16) (i32.const 5))))"#);
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(&module).unwrap());
+    bot.run_tick();
+    assert_eq!(bot.take_debug_output(), vec!["hello"]);
+    // A length past the end of memory traps rather than being read
+    let module = write("wasm_debug_len", r#"(module (import "env" "debug_print" (func $debug_print (param i32 i32)))
+        (memory (export "memory") 1)
+        (func (export "loop") (call $debug_print (i32.const 16) (i32.const 2147483647))))"#);
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(&module).unwrap());
+    bot.run_tick();
+    assert!(bot.program_as::<Kilolib<WasmProgram>>().unwrap().globals().error().unwrap().contains("bad address"));
+    assert!(bot.take_debug_output().is_empty());
+
+    // The start function runs before there is a bot, so imports trap there instead of panicking
+    let module = write("wasm_start", r#"(module (import "env" "kilo_uid" (func $uid (result i32)))
+        (func $init (drop (call $uid))) (start $init) (func (export "loop")))"#);
+    assert!(wasm::load(&module).is_err());
+
+    // So do modules
+    let module = write("wasm_pheromone", r#"(module (import "env" "get_pheromone" (func $get (result i32)))
+        (import "env" "deposit_pheromone" (func $deposit (param i32)))
+        (func (export "loop") (call $deposit (i32.add (call $get) (i32.const 1)))))"#);
+    let mut bot = kilobot::new_kilobot(0);
+    bot.set_program(programs::from_name(&module).unwrap());
+    bot.set_pheromone(41);
+    bot.run_tick();
+    assert_eq!(bot.take_deposit(), 42);
+
+    // Modules can only import what the simulator provides
+    let module = write("wasm_escape", r#"(module (import "wasi_snapshot_preview1" "fd_write"
+        (func (param i32 i32 i32 i32) (result i32))) (func (export "loop")))"#);
+    assert!(programs::from_name(&module).is_err());
+    assert!(wasm::load(&module).is_err());
+
+    // A module's state is its memory below __heap_base, or all of its memory without it
+    let module = write("wasm_memory", r#"(module (memory (export "memory") 1) (func (export "loop")))"#);
+    assert_eq!(programs::from_name(&module).unwrap().state_size(), Some(65_536));
+    let module = write("wasm_heap_base", r#"(module (memory (export "memory") 1)
+        (global (export "__heap_base") i32 (i32.const 1024)) (func (export "loop")))"#);
+    assert_eq!(programs::from_name(&module).unwrap().state_size(), Some(1024));
+    assert_eq!(programs::from_name("wasm/examples/simple_movement.wat").unwrap().state_size(), Some(0));
+}
+
+fn test_localization()
+{
+    use kilobot::localization::Localizer;
+    let truth: (f64, f64) = (100.0, 50.0);
+    let anchors = [(0.0, 0.0), (150.0, 0.0), (200.0, 100.0), (50.0, 120.0), (100.0, -40.0)];
+    let mut rng = rng::Rng::new(7);
+    let mut localizer = Localizer::new();
+    assert!(localizer.estimate(None).is_none());
+    for _ in 0..5
+    {
+        for (uid, &a) in anchors.iter().enumerate()
+        {
+            // Up to 8 mm of noise on every distance
+            let dist = (a.0 - truth.0).hypot(a.1 - truth.1) + rng.below(17) as f64 - 8.0;
+            localizer.add(uid as u16, a, dist.round() as u16);
+        }
+    }
+    let estimate = localizer.estimate(None).unwrap();
+    assert!((estimate.position.0 - truth.0).hypot(estimate.position.1 - truth.1) < 5.0);
+    assert_eq!(estimate.anchors, anchors.len());
+
+    // One neighbour that is badly off is ignored rather than dragging the estimate with it
+    for _ in 0..5
+    {
+        localizer.add(5, (250.0, 50.0), 60);
+    }
+    let estimate = localizer.estimate(Some(estimate.position)).unwrap();
+    assert!((estimate.position.0 - truth.0).hypot(estimate.position.1 - truth.1) < 5.0);
+    assert_eq!(estimate.anchors, anchors.len());
+    localizer.remove(5);
+    localizer.clear();
+    assert!(localizer.estimate(None).is_none());
+
+    // Estimates in a rotated, shifted and mirrored frame still count as perfect
+    let real = [(0.0, 0.0), (33.0, 0.0), (33.0, 66.0), (99.0, 33.0)];
+    let pairs: Vec<((f64, f64), (f64, f64))> = real.iter().map(|&(x, y)| ((10.0 - y, 20.0 - x), (x, y))).collect();
+    assert!(metrics::localization_error(&pairs).unwrap() < 1e-9);
+    let pairs: Vec<((f64, f64), (f64, f64))> = real.iter().map(|&(x, y)| ((x, y), (x, y))).collect();
+    assert!(metrics::localization_error(&pairs).unwrap() < 1e-9);
+    assert!(metrics::localization_error(&[]).is_none());
+}
+
+fn test_self_assembly()
+{
+    use programs::self_assembly::{SelfAssembly, State, SHAPE_CELL_MM, SHAPE_ORIGIN};
+    let mut controller = Scenario::from_file("scenarios/self_assembly.txt").ok().unwrap().build().ok().unwrap();
+    for _ in 0..32000
+    {
+        controller.step();
+    }
+    let board = &controller.board;
+    let seed = board.get_coord_from_index(&board.get_index_of_uid(0).ok().unwrap()).ok().unwrap();
+    let mut joined = 0;
+    let (mut upper_arm, mut lower_leg) = (0, 0);
+    for index in board.get_occupied_indices()
+    {
+        let program = board.get_bot_at_index(index).ok().unwrap().program_as::<SelfAssembly>().unwrap();
+        if program.get_state() != State::Joined
+        {
+            continue;
+        }
+        joined += 1;
+        // Joined bots are inside the shape, and know where they are to within a few mm
+        let coord = board.get_coord_from_index(&index).ok().unwrap();
+        let truth = ((coord.x as f64 - seed.x as f64) * SHAPE_CELL_MM, (coord.y as f64 - seed.y as f64) * SHAPE_CELL_MM);
+        assert!(SelfAssembly::inside_shape(truth));
+        let (col, row) = (coord.x as i32 - seed.x as i32 + SHAPE_ORIGIN.0, coord.y as i32 - seed.y as i32 + SHAPE_ORIGIN.1);
+        upper_arm += (col > 5 && row < 3) as usize;
+        lower_leg += (col > 5 && row > 3) as usize;
+        let estimate = program.get_position().unwrap();
+        assert!(((estimate.0 - truth.0).powi(2) + (estimate.1 - truth.1).powi(2)).sqrt() < 10.0);
+    }
+    assert!(joined >= 20);
+    // The K isn't convex, bots fill the legs on both sides of the notch between them
+    assert!(upper_arm > 0 && lower_leg > 0);
+    let metrics = metrics::SwarmMetrics::compute(board);
+    assert!(metrics.localized >= joined && metrics.localization_error.unwrap() < 10.0);
+}
+
+#[cfg(feature = "parallel")]
+fn test_parallel_matches_serial()
+{
+    let scenario = Scenario { width: 40, height: 40, bots: 300, ..Scenario::default() };
+    let mut serial = scenario.build().ok().unwrap();
+    let mut parallel = scenario.build().ok().unwrap();
+    serial.set_parallel(false);
+    parallel.set_parallel(true);
+    for _ in 0..500
+    {
+        serial.step();
+        parallel.step();
+    }
+    let summary = |controller: &BoardController| -> Vec<String> {
+        controller.board.get_occupied_indices().iter().map(|&i| {
+            let loc = controller.board.get_bot_location_at_index(i).ok().unwrap();
+            let bot = loc.bot();
+            format!("{} {} {} {:?} {} {}", i, loc, bot.get_led().g, bot.get_motor_values(),
+                    bot.transceiver().get_tx_count(), bot.transceiver().get_rx_count())
+        }).collect()
+    };
+    assert_eq!(summary(&serial), summary(&parallel));
+}
@@ -0,0 +1,270 @@
+/*
+ * metrics
+ * Purpose: Standard statistics of a swarm, computed from the state of the board at one tick
+ *
+ */
+use std::collections::HashMap;
+use std::io::{self, Write};
+use crate::hal::Hal;
+use crate::board::Board;
+use crate::board_controller::BoardController;
+use crate::board::board_map::BoardMap;
+
+/// Names of the metrics, in the order they are written by SwarmMetrics::to_csv_row
+pub const METRIC_COLUMNS: [&str; 12] = ["components", "largest_cluster", "mean_nn_distance", "coverage", "polarization",
+                                        "localized", "localization_error", "phase_spread", "in_bootloader", "uploaded",
+                                        "overruns", "memory_violations"];
+/// A position in mm
+pub type Position = (f64, f64);
+
+/// Statistics of the swarm at one point in time
+/// # Fields
+/// * 'components' - Number of connected components of the communication graph. Two bots are
+///   connected if either of them can hear the other
+/// * 'largest_cluster' - Number of bots in the largest component
+/// * 'mean_nn_distance' - Mean distance from each bot to its nearest neighbour in board units,
+///   or None if there are fewer than two bots
+/// * 'coverage' - Fraction of the board's spaces inside at least one bot's signal
+/// * 'polarization' - Length of the mean heading vector of all bots. 1 when every bot faces the
+///   same way, close to 0 when facings are spread evenly
+/// * 'localized' - Number of bots whose program has an estimate of its position
+/// * 'localization_error' - Root mean square distance in mm between the estimated and real
+///   positions of the localized bots, or None if no bot is localized. Programs pick their own
+///   coordinate frame, so the estimates are first rotated, shifted and if need be mirrored to line up
+///   with the real positions as well as they can
+/// * 'phase_spread' - How spread out the phases of the bots' oscillators are, or None if no bot
+///   has one. 0 when every oscillator is at the same phase, close to 1 when phases are spread evenly
+///   around the cycle
+/// * 'in_bootloader' - Number of bots in their bootloader, waiting for a program
+/// * 'uploaded' - Number of bots that have received a program over the air
+/// * 'overruns' - Total number of times a bot's program did more work in a tick than its CPU budget
+/// * 'memory_violations' - Number of bots whose program crashed by outgrowing the memory limit
+pub struct SwarmMetrics
+{
+    pub components: usize,
+    pub largest_cluster: usize,
+    pub mean_nn_distance: Option<f64>,
+    pub coverage: f64,
+    pub polarization: f64,
+    pub localized: usize,
+    pub localization_error: Option<f64>,
+    pub phase_spread: Option<f64>,
+    pub in_bootloader: usize,
+    pub uploaded: usize,
+    pub overruns: u64,
+    pub memory_violations: usize,
+}
+
+impl SwarmMetrics
+{
+    /// Compute the metrics of a board
+    /// # Arguments
+    /// * 'board' - Board to measure
+    pub fn compute(board: &Board) -> SwarmMetrics
+    {
+        let indices = board.get_occupied_indices();
+        let uids: Vec<u16> = indices.iter().map(|&i| board.get_bot_at_index(i).ok().unwrap().get_uid()).collect();
+        let (components, largest_cluster) = SwarmMetrics::components(board, &uids);
+
+        let mut heading = (0.0, 0.0);
+        let mut nn_total = 0.0;
+        let mut estimates = Vec::new();
+        let mut phases = Vec::new();
+        let (mut in_bootloader, mut uploaded, mut overruns, mut memory_violations) = (0, 0, 0, 0);
+        for (&index, &uid) in indices.iter().zip(uids.iter())
+        {
+            let bot = board.get_bot_at_index(index).ok().unwrap();
+            phases.extend(bot.get_oscillator_phase());
+            in_bootloader += bot.in_bootloader() as usize;
+            uploaded += (bot.get_uploads() > 0) as usize;
+            overruns += bot.get_overruns() as u64;
+            memory_violations += bot.get_memory_violation().is_some() as usize;
+            if let Some(estimate) = bot.get_position_estimate()
+            {
+                let coord = board.get_coord_from_index(&index).ok().unwrap();
+                estimates.push((estimate, coord.as_mm_tuple()));
+            }
+            let facing = (board.get_bot_location_at_index(index).ok().unwrap().get_facing() as f64).to_radians();
+            heading.0 += facing.sin();
+            heading.1 += facing.cos();
+            if uids.len() > 1
+            {
+                nn_total += SwarmMetrics::nearest_neighbour(board, index, uid);
+            }
+        }
+        let n = uids.len().max(1) as f64;
+
+        SwarmMetrics {
+            components,
+            largest_cluster,
+            mean_nn_distance: if uids.len() > 1 { Some(nn_total / n) } else { None },
+            coverage: board.signal_map().get_num_covered_spaces() as f64 / board.len() as f64,
+            polarization: (heading.0 * heading.0 + heading.1 * heading.1).sqrt() / n,
+            localized: estimates.len(),
+            localization_error: localization_error(&estimates),
+            phase_spread: phase_spread(&phases),
+            in_bootloader,
+            uploaded,
+            overruns,
+            memory_violations,
+        }
+    }
+
+    /// Write the metrics as CSV fields, matching METRIC_COLUMNS
+    pub fn to_csv_row(&self) -> Vec<String>
+    {
+        vec![
+            self.components.to_string(),
+            self.largest_cluster.to_string(),
+            self.mean_nn_distance.map(|d| format!("{:.3}", d)).unwrap_or_default(),
+            format!("{:.4}", self.coverage),
+            format!("{:.4}", self.polarization),
+            self.localized.to_string(),
+            self.localization_error.map(|e| format!("{:.3}", e)).unwrap_or_default(),
+            self.phase_spread.map(|s| format!("{:.4}", s)).unwrap_or_default(),
+            self.in_bootloader.to_string(),
+            self.uploaded.to_string(),
+            self.overruns.to_string(),
+            self.memory_violations.to_string(),
+        ]
+    }
+
+    /// Count the connected components of the communication graph with a union-find
+    /// # Returns
+    /// * (number of components, size of the largest component)
+    fn components(board: &Board, uids: &[u16]) -> (usize, usize)
+    {
+        let position: HashMap<u16, usize> = uids.iter().enumerate().map(|(i, &uid)| (uid, i)).collect();
+        let mut parent: Vec<usize> = (0..uids.len()).collect();
+        fn find(parent: &mut [usize], mut i: usize) -> usize
+        {
+            while parent[i] != i
+            {
+                parent[i] = parent[parent[i]];
+                i = parent[i];
+            }
+            i
+        }
+        for (i, &uid) in uids.iter().enumerate()
+        {
+            for (src_uid, _) in board.get_bots_heard_by(uid).unwrap_or_default()
+            {
+                if let Some(&j) = position.get(&src_uid)
+                {
+                    let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                    parent[a] = b;
+                }
+            }
+        }
+        let mut sizes: HashMap<usize, usize> = HashMap::new();
+        for i in 0..uids.len()
+        {
+            *sizes.entry(find(&mut parent, i)).or_insert(0) += 1;
+        }
+        (sizes.len(), sizes.values().copied().max().unwrap_or(0))
+    }
+
+    /// Distance from a bot to its nearest neighbour, searching outwards in growing circles
+    /// Only called when there is at least one other bot on the board
+    fn nearest_neighbour(board: &Board, index: usize, uid: u16) -> f64
+    {
+        let coord = board.get_coord_from_index(&index).ok().unwrap();
+        let mut radius = 2.0;
+        loop
+        {
+            let nearest = board.get_bots_near(&coord, radius).into_iter()
+                .filter(|n| n.0 != uid)
+                .map(|n| n.1)
+                .fold(f64::INFINITY, f64::min);
+            if nearest.is_finite()
+            {
+                return nearest;
+            }
+            radius *= 2.0;
+        }
+    }
+}
+
+/// Root mean square distance between estimated and real positions, after the estimates have been
+/// rotated and shifted (and mirrored, if that fits better) to best match the real positions
+/// # Arguments
+/// * 'pairs' - (estimated position, real position) of every localized bot, both in mm
+/// # Returns
+/// * The error in mm, or None if there are no pairs
+pub fn localization_error(pairs: &[(Position, Position)]) -> Option<f64>
+{
+    if pairs.is_empty()
+    {
+        return None;
+    }
+    let n = pairs.len() as f64;
+    let (ex, ey, tx, ty) = pairs.iter()
+        .fold((0.0, 0.0, 0.0, 0.0), |m, (e, t)| (m.0 + e.0 / n, m.1 + e.1 / n, m.2 + t.0 / n, m.3 + t.1 / n));
+
+    let error = |mirror: f64| -> f64 {
+        // Best rotation of the centred estimates onto the centred real positions
+        let centred: Vec<_> = pairs.iter()
+            .map(|(e, t)| ((e.0 - ex, mirror * (e.1 - ey)), (t.0 - tx, t.1 - ty)))
+            .collect();
+        let dot: f64 = centred.iter().map(|(e, t)| e.0 * t.0 + e.1 * t.1).sum();
+        let cross: f64 = centred.iter().map(|(e, t)| e.0 * t.1 - e.1 * t.0).sum();
+        let (sin, cos) = cross.atan2(dot).sin_cos();
+        let total: f64 = centred.iter()
+            .map(|(e, t)| (e.0 * cos - e.1 * sin - t.0).powi(2) + (e.0 * sin + e.1 * cos - t.1).powi(2))
+            .sum();
+        (total / n).sqrt()
+    };
+    Some(error(1.0).min(error(-1.0)))
+}
+
+/// Spread of a set of phases: one minus the length of their mean as unit vectors around the cycle,
+/// the Kuramoto order parameter
+/// # Arguments
+/// * 'phases' - Phases from 0 up to 1
+/// # Returns
+/// * The spread from 0 to 1, or None if there are no phases
+pub fn phase_spread(phases: &[f64]) -> Option<f64>
+{
+    if phases.is_empty()
+    {
+        return None;
+    }
+    let (sin, cos) = phases.iter()
+        .map(|p| (p * std::f64::consts::TAU).sin_cos())
+        .fold((0.0, 0.0), |sum, (s, c)| (sum.0 + s, sum.1 + c));
+    Some(1.0 - (sin * sin + cos * cos).sqrt() / phases.len() as f64)
+}
+
+/// Step a simulation and write its metrics as CSV, one row every few ticks
+/// # Arguments
+/// * 'controller' - Simulation to run
+/// * 'ticks' - Number of ticks to run for
+/// * 'every' - Ticks between rows. The first row is the state before the first tick
+/// * 'out' - Where to write the CSV
+/// * 'debug' - Where to write the bots' debug lines as they are printed, or None to leave them in
+///   the controller's debug log
+pub fn write_series<W: Write>(controller: &mut BoardController, ticks: u64, every: u64, out: &mut W,
+                              mut debug: Option<&mut dyn Write>) -> io::Result<()>
+{
+    writeln!(out, "tick,{}", METRIC_COLUMNS.join(","))?;
+    for tick in 0..=ticks
+    {
+        if tick % every.max(1) == 0 || tick == ticks
+        {
+            let row = SwarmMetrics::compute(&controller.board).to_csv_row();
+            writeln!(out, "{},{}", controller.get_tick(), row.join(","))?;
+        }
+        if tick < ticks
+        {
+            controller.step();
+            if let Some(debug) = debug.as_mut()
+            {
+                for line in controller.take_debug_log()
+                {
+                    writeln!(debug, "tick {} bot {}: {}", line.tick, line.uid, line.text)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
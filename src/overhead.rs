@@ -0,0 +1,161 @@
+/*
+ * overhead
+ * Purpose: The overhead controller, which hangs above the arena and broadcasts to every bot at
+ * once, e.g. to upload a new program to the whole swarm. It can also track every bot, like the
+ * camera of an ARK setup, and tell each one where it is, see GpsSettings
+ *
+ */
+use std::collections::VecDeque;
+use crate::board::Board;
+use crate::board::board_map::BoardMap;
+use crate::kilobot::bootloader;
+use crate::kilobot::messages::{GpsFix, Message, MessageType};
+use crate::rng::Rng;
+
+/// Number of BOOT messages sent before an image, so bots that miss some still enter the bootloader
+pub const BOOT_REPEATS: usize = 8;
+
+/// Settings of the GPS service, which sends every bot its own position and heading in a GPS
+/// message, see GpsFix
+/// # Fields
+/// * 'period' - Ticks between fixes
+/// * 'noise' - Standard deviation of the error added to the position, in mm
+/// * 'heading_noise' - Standard deviation of the error added to the heading, in degrees
+/// * 'dropout' - Chance that a bot misses a fix, from 0 to 1
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct GpsSettings
+{
+    pub period: u32,
+    pub noise: f64,
+    pub heading_noise: f64,
+    pub dropout: f64,
+}
+
+/// The overhead controller. It broadcasts one message per tick, in the order they were queued,
+/// and sends GPS fixes on top of that if the GPS service is on
+/// # Fields
+/// * 'queue' - Messages waiting to be sent
+/// * 'sent' - Number of messages sent so far
+/// * 'gps' - Settings of the GPS service and the generator of its errors, or None if it is off
+/// * 'fixes' - Number of GPS fixes sent so far
+#[derive(Default)]
+pub struct Overhead
+{
+    queue: VecDeque<Message>,
+    sent: u64,
+    gps: Option<(GpsSettings, Rng)>,
+    fixes: u64,
+}
+
+impl Overhead
+{
+    /// Create an overhead controller with nothing to send
+    pub fn new() -> Overhead
+    {
+        Overhead::default()
+    }
+
+    /// Queue a message to broadcast
+    /// # Arguments
+    /// * 'msg' - The message
+    pub fn send(&mut self, msg: Message)
+    {
+        self.queue.push_back(msg);
+    }
+
+    /// Queue the upload of a program to every bot: BOOT messages to send the bots into their
+    /// bootloader, then the image, see bootloader::image_messages
+    /// # Arguments
+    /// * 'image' - Image of the program, see programs::from_image
+    /// * 'rounds' - Number of times the image is sent, so bots that missed part of it can fill in
+    ///   the gaps
+    pub fn upload(&mut self, image: &[u8], rounds: u32)
+    {
+        for _ in 0..BOOT_REPEATS
+        {
+            self.send(Message::new(MessageType::BOOT as u8, [0; 9]));
+        }
+        let messages = bootloader::image_messages(image);
+        for _ in 0..rounds
+        {
+            self.queue.extend(messages.iter().cloned());
+        }
+    }
+
+    /// Returns whether every queued message has been sent
+    pub fn is_idle(&self) -> bool
+    {
+        self.queue.is_empty()
+    }
+
+    /// Get the number of messages sent so far
+    pub fn get_sent(&self) -> u64
+    {
+        self.sent
+    }
+
+    /// Take the message to broadcast this tick
+    /// # Returns
+    /// * The message, or None if there is nothing to send
+    pub fn transmit(&mut self) -> Option<Message>
+    {
+        let msg = self.queue.pop_front()?;
+        self.sent += 1;
+        Some(msg)
+    }
+
+    /// Turn the GPS service on or off
+    /// # Arguments
+    /// * 'settings' - Settings of the service, or None to turn it off
+    /// * 'seed' - Seed of the errors and dropouts, so runs are reproducible
+    pub fn set_gps(&mut self, settings: Option<GpsSettings>, seed: u32)
+    {
+        self.gps = settings.map(|settings| (settings, Rng::new(seed)));
+    }
+
+    /// Get the settings of the GPS service, or None if it is off
+    pub fn get_gps(&self) -> Option<&GpsSettings>
+    {
+        self.gps.as_ref().map(|gps| &gps.0)
+    }
+
+    /// Get the number of GPS fixes sent so far
+    pub fn get_fixes(&self) -> u64
+    {
+        self.fixes
+    }
+
+    /// Work out the GPS fixes to send this tick from the bots' true positions and facings
+    /// # Arguments
+    /// * 'tick' - The current tick. Fixes are sent on every multiple of the period
+    /// * 'board' - The board the bots are on
+    /// # Returns
+    /// * The fixes, as GPS messages by index of the bot they are for
+    pub fn gps_fixes(&mut self, tick: u64, board: &Board) -> Vec<(usize, Message)>
+    {
+        let (settings, rng) = match &mut self.gps
+        {
+            Some((settings, rng)) if tick.is_multiple_of(settings.period.max(1) as u64) => (*settings, rng),
+            _ => return Vec::new(),
+        };
+        let mut fixes = Vec::new();
+        for index in board.get_occupied_indices()
+        {
+            // Draw the same numbers whether or not the fix is dropped, so the errors of one bot
+            // don't depend on the luck of the others
+            let dropped = rng.next_f64() < settings.dropout;
+            let (x, y) = board.get_coord_from_index(&index).ok().unwrap().as_mm_tuple();
+            let x = x + settings.noise * rng.next_gaussian();
+            let y = y + settings.noise * rng.next_gaussian();
+            let facing = board.get_bot_location_at_index(index).ok().unwrap().get_facing() as f64;
+            let heading = (facing + settings.heading_noise * rng.next_gaussian()).round().rem_euclid(360.0);
+            if !dropped
+            {
+                let clamp = |mm: f64| mm.round().clamp(0.0, u16::MAX as f64) as u16;
+                fixes.push((index, GpsFix { x: clamp(x), y: clamp(y), heading: Some(heading as u16) }.to_message()));
+            }
+        }
+        self.fixes += fixes.len() as u64;
+        fixes
+    }
+}
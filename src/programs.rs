@@ -0,0 +1,87 @@
+/*
+ * programs
+ * Purpose: Library of ready made kilobot programs
+ *
+ */
+pub mod firefly;
+pub mod gradient;
+pub mod self_assembly;
+pub mod simple_movement;
+pub mod taxis;
+pub mod wander;
+
+use std::fmt;
+
+use crate::kilobot::program::Program;
+
+/// Errors that can occur while creating a program from its name
+#[derive(Debug)]
+pub enum ProgramError
+{
+    /// No ready made program has the name, and it isn't the path of a program that can be loaded
+    Unknown,
+    /// The name is the path of a program that couldn't be loaded, with the loader's description of
+    /// the problem. Only the ffi, scripting and wasm features load programs from files
+    #[allow(dead_code)]
+    Load(String),
+}
+
+impl fmt::Display for ProgramError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            ProgramError::Unknown => write!(f, "unknown program"),
+            ProgramError::Load(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+/// Create a program from its name, so scenarios can choose what their bots run
+/// # Arguments
+/// * 'name' - Name of the program. With the ffi feature, the path of a C program built as a shared
+///   library also works, see ffi::load, with the scripting feature the path of a Lua script, see
+///   scripting::load, and with the wasm feature the path of a WebAssembly module, see wasm::load
+/// # Returns
+/// * A fresh instance of the program, ProgramError::Unknown if there is no program with that name, or
+///   ProgramError::Load if the file it names couldn't be loaded
+pub fn from_name(name: &str) -> Result<Box<dyn Program>, ProgramError>
+{
+    match name
+    {
+        "aggregate" => Ok(Box::new(taxis::Aggregate::new())),
+        "disperse" => Ok(Box::new(taxis::Disperse::new())),
+        "firefly" => Ok(Box::new(firefly::Firefly::new())),
+        "gradient" => Ok(Box::new(gradient::Gradient::new())),
+        "phototaxis" => Ok(Box::new(taxis::Phototaxis::new())),
+        "self_assembly" => Ok(Box::new(self_assembly::SelfAssembly::new())),
+        "simple_movement" => Ok(Box::new(simple_movement::new())),
+        "trail" => Ok(Box::new(taxis::Trail::new())),
+        "wander" => Ok(Box::new(wander::Wander::new())),
+        #[cfg(feature = "ffi")]
+        _ if name.ends_with(std::env::consts::DLL_SUFFIX) => crate::ffi::load(name).map(|p| Box::new(p) as Box<dyn Program>).map_err(|e| ProgramError::Load(e.to_string())),
+        #[cfg(feature = "scripting")]
+        _ if name.ends_with(".lua") => crate::scripting::load(name).map(|p| Box::new(p) as Box<dyn Program>).map_err(|e| ProgramError::Load(e.to_string())),
+        #[cfg(feature = "wasm")]
+        _ if name.ends_with(".wasm") || name.ends_with(".wat") => crate::wasm::load(name).map(|p| Box::new(p) as Box<dyn Program>).map_err(|e| ProgramError::Load(e.to_string())),
+        _ => Err(ProgramError::Unknown),
+    }
+}
+
+/// Create a program from an image uploaded over the air, see kilobot::bootloader. The image of a
+/// ready made program is its name as text, and with the wasm feature an image can also be a
+/// WebAssembly module
+/// # Arguments
+/// * 'image' - The image
+/// # Returns
+/// * A fresh instance of the program, or None if the image isn't a program
+pub fn from_image(image: &[u8]) -> Option<Box<dyn Program>>
+{
+    #[cfg(feature = "wasm")]
+    if image.starts_with(b"\0asm")
+    {
+        return crate::wasm::load_bytes(image, "uploaded image").ok().map(|p| Box::new(p) as Box<dyn Program>);
+    }
+    from_name(std::str::from_utf8(image).ok()?).ok()
+}
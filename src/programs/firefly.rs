@@ -0,0 +1,123 @@
+/*
+ * firefly
+ * Purpose: Synchronization of pulse-coupled oscillators, after Mirollo and Strogatz, "Synchronization
+ * of pulse-coupled biological oscillators" (1990). Every bot has a clock that counts up to PERIOD and
+ * flashes its LED when it wraps around. The next message a bot sends after flashing says so, and a
+ * bot that hears about a flash moves its own clock ahead by a fraction of how far it already was
+ * through its cycle when the neighbour flashed. Bots close to flashing are pulled into flashing with
+ * their neighbour, and once two bots flash together they stay together, so starting from random
+ * phases the swarm ends up flashing in sync.
+ *
+ * News of a flash only goes out with the next message, up to a whole tx period later, which is
+ * long enough to stop the swarm from ever synchronizing. Messages carry how long ago the flash was
+ * so the receiver can respond as if it had seen the flash itself
+ *
+ */
+use crate::hal::Hal;
+use crate::kilobot::TICKS_PER_SECOND;
+use crate::kilobot::messages::Message;
+use crate::kilobot::program::Program;
+
+/// Ticks between flashes
+pub const PERIOD: u16 = 2 * TICKS_PER_SECOND as u16;
+/// Ticks the LED stays on after a flash
+const FLASH_TICKS: u16 = TICKS_PER_SECOND as u16 / 8;
+/// Fraction of its clock a bot moves ahead by when it hears a neighbour flash
+const COUPLING: f64 = 0.2;
+/// Ticks after flashing during which a bot ignores its neighbours' flashes. Without it a bot that
+/// has just been pulled into step with one neighbour is pulled straight back out by the next
+const REFRACTORY_TICKS: u16 = PERIOD / 4;
+
+/// Pulse-coupled oscillator. Bots don't move
+/// # Fields
+/// * 'clock' - Ticks since the last flash, from 0 up to PERIOD - 1
+/// * 'flashed' - Whether the bot has flashed since it last sent a message
+#[derive(Default)]
+pub struct Firefly
+{
+    clock: u16,
+    flashed: bool,
+}
+
+impl Firefly
+{
+    /// Create a new Firefly program
+    pub fn new() -> Firefly
+    {
+        Firefly::default()
+    }
+}
+
+impl Program for Firefly
+{
+    fn setup(&mut self, bot: &mut dyn Hal)
+    {
+        // Start at a random point of the cycle
+        self.clock = (bot.rand() as u16 * PERIOD) >> 8;
+    }
+
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        self.clock += 1;
+        if self.clock >= PERIOD
+        {
+            self.clock = 0;
+            self.flashed = true;
+        }
+
+        if self.clock < FLASH_TICKS
+        {
+            bot.set_led(255, 255, 0);
+        } else {
+            bot.set_led(0, 0, 0);
+        }
+    }
+
+    fn message_tx(&mut self, _bot: &mut dyn Hal) -> Option<Message>
+    {
+        // Whether the bot has flashed since its last message, and how many ticks ago
+        let clock = self.clock.to_le_bytes();
+        Some(Message::new(0, [self.flashed as u8, clock[0], clock[1], 0, 0, 0, 0, 0, 0]))
+    }
+
+    fn message_tx_success(&mut self, _bot: &mut dyn Hal)
+    {
+        self.flashed = false;
+    }
+
+    fn message_rx(&mut self, _bot: &mut dyn Hal, msg: &Message, _dist: u16)
+    {
+        let data = msg.data();
+        if !msg.is_normal() || data[0] != 1
+        {
+            return;
+        }
+        // Work out where this bot's clock was when the neighbour flashed and move it ahead from
+        // there. If that takes it past the end of the cycle it would have flashed along with the
+        // neighbour, so it is now exactly in step with it
+        let ago = u16::from_le_bytes([data[1], data[2]]).min(PERIOD - 1);
+        let then = (self.clock + PERIOD - ago) % PERIOD;
+        if then < REFRACTORY_TICKS
+        {
+            return;
+        }
+        let moved = then + (then as f64 * COUPLING).ceil() as u16;
+        let (clock, flashed) = if moved >= PERIOD
+        {
+            (ago, true)
+        } else if moved + ago >= PERIOD {
+            // Would have reached the end of its cycle since
+            (moved + ago - PERIOD, true)
+        } else {
+            (moved + ago, false)
+        };
+        self.clock = clock;
+        // Its neighbours need to hear about a flash it would have had too
+        self.flashed |= flashed;
+    }
+
+    fn oscillator_phase(&self) -> Option<f64>
+    {
+        Some(self.clock as f64 / PERIOD as f64)
+    }
+}
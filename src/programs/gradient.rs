@@ -0,0 +1,112 @@
+/*
+ * gradient
+ * Purpose: Hop count gradient, following the kilolib gradient example. The seed bot broadcasts a
+ * value of 0, every other bot takes the smallest value it hears plus one and broadcasts that.
+ * The LED shows the value, cycling through six colours so neighbouring rings are easy to tell apart
+ *
+ */
+use crate::hal::{debug_print, Hal};
+use crate::kilobot::TICKS_PER_SECOND;
+use crate::kilobot::messages::Message;
+use crate::kilobot::program::Program;
+
+/// Uid of the bot that anchors the gradient
+pub const SEED_UID: u16 = 0;
+/// Value of a bot that hasn't heard any gradient
+pub const GRADIENT_MAX: u16 = u16::MAX;
+/// Ticks without hearing a value that supports the bot's own before it forgets it. Lets the
+/// gradient recover when bots move or the seed disappears
+const ANCHOR_TIMEOUT: u32 = 2 * TICKS_PER_SECOND;
+/// LED colours of gradient values, value 0 is red
+const COLOURS: [(u8, u8, u8); 6] = [(255, 0, 0), (255, 255, 0), (0, 255, 0), (0, 255, 255), (0, 0, 255), (255, 0, 255)];
+
+/// Hop count gradient. Bots don't move
+/// # Fields
+/// * 'value' - The bot's gradient value
+/// * 'last_anchored' - Tick the bot last heard a value that supports its own
+/// * 'received' - Smallest value received since the last tick
+#[derive(Default)]
+pub struct Gradient
+{
+    value: u16,
+    last_anchored: u32,
+    received: Option<u16>,
+}
+
+impl Gradient
+{
+    /// Create a new Gradient program
+    pub fn new() -> Gradient
+    {
+        Gradient::default()
+    }
+
+    /// Get the bot's gradient value, or GRADIENT_MAX if it hasn't heard one
+    pub fn get_value(&self) -> u16
+    {
+        self.value
+    }
+}
+
+impl Program for Gradient
+{
+    fn setup(&mut self, bot: &mut dyn Hal)
+    {
+        self.value = if bot.get_uid() == SEED_UID { 0 } else { GRADIENT_MAX };
+    }
+
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        let old_value = self.value;
+        if bot.get_uid() != SEED_UID
+        {
+            if bot.get_ticks() > self.last_anchored + ANCHOR_TIMEOUT
+            {
+                self.value = GRADIENT_MAX;
+            }
+            if let Some(received) = self.received.take()
+            {
+                if received.saturating_add(1) <= self.value
+                {
+                    self.value = received.saturating_add(1);
+                    self.last_anchored = bot.get_ticks();
+                }
+            }
+        }
+        match self.value
+        {
+            v if v == old_value => {},
+            GRADIENT_MAX => debug_print!(bot, "lost the gradient"),
+            v => debug_print!(bot, "gradient {}", v),
+        }
+
+        match self.value
+        {
+            GRADIENT_MAX => bot.set_led(0, 0, 0),
+            v => {
+                let (r, g, b) = COLOURS[v as usize % COLOURS.len()];
+                bot.set_led(r, g, b);
+            },
+        }
+    }
+
+    fn message_tx(&mut self, _bot: &mut dyn Hal) -> Option<Message>
+    {
+        if self.value == GRADIENT_MAX
+        {
+            return None;
+        }
+        let value = self.value.to_le_bytes();
+        Some(Message::new(0, [value[0], value[1], 0, 0, 0, 0, 0, 0, 0]))
+    }
+
+    fn message_rx(&mut self, _bot: &mut dyn Hal, msg: &Message, _dist: u16)
+    {
+        if !msg.is_normal()
+        {
+            return;
+        }
+        let value = u16::from_le_bytes([msg.data()[0], msg.data()[1]]);
+        self.received = Some(self.received.map_or(value, |r| r.min(value)));
+    }
+}
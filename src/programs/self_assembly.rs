@@ -0,0 +1,546 @@
+/*
+ * self_assembly
+ * Purpose: Shape formation modeled on Rubenstein et al., "Programmable self-assembly in a
+ * thousand-robot swarm" (Science, 2014)
+ *
+ * Four seed bots with known positions anchor a coordinate system and a hop count gradient. Bots on
+ * the outside of the swarm with the highest gradient leave one at a time and follow the edge of
+ * the swarm clockwise, keeping it on their right. Stationary bots work out their position by
+ * trilateration from localized neighbours, and moving bots do the same as they pass. Once a moving
+ * bot is inside the target shape it keeps going until it is about to leave the shape, or until
+ * the closest bot is part of the shape and has a gradient at least as high as its own, and then
+ * joins the shape where it is.
+ *
+ * Bots only move in steps of one body length and turns of 45 degrees, stopping to listen to their
+ * neighbours between steps.
+ *
+ */
+use std::collections::VecDeque;
+use crate::hal::Hal;
+use crate::kilobot::{FORWARD_SPEED, ROTATION_SPEED, TICKS_PER_SECOND};
+use crate::kilobot::localization::{Localizer, MIN_ANCHORS};
+use crate::kilobot::messages::Message;
+use crate::kilobot::program::Program;
+
+/// Number of seed bots. Seeds are the bots with the lowest uids
+pub const NUM_SEEDS: u16 = 4;
+/// Positions of the seeds in mm, indexed by uid. These match the first four bots of a "half_disc"
+/// placement: bot 0 in the middle, 1 and 2 either side of it and 3 below it
+pub const SEED_POSITIONS: [(f64, f64); NUM_SEEDS as usize] = [(0.0, 0.0), (-33.0, 0.0), (33.0, 0.0), (0.0, 33.0)];
+/// Side of one cell of the shape bitmap in mm
+pub const SHAPE_CELL_MM: f64 = 33.0;
+/// Target shape, one character per cell, with '#' inside the shape. Rows run top to bottom. A K, as
+/// in the paper, so the shape isn't convex and its legs can only be reached around the notch between them
+pub const SHAPE: [&str; 7] = [
+    "###.....###",
+    "###....###.",
+    "###...###..",
+    "######.....",
+    "###...###..",
+    "###....###.",
+    "###.....###",
+];
+/// Cell of SHAPE that contains seed 0, as (column, row)
+pub const SHAPE_ORIGIN: (i32, i32) = (5, 7);
+
+/// Diameter of a kilobot in mm. Bots move one body length at a time
+const BODY_LENGTH_MM: u32 = 33;
+/// Ticks needed to drive forward one body length
+const STEP_TICKS: u32 = (BODY_LENGTH_MM * TICKS_PER_SECOND).div_ceil(FORWARD_SPEED as u32);
+/// Ticks needed to turn 45 degrees
+const TURN_TICKS: u32 = 45 * TICKS_PER_SECOND / ROTATION_SPEED as u32;
+/// Distance a bot following the edge tries to keep to its closest neighbour, in mm
+const EDGE_DISTANCE_MIN: u16 = 30;
+const EDGE_DISTANCE_MAX: u16 = 50;
+/// A moving bot waits while a moving bot with a lower uid is closer than this, in mm
+const YIELD_DISTANCE: u16 = 100;
+/// Only neighbours closer than this count towards the gradient, in mm
+const GRADIENT_DISTANCE: u16 = 50;
+/// Chance out of 256 that a waiting bot that is allowed to start moving does so, checked once per
+/// transmission period. Keeps bots on the edge of the swarm from all leaving at once
+const START_CHANCE: u8 = 32;
+/// Number of decisions in a row a moving bot can fail to get anywhere before it gives up, stops and
+/// waits for another turn. Clears jams of moving bots that are in each other's way
+const STUCK_LIMIT: u8 = 8;
+/// Number of recently visited places a moving bot remembers. Coming back to one of them isn't
+/// progress
+const VISITED_LEN: usize = 16;
+/// Ticks to wait at the start for the gradient and localization to settle before anyone moves
+const STARTUP_TICKS: u32 = 10 * TICKS_PER_SECOND;
+/// Largest root mean square error in mm of the measured distances for a position estimate to be
+/// trusted
+const MAX_FIT_ERROR: f64 = 10.0;
+/// Value of a bot that hasn't heard any gradient
+const GRADIENT_MAX: u8 = u8::MAX;
+
+/// What a bot is doing
+/// * 'Seed' - One of the seeds, never moves and knows its position
+/// * 'Waiting' - Part of the initial swarm, waiting for its turn to move
+/// * 'Moving' - Following the edge of the swarm
+/// * 'Joined' - Stopped as part of the shape
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum State
+{
+    Seed,
+    Waiting,
+    Moving,
+    Joined,
+}
+
+impl State
+{
+    fn from_u8(value: u8) -> State
+    {
+        match value
+        {
+            0 => State::Seed,
+            2 => State::Moving,
+            3 => State::Joined,
+            _ => State::Waiting,
+        }
+    }
+
+    fn as_u8(self) -> u8
+    {
+        match self
+        {
+            State::Seed => 0,
+            State::Waiting => 1,
+            State::Moving => 2,
+            State::Joined => 3,
+        }
+    }
+}
+
+/// Motion a moving bot is part way through
+enum Action
+{
+    /// Stopped, listening to neighbours for the given number of ticks
+    Listen(u32),
+    /// Turning left (true) or right (false) for the given number of ticks, then stepping forward
+    Turn(bool, u32),
+    /// Driving forward for the given number of ticks
+    Step(u32),
+}
+
+/// What a bot heard from one neighbour
+/// # Fields
+/// * 'uid' - Uid of the neighbour
+/// * 'dist' - Measured distance in mm
+/// * 'gradient' - Gradient value of the neighbour
+/// * 'state' - State of the neighbour
+/// * 'position' - Position of the neighbour in mm, if it is localized
+struct Neighbour
+{
+    uid: u16,
+    dist: u16,
+    gradient: u8,
+    state: State,
+    position: Option<(f64, f64)>,
+}
+
+/// Self-assembly into SHAPE. Run with a "half_disc" placement so the seeds are where they expect
+/// # Fields
+/// * 'state' - What the bot is doing
+/// * 'gradient' - Hop count from seed 0 through stationary bots
+/// * 'position' - Estimated position in mm, or None if not localized
+/// * 'heading' - Estimated heading of a moving bot in degrees clockwise from the seeds' "up", or
+///   None if it isn't known
+/// * 'last_step_from' - Estimated position before the last step, used to work out the heading
+/// * 'last_target' - Where the last step was meant to end up
+/// * 'stuck' - Number of decisions in a row the bot has failed to get anywhere new
+/// * 'visited' - Recently visited places, rounded to the nearest body length
+/// * 'neighbours' - Neighbours heard since the last decision
+/// * 'localizer' - Positions of and distances to the stationary neighbours that know where they are
+/// * 'action' - Motion in progress while moving
+#[derive(Default)]
+pub struct SelfAssembly
+{
+    state: Option<State>,
+    gradient: u8,
+    position: Option<(f64, f64)>,
+    heading: Option<u16>,
+    last_step_from: Option<(f64, f64)>,
+    last_target: Option<(f64, f64)>,
+    stuck: u8,
+    visited: VecDeque<(i32, i32)>,
+    neighbours: Vec<Neighbour>,
+    localizer: Localizer,
+    action: Option<Action>,
+}
+
+impl SelfAssembly
+{
+    /// Create a new SelfAssembly program
+    pub fn new() -> SelfAssembly
+    {
+        SelfAssembly::default()
+    }
+
+    /// Get what the bot is doing
+    pub fn get_state(&self) -> State
+    {
+        self.state.unwrap_or(State::Waiting)
+    }
+
+    /// Get the bot's estimated position in mm, or None if it hasn't localized
+    pub fn get_position(&self) -> Option<(f64, f64)>
+    {
+        self.position
+    }
+
+    /// Returns whether a position in mm is inside SHAPE
+    pub fn inside_shape(position: (f64, f64)) -> bool
+    {
+        let col = (position.0 / SHAPE_CELL_MM).round() as i32 + SHAPE_ORIGIN.0;
+        let row = (position.1 / SHAPE_CELL_MM).round() as i32 + SHAPE_ORIGIN.1;
+        if col < 0 || row < 0 || row as usize >= SHAPE.len()
+        {
+            return false;
+        }
+        SHAPE[row as usize].as_bytes().get(col as usize) == Some(&b'#')
+    }
+
+    /// Take everything heard since the last decision and update the gradient and position from
+    /// the stationary neighbours
+    /// # Arguments
+    /// * 'keep_position' - Whether to keep the previous position if there is no new estimate.
+    ///   Bots that have moved since then can't
+    fn update_from_neighbours(&mut self, keep_position: bool) -> Vec<Neighbour>
+    {
+        let heard = std::mem::take(&mut self.neighbours);
+        if self.get_state() == State::Seed
+        {
+            return heard;
+        }
+        let stationary: Vec<&Neighbour> = heard.iter().filter(|n| n.state != State::Moving).collect();
+        self.gradient = stationary.iter()
+            .filter(|n| n.dist < GRADIENT_DISTANCE)
+            .map(|n| n.gradient.saturating_add(1))
+            .min()
+            .unwrap_or(GRADIENT_MAX);
+
+        let previous = if keep_position { self.position } else { self.position.take() };
+        if let Some(estimate) = self.localizer.estimate(previous)
+        {
+            if estimate.anchors >= MIN_ANCHORS && estimate.error <= MAX_FIT_ERROR
+            {
+                self.position = Some(estimate.position);
+            }
+        }
+        heard
+    }
+
+    /// Decide what a moving bot does next, after it has listened to its neighbours
+    fn decide_move(&mut self, bot: &mut dyn Hal)
+    {
+        let heard = self.update_from_neighbours(false);
+        // Only stationary bots make up the edge, following a moving bot would lead the bot astray
+        let stationary: Vec<&Neighbour> = heard.iter().filter(|n| n.state != State::Moving).collect();
+        let nearest = match stationary.iter().min_by_key(|n| (n.dist, n.uid))
+        {
+            Some(n) => n,
+            // Lost the swarm, turn around and step back towards it
+            None => return self.turn_and_step(4, false),
+        };
+        if heard.iter().any(|n| n.state == State::Moving && n.uid < bot.get_uid() && n.dist < YIELD_DISTANCE)
+        {
+            return self.listen(bot);
+        }
+
+        // Work out the heading from where the last step started and ended. If the bot didn't get
+        // anywhere it was blocked by a bot it can't see, so the heading is unchanged and the same
+        // step isn't tried again straight away
+        let mut blocked_target = None;
+        let mut was_blocked = false;
+        if let (Some(from), Some(to)) = (self.last_step_from.take(), self.position)
+        {
+            let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+            if (dx * dx + dy * dy).sqrt() > BODY_LENGTH_MM as f64 / 2.0
+            {
+                let place = ((to.0 / BODY_LENGTH_MM as f64).round() as i32, (to.1 / BODY_LENGTH_MM as f64).round() as i32);
+                if self.visited.contains(&place)
+                {
+                    self.stuck += 1;
+                } else {
+                    self.stuck = 0;
+                    self.visited.push_back(place);
+                    if self.visited.len() > VISITED_LEN
+                    {
+                        self.visited.pop_front();
+                    }
+                }
+                let degrees = dx.atan2(-dy).to_degrees().rem_euclid(360.0);
+                self.heading = Some(((degrees / 45.0).round() as u16 % 8) * 45);
+            } else {
+                blocked_target = self.last_target;
+                was_blocked = true;
+                self.stuck += 1;
+            }
+        }
+        if self.stuck >= STUCK_LIMIT
+        {
+            return self.give_way(bot);
+        }
+        let blocked = |p: (f64, f64)| blocked_target.is_some_and(|b: (f64, f64)| (p.0 - b.0).abs() + (p.1 - b.1).abs() < BODY_LENGTH_MM as f64 / 2.0);
+
+        let (position, heading) = match (self.position, self.heading)
+        {
+            (Some(position), Some(heading)) => (position, heading),
+            _ => {
+                // Without a position and heading, fall back to steering by the distance to the
+                // closest neighbour alone. This isn't progress along the edge, so it counts
+                // towards giving up
+                self.last_step_from = self.position;
+                self.last_target = None;
+                self.stuck += 1;
+                // A blocked step turns well away, so successive tries cover every direction
+                return if was_blocked
+                {
+                    self.turn_and_step(3, true)
+                } else if nearest.dist < EDGE_DISTANCE_MIN
+                {
+                    self.turn_and_step(1, true)
+                } else if nearest.dist > EDGE_DISTANCE_MAX {
+                    self.turn_and_step(1, false)
+                } else {
+                    self.turn_and_step(0, false)
+                };
+            },
+        };
+
+        // Right hand rule: take the rightmost direction that keeps the bot next to the swarm
+        // without bumping into it. Directions are tried from 90 degrees right round to behind
+        let distance_to = |p: (f64, f64), others: &mut dyn Iterator<Item = (f64, f64)>| others
+            .map(|a| ((p.0 - a.0).powi(2) + (p.1 - a.1).powi(2)).sqrt())
+            .fold(f64::INFINITY, f64::min);
+        let edge_distance = |p| distance_to(p, &mut stationary.iter().filter_map(|n| n.position));
+        let clear_of_moving = |p| distance_to(p, &mut heard.iter().filter(|n| n.state == State::Moving).filter_map(|n| n.position)) >= EDGE_DISTANCE_MIN as f64;
+        let (turns, target) = match (0..8_u16)
+            .map(|k| (k, step_from(position, (heading + 450 - 45 * k) % 360)))
+            .find(|(_, p)| !blocked(*p) && clear_of_moving(*p) && (EDGE_DISTANCE_MIN as f64..=EDGE_DISTANCE_MAX as f64).contains(&edge_distance(*p)))
+        {
+            Some(choice) => choice,
+            // Nowhere along the edge to go, e.g. boxed in by other bots. Try again later
+            None => return self.give_way(bot),
+        };
+
+        if SelfAssembly::inside_shape(position)
+        {
+            let leaving = !SelfAssembly::inside_shape(target);
+            let settled = nearest.state != State::Waiting && nearest.gradient >= self.gradient;
+            if leaving || settled
+            {
+                self.state = Some(State::Joined);
+                self.action = None;
+                bot.stop();
+                return;
+            }
+        }
+
+        self.last_step_from = Some(position);
+        self.last_target = Some(target);
+        self.heading = Some((heading + 450 - 45 * turns) % 360);
+        if turns <= 2
+        {
+            self.turn_and_step(2 - turns as u32, false)
+        } else {
+            self.turn_and_step(turns as u32 - 2, true)
+        }
+    }
+
+    /// Called when a moving bot can't get anywhere. Listen and try again, or if that has happened
+    /// too often, stop and wait for another turn to move
+    fn give_way(&mut self, bot: &mut dyn Hal)
+    {
+        self.stuck += 1;
+        if self.stuck >= STUCK_LIMIT
+        {
+            self.state = Some(State::Waiting);
+            self.stuck = 0;
+            self.visited.clear();
+            self.heading = None;
+            self.action = None;
+            bot.stop();
+        } else {
+            self.listen(bot);
+        }
+    }
+
+    /// Turn 45 degrees a number of times and then step forward one body length
+    /// # Arguments
+    /// * 'turns' - Number of 45 degree turns
+    /// * 'left' - true to turn left, false to turn right
+    fn turn_and_step(&mut self, turns: u32, left: bool)
+    {
+        self.action = Some(Action::Turn(left, turns * TURN_TICKS));
+    }
+
+    /// Stop and listen to the neighbours for one transmission period
+    fn listen(&mut self, bot: &mut dyn Hal)
+    {
+        bot.stop();
+        self.action = Some(Action::Listen(bot.get_tx_period() + 1));
+    }
+
+    /// Advance the motion of a moving bot by one tick
+    fn run_moving(&mut self, bot: &mut dyn Hal)
+    {
+        self.action = match self.action.take()
+        {
+            None | Some(Action::Listen(0)) => {
+                self.decide_move(bot);
+                return;
+            },
+            Some(Action::Listen(t)) => Some(Action::Listen(t - 1)),
+            Some(Action::Turn(_, 0)) => Some(Action::Step(STEP_TICKS)),
+            Some(Action::Turn(left, t)) => {
+                if left { bot.turn_left() } else { bot.turn_right() }
+                Some(Action::Turn(left, t - 1))
+            },
+            Some(Action::Step(0)) => {
+                self.listen(bot);
+                return;
+            },
+            Some(Action::Step(t)) => {
+                bot.move_forward();
+                Some(Action::Step(t - 1))
+            },
+        };
+        // Anything heard while moving is out of date by the time the bot decides
+        if !matches!(self.action, Some(Action::Listen(_)))
+        {
+            self.neighbours.clear();
+            self.localizer.clear();
+        }
+    }
+
+    /// Check whether a waiting bot should start moving: it must be on the outside of the swarm,
+    /// which is where the gradient is highest among the waiting bots, and no neighbour may be moving
+    fn decide_start(&mut self, bot: &mut dyn Hal)
+    {
+        let heard = self.update_from_neighbours(true);
+        if bot.get_ticks() < STARTUP_TICKS || self.gradient == GRADIENT_MAX
+        {
+            return;
+        }
+        let uid = bot.get_uid();
+        let highest = heard.iter().all(|n| {
+            n.state != State::Moving && (n.state != State::Waiting || n.dist >= GRADIENT_DISTANCE || (self.gradient, uid) > (n.gradient, n.uid))
+        });
+        if highest && bot.rand() < START_CHANCE
+        {
+            self.state = Some(State::Moving);
+            self.listen(bot);
+        }
+    }
+}
+
+impl Program for SelfAssembly
+{
+    fn setup(&mut self, bot: &mut dyn Hal)
+    {
+        let uid = bot.get_uid();
+        if uid < NUM_SEEDS
+        {
+            self.state = Some(State::Seed);
+            self.gradient = if uid == 0 { 0 } else { 1 };
+            self.position = Some(SEED_POSITIONS[uid as usize]);
+        } else {
+            self.state = Some(State::Waiting);
+            self.gradient = GRADIENT_MAX;
+        }
+    }
+
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        let period = bot.get_tx_period().max(1);
+        match self.get_state()
+        {
+            State::Moving => self.run_moving(bot),
+            State::Waiting if bot.get_ticks().is_multiple_of(period) => self.decide_start(bot),
+            State::Seed | State::Joined if bot.get_ticks().is_multiple_of(period) => {
+                self.update_from_neighbours(true);
+            },
+            _ => {},
+        }
+
+        match self.get_state()
+        {
+            State::Seed => bot.set_led(255, 255, 255),
+            State::Waiting if self.position.is_some() => bot.set_led(0, 0, 255),
+            State::Waiting => bot.set_led(0, 0, 64),
+            State::Moving if self.position.is_some_and(SelfAssembly::inside_shape) => bot.set_led(255, 255, 0),
+            State::Moving => bot.set_led(255, 0, 0),
+            State::Joined => bot.set_led(0, 255, 0),
+        }
+    }
+
+    fn message_tx(&mut self, bot: &mut dyn Hal) -> Option<Message>
+    {
+        // uid, gradient, state, whether the position is known, then the position
+        let uid = bot.get_uid().to_le_bytes();
+        let mut data = [uid[0], uid[1], self.gradient, self.get_state().as_u8(), 0, 0, 0, 0, 0];
+        // Moving bots share their position too, so others can keep out of their way. It isn't
+        // used to localize from, a bot that is moving can't measure it accurately
+        if let Some((x, y)) = self.position
+        {
+            let (x, y) = ((x.round() as i16).to_le_bytes(), (y.round() as i16).to_le_bytes());
+            data[4] = 1;
+            data[5..9].copy_from_slice(&[x[0], x[1], y[0], y[1]]);
+        }
+        Some(Message::new(0, data))
+    }
+
+    fn message_rx(&mut self, _bot: &mut dyn Hal, msg: &Message, dist: u16)
+    {
+        if !msg.is_normal()
+        {
+            return;
+        }
+        let data = msg.data();
+        let neighbour = Neighbour {
+            uid: u16::from_le_bytes([data[0], data[1]]),
+            dist,
+            gradient: data[2],
+            state: State::from_u8(data[3]),
+            position: if data[4] == 1
+            {
+                Some((i16::from_le_bytes([data[5], data[6]]) as f64, i16::from_le_bytes([data[7], data[8]]) as f64))
+            } else {
+                None
+            },
+        };
+        // Moving bots can't be localized from, they don't know accurately where they are
+        match neighbour.position
+        {
+            Some(position) if neighbour.state != State::Moving => self.localizer.add(neighbour.uid, position, dist),
+            _ => self.localizer.remove(neighbour.uid),
+        }
+        // Only keep the latest message from each neighbour
+        self.neighbours.retain(|n| n.uid != neighbour.uid);
+        self.neighbours.push(neighbour);
+    }
+
+    fn position_estimate(&self) -> Option<(f64, f64)>
+    {
+        self.position
+    }
+
+    fn state_size(&self) -> Option<usize>
+    {
+        Some(std::mem::size_of_val(self) + self.visited.len() * std::mem::size_of::<(i32, i32)>()
+             + self.neighbours.len() * std::mem::size_of::<Neighbour>() + self.localizer.heap_size())
+    }
+}
+
+/// Get the position one step from a position
+/// # Arguments
+/// * 'position' - Where the step starts, in mm
+/// * 'heading' - Direction of the step in degrees, a multiple of 45
+fn step_from(position: (f64, f64), heading: u16) -> (f64, f64)
+{
+    let radians = (heading as f64).to_radians();
+    let step = BODY_LENGTH_MM as f64;
+    (position.0 + radians.sin().round() * step, position.1 - radians.cos().round() * step)
+}
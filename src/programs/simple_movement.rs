@@ -0,0 +1,54 @@
+/*
+ * simple_movement
+ * Purpose: Port of the kilolib simple_movement example, to show how a C program carries over to
+ * the kilolib module. The bot drives straight, turns left and turns right for 2 seconds each, with
+ * its LED green, red and blue, and then starts over
+ *
+ */
+use crate::kilolib::*;
+
+/// The example has no globals
+#[derive(Default)]
+pub struct Globals {}
+
+fn setup(_g: &mut Globals) {}
+
+fn loop_(_g: &mut Globals)
+{
+    // Set the LED green.
+    set_color(RGB(0, 1, 0));
+    // Spinup the motors to overcome friction.
+    spinup_motors();
+    // Move straight for 2 seconds (2000 ms).
+    set_motors(kilo_straight_left(), kilo_straight_right());
+    delay(2000);
+
+    // Set the LED red.
+    set_color(RGB(1, 0, 0));
+    // Spinup the motors to overcome friction.
+    spinup_motors();
+    // Turn left for 2 seconds (2000 ms).
+    set_motors(kilo_turn_left(), 0);
+    delay(2000);
+
+    // Set the LED blue.
+    set_color(RGB(0, 0, 1));
+    // Spinup the motors to overcome friction.
+    spinup_motors();
+    // Turn right for 2 seconds (2000 ms).
+    set_motors(0, kilo_turn_right());
+    delay(2000);
+
+    // Set the LED off.
+    set_color(RGB(0, 0, 0));
+    // Stop for half a second (500 ms).
+    set_motors(0, 0);
+    delay(500);
+}
+
+/// Create a new simple_movement program, the body of the example's main()
+pub fn new() -> Kilolib<Globals>
+{
+    let kilo = kilo_init(Globals::default());
+    kilo_start(kilo, setup, loop_)
+}
@@ -0,0 +1,146 @@
+/*
+ * taxis
+ * Purpose: Demo programs for the motion behaviours. Disperse spreads a swarm out, Aggregate gathers
+ * it into clumps, Phototaxis drives every bot towards the light and Trail lays and follows trails of
+ * virtual pheromone
+ *
+ */
+use crate::hal::Hal;
+use crate::kilobot::motion;
+use crate::kilobot::messages::Message;
+use crate::kilobot::program::Program;
+
+/// Distance in mm a dispersing bot keeps from every neighbour, three bodies apart
+const DISPERSE_MM: u16 = 99;
+/// Distance in mm an aggregating bot gets within of a neighbour, about touching
+const AGGREGATE_MM: u16 = 40;
+/// Pheromone a trail laying bot deposits every tick
+const TRAIL_DEPOSIT: u16 = 4;
+
+/// Broadcast something so neighbours can measure their distance to this bot
+fn beacon(bot: &dyn Hal) -> Option<Message>
+{
+    let uid = bot.get_uid().to_le_bytes();
+    Some(Message::new(0, [uid[0], uid[1], 0, 0, 0, 0, 0, 0, 0]))
+}
+
+/// Spread out until no neighbour is within DISPERSE_MM. Red while moving, green once settled
+pub struct Disperse
+{
+    disperse: motion::Disperse,
+}
+
+impl Disperse
+{
+    /// Create a new Disperse program
+    pub fn new() -> Disperse
+    {
+        Disperse { disperse: motion::Disperse::new(DISPERSE_MM) }
+    }
+}
+
+impl Program for Disperse
+{
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        self.disperse.step(bot);
+        if self.disperse.is_settled() { bot.set_led(0, 255, 0) } else { bot.set_led(255, 0, 0) }
+    }
+
+    fn message_tx(&mut self, bot: &mut dyn Hal) -> Option<Message>
+    {
+        beacon(bot)
+    }
+
+    fn message_rx(&mut self, _bot: &mut dyn Hal, msg: &Message, dist: u16)
+    {
+        self.disperse.heard(msg, dist);
+    }
+}
+
+/// Gather until a neighbour is within AGGREGATE_MM. Red while moving, green once settled
+pub struct Aggregate
+{
+    aggregate: motion::Aggregate,
+}
+
+impl Aggregate
+{
+    /// Create a new Aggregate program
+    pub fn new() -> Aggregate
+    {
+        Aggregate { aggregate: motion::Aggregate::new(AGGREGATE_MM) }
+    }
+}
+
+impl Program for Aggregate
+{
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        self.aggregate.step(bot);
+        if self.aggregate.is_settled() { bot.set_led(0, 255, 0) } else { bot.set_led(255, 0, 0) }
+    }
+
+    fn message_tx(&mut self, bot: &mut dyn Hal) -> Option<Message>
+    {
+        beacon(bot)
+    }
+
+    fn message_rx(&mut self, _bot: &mut dyn Hal, msg: &Message, dist: u16)
+    {
+        self.aggregate.heard(msg, dist);
+    }
+}
+
+/// Drive towards the light. The LED is as bright as the light the bot senses
+pub struct Phototaxis
+{
+    phototaxis: motion::Phototaxis,
+}
+
+impl Phototaxis
+{
+    /// Create a new Phototaxis program
+    pub fn new() -> Phototaxis
+    {
+        Phototaxis { phototaxis: motion::Phototaxis::new() }
+    }
+}
+
+impl Program for Phototaxis
+{
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        self.phototaxis.step(bot);
+        let level = (bot.get_ambient_light() >> 2) as u8;
+        bot.set_led(level, level, 0);
+    }
+}
+
+/// Mark every space the bot drives over with pheromone and follow the marks already there, so paths
+/// that are used often get stronger, like ant trails. The LED is as bright as the pheromone the bot
+/// senses
+pub struct Trail
+{
+    chemotaxis: motion::Chemotaxis,
+}
+
+impl Trail
+{
+    /// Create a new Trail program
+    pub fn new() -> Trail
+    {
+        Trail { chemotaxis: motion::Chemotaxis::new() }
+    }
+}
+
+impl Program for Trail
+{
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        self.chemotaxis.step(bot);
+        bot.deposit_pheromone(TRAIL_DEPOSIT);
+        let level = (bot.get_pheromone() >> 2) as u8;
+        bot.set_led(level, level / 2, 0);
+    }
+}
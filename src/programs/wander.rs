@@ -0,0 +1,65 @@
+/*
+ * wander
+ * Purpose: Simple demo program. Bots wander around, broadcast their uid and light up green when
+ * they can hear another bot, or blue when they are alone
+ *
+ */
+use crate::hal::Hal;
+use crate::kilobot::TICKS_PER_SECOND;
+use crate::kilobot::messages::Message;
+use crate::kilobot::motion::RandomWalk;
+use crate::kilobot::program::Program;
+
+/// Number of ticks a bot stays green after hearing a neighbour
+const HEARD_TIMEOUT: u32 = 2 * TICKS_PER_SECOND;
+
+/// Wander around, alternating between driving forward and turning for random amounts of time
+/// # Fields
+/// * 'walk' - Turns for up to 2 seconds in a random direction, then drives forward for 4 to 12
+///   seconds
+/// * 'last_heard' - Tick a message was last received on
+pub struct Wander
+{
+    walk: RandomWalk,
+    last_heard: Option<u32>,
+}
+
+impl Wander
+{
+    /// Create a new Wander program
+    pub fn new() -> Wander
+    {
+        Wander {
+            walk: RandomWalk::new(4 * TICKS_PER_SECOND..=12 * TICKS_PER_SECOND - 1, 1..=2 * TICKS_PER_SECOND),
+            last_heard: None,
+        }
+    }
+}
+
+impl Program for Wander
+{
+    fn run(&mut self, bot: &mut dyn Hal)
+    {
+        self.walk.step(bot);
+
+        match self.last_heard
+        {
+            Some(t) if bot.get_ticks().saturating_sub(t) < HEARD_TIMEOUT => bot.set_led(0, 255, 0),
+            _ => bot.set_led(0, 0, 255),
+        }
+    }
+
+    fn message_tx(&mut self, bot: &mut dyn Hal) -> Option<Message>
+    {
+        let uid = bot.get_uid().to_le_bytes();
+        Some(Message::new(0, [uid[0], uid[1], 0, 0, 0, 0, 0, 0, 0]))
+    }
+
+    fn message_rx(&mut self, bot: &mut dyn Hal, msg: &Message, _dist: u16)
+    {
+        if msg.is_normal()
+        {
+            self.last_heard = Some(bot.get_ticks());
+        }
+    }
+}
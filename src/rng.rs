@@ -0,0 +1,76 @@
+/*
+ * rng
+ * Purpose: Small deterministic pseudo random number generator shared by the kilobots and the
+ * board setup code. Runs are reproducible for a given seed, which a hardware rng is not.
+ *
+ */
+
+/// Xorshift32 pseudo random number generator
+/// # Fields
+/// * 'state' - Current state of the generator, never zero
+#[derive(Clone)]
+pub struct Rng
+{
+    state: u32,
+}
+
+impl Rng
+{
+    /// Create a new Rng
+    /// # Arguments
+    /// * 'seed' - Seed of the generator. Any value is accepted, a seed of 0 is remapped since
+    ///   xorshift would get stuck on it
+    pub fn new(seed: u32) -> Rng
+    {
+        // Scramble the seed so that consecutive seeds (e.g. uids) don't produce similar streams
+        let mut state = seed.wrapping_mul(2_654_435_761) ^ 0x9E37_79B9;
+        if state == 0
+        {
+            state = 0x9E37_79B9;
+        }
+        Rng { state }
+    }
+
+    /// Get the next random u32
+    pub fn next_u32(&mut self) -> u32
+    {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.state = x;
+        x
+    }
+
+    /// Get the next random u8
+    pub fn next_u8(&mut self) -> u8
+    {
+        (self.next_u32() >> 24) as u8
+    }
+
+    /// Get a random number in the range [0, max)
+    /// # Arguments
+    /// * 'max' - Exclusive upper bound. Returns 0 if max is 0
+    pub fn below(&mut self, max: u32) -> u32
+    {
+        if max == 0
+        {
+            return 0;
+        }
+        self.next_u32() % max
+    }
+
+    /// Get a random f64 in the range [0, 1)
+    pub fn next_f64(&mut self) -> f64
+    {
+        self.next_u32() as f64 / (u32::MAX as f64 + 1.0)
+    }
+
+    /// Get a random f64 from the standard normal distribution, using the Box-Muller transform
+    pub fn next_gaussian(&mut self) -> f64
+    {
+        // 1 - next_f64() is in (0, 1], so the log is always finite
+        let radius = (-2.0 * (1.0 - self.next_f64()).ln()).sqrt();
+        radius * (2.0 * std::f64::consts::PI * self.next_f64()).cos()
+    }
+}
@@ -0,0 +1,527 @@
+/*
+ * scenario
+ * Purpose: Describe a simulation setup in a plain text file so it can be changed without recompiling
+ *
+ * A scenario file is a list of 'key = value' lines. Blank lines and anything after a '#' are
+ * ignored, and any key that is left out keeps its default. For example:
+ *
+ *      # 50 wandering bots on a 40x30 board
+ *      width = 40
+ *      height = 30
+ *      bots = 50
+ *      program = wander
+ *      ticks = 3200
+ *
+ */
+use std::{fmt, fs, io};
+use std::convert::TryFrom;
+use crate::hal::Hal;
+use crate::board::{Board, CoordinatePair};
+use crate::board::board_map::BoardMap;
+use crate::board::environment::RuleEnvironment;
+use crate::board::bot_map::TURN_STEP;
+use crate::board_controller::BoardController;
+use crate::kilobot;
+use crate::kilobot::bootloader::MAX_IMAGE_LEN;
+use crate::kilobot::transceiver::{DEFAULT_TX_PERIOD, DEFAULT_TX_RADIUS};
+use crate::overhead::GpsSettings;
+use crate::kilobot::program::Program;
+use crate::programs;
+use crate::programs::ProgramError;
+use crate::rng::Rng;
+
+/// Every key a scenario understands, in the order they are written out
+pub const SCENARIO_KEYS: [&str; 22] = ["width", "height", "bots", "seed", "tx_period", "tx_radius", "ticks", "program", "placement", "light",
+                                       "upload", "upload_rounds", "contention", "cpu_budget", "memory_limit", "environment",
+                                       "gps", "gps_noise", "gps_heading_noise", "gps_dropout", "evaporation", "diffusion"];
+/// Spaces between neighbouring bots of a hex packed swarm
+const HEX_SPACING: usize = 2;
+/// Most bots a scenario can have, one for every uid
+const MAX_BOTS: usize = u16::MAX as usize + 1;
+
+/// Errors that can occur while reading or building a scenario
+pub enum ScenarioError
+{
+    Io(io::Error),
+    /// A line or value that couldn't be understood, with a description of the problem
+    Parse(String),
+    UnknownProgram(String),
+    /// A program file that couldn't be loaded, with its name and a description of the problem
+    ProgramLoad(String, String),
+    /// More bots than there is room for on the board
+    TooManyBots,
+    /// An upload image larger than the flash, with its length
+    ImageTooLarge(usize),
+    /// A program whose state doesn't fit in the memory limit, with its name and state size
+    StateTooLarge(String, usize),
+    /// A program whose state can't be measured, so the memory limit can't be held to, with its name
+    StateUnmeasured(String),
+}
+
+impl fmt::Display for ScenarioError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            ScenarioError::Io(e) => write!(f, "{}", e),
+            ScenarioError::Parse(msg) => write!(f, "{}", msg),
+            ScenarioError::UnknownProgram(name) => write!(f, "unknown program '{}'", name),
+            ScenarioError::ProgramLoad(name, msg) => write!(f, "couldn't load program '{}': {}", name, msg),
+            ScenarioError::TooManyBots => write!(f, "more bots than spaces on the board"),
+            ScenarioError::StateTooLarge(name, size) => write!(f, "state of program '{}' is {} bytes, more than the memory limit", name, size),
+            ScenarioError::StateUnmeasured(name) => write!(f, "state of program '{}' can't be measured for the memory limit", name),
+            ScenarioError::ImageTooLarge(len) => write!(f, "upload image is {} bytes, more than the {} bytes of flash", len, MAX_IMAGE_LEN),
+        }
+    }
+}
+
+/// Create a program from its name, see programs::from_name
+/// # Arguments
+/// * 'name' - Name of the program
+/// # Returns
+/// * A fresh instance of the program, or ScenarioError if it is unknown or couldn't be loaded
+pub fn load_program(name: &str) -> Result<Box<dyn Program>, ScenarioError>
+{
+    programs::from_name(name).map_err(|e| match e
+    {
+        ProgramError::Unknown => ScenarioError::UnknownProgram(name.to_string()),
+        ProgramError::Load(msg) => ScenarioError::ProgramLoad(name.to_string(), msg),
+    })
+}
+
+/// A simulation setup
+/// # Fields
+/// * 'width' - Width of the board
+/// * 'height' - Height of the board
+/// * 'bots' - Number of bots
+/// * 'seed' - Seed used to place the bots and to seed every bot's random number generator
+/// * 'tx_period' - Ticks between transmissions of every bot
+/// * 'tx_radius' - Broadcast radius of every bot in board units
+/// * 'ticks' - Number of ticks the scenario runs for
+/// * 'program' - Name of the program every bot runs, see programs::from_name. Different groups of
+///   bots can run different programs, given as a space separated list of 'name:count' with bots
+///   assigned in uid order, e.g. "seed.lua:4 follower.lua:36". The count of the last group can be
+///   left out to give it the rest of the bots
+/// * 'placement' - How bots are placed: "random" anywhere on the board, "hex" packed in a
+///   hexagonal lattice around the centre of the board with bot 0 in the middle, or "half_disc" with
+///   a bot in every space of a half disc below the centre, bot 0 in the middle of its flat top edge
+/// * 'light' - Space the light shines down on, written "x y", or "none" for a dark board
+/// * 'upload' - Program the overhead controller uploads to every bot once the scenario starts, see
+///   programs::from_image. Either a program name, or the path of a file, e.g. a .wasm module, whose
+///   bytes are the image. "none" uploads nothing
+/// * 'upload_rounds' - Number of times the image is broadcast, so bots that missed part of it can
+///   catch up
+/// * 'contention' - Whether messages that reach a bot in the same tick collide and are all lost,
+///   "on" or "off"
+/// * 'cpu_budget' - Processor cycles every bot has per tick, see Kilobot::set_cpu_budget. "kilobot"
+///   for the 8 MHz of a real kilobot, or "none" to let programs do any amount of work per tick
+/// * 'memory_limit' - Largest state in bytes a bot's program can keep, see Kilobot::set_memory_limit.
+///   "kilobot" for the 2 KB of SRAM of a real kilobot, or "none" for no limit
+/// * 'environment' - Rules of the virtual environment projected onto the board, see
+///   RuleEnvironment::parse, or "none" for no environment
+/// * 'gps' - Ticks between the GPS fixes the overhead controller sends every bot, see GpsSettings,
+///   or "off" for no GPS
+/// * 'gps_noise' - Standard deviation of the error of GPS positions in mm
+/// * 'gps_heading_noise' - Standard deviation of the error of GPS headings in degrees
+/// * 'gps_dropout' - Chance that a bot misses a GPS fix, from 0 to 1
+/// * 'evaporation' - Fraction of the virtual pheromone that evaporates every tick, from 0 to 1, see
+///   PheromoneMap
+/// * 'diffusion' - Fraction of the virtual pheromone on every space that spreads to its neighbours
+///   every tick, from 0 to 1
+#[derive(Clone)]
+pub struct Scenario
+{
+    pub width: usize,
+    pub height: usize,
+    pub bots: usize,
+    pub seed: u32,
+    pub tx_period: u32,
+    pub tx_radius: f64,
+    pub ticks: u64,
+    pub program: String,
+    pub placement: String,
+    pub light: Option<(usize, usize)>,
+    pub upload: Option<String>,
+    pub upload_rounds: u32,
+    pub contention: bool,
+    pub cpu_budget: Option<u32>,
+    pub memory_limit: Option<usize>,
+    pub environment: Option<String>,
+    pub gps: Option<u32>,
+    pub gps_noise: f64,
+    pub gps_heading_noise: f64,
+    pub gps_dropout: f64,
+    pub evaporation: f64,
+    pub diffusion: f64,
+}
+
+impl Default for Scenario
+{
+    fn default() -> Scenario
+    {
+        Scenario {
+            width: 30,
+            height: 20,
+            bots: 40,
+            seed: 0,
+            tx_period: DEFAULT_TX_PERIOD,
+            tx_radius: DEFAULT_TX_RADIUS,
+            ticks: 3200,
+            program: "wander".to_string(),
+            placement: "random".to_string(),
+            light: None,
+            upload: None,
+            upload_rounds: 1,
+            contention: false,
+            cpu_budget: None,
+            memory_limit: None,
+            environment: None,
+            gps: None,
+            gps_noise: 0.0,
+            gps_heading_noise: 0.0,
+            gps_dropout: 0.0,
+            evaporation: 0.0,
+            diffusion: 0.0,
+        }
+    }
+}
+
+impl Scenario
+{
+    /// Read a scenario from a file
+    /// # Arguments
+    /// * 'path' - Path of the scenario file
+    pub fn from_file(path: &str) -> Result<Scenario, ScenarioError>
+    {
+        let text = fs::read_to_string(path).map_err(ScenarioError::Io)?;
+        Scenario::parse(&text)
+    }
+
+    /// Parse the text of a scenario file
+    /// # Arguments
+    /// * 'text' - Contents of a scenario file
+    pub fn parse(text: &str) -> Result<Scenario, ScenarioError>
+    {
+        let mut scenario = Scenario::default();
+        for (line_num, line) in text.lines().enumerate()
+        {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty()
+            {
+                continue;
+            }
+            match line.split_once('=')
+            {
+                Some((key, value)) => scenario.set(key.trim(), value.trim())
+                    .map_err(|e| ScenarioError::Parse(format!("line {}: {}", line_num + 1, e)))?,
+                None => return Err(ScenarioError::Parse(format!("line {}: expected 'key = value'", line_num + 1))),
+            }
+        }
+        Ok(scenario)
+    }
+
+    /// Set one of the scenario's values from text
+    /// # Arguments
+    /// * 'key' - One of SCENARIO_KEYS
+    /// * 'value' - New value
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ScenarioError>
+    {
+        fn num<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, ScenarioError>
+        {
+            value.parse().map_err(|_| ScenarioError::Parse(format!("invalid value '{}' for {}", value, key)))
+        }
+        fn fraction(key: &str, value: &str) -> Result<f64, ScenarioError>
+        {
+            match num(key, value)?
+            {
+                fraction if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+                _ => Err(ScenarioError::Parse(format!("invalid value '{}' for {}", value, key))),
+            }
+        }
+        match key
+        {
+            "width" => self.width = num(key, value)?,
+            "height" => self.height = num(key, value)?,
+            "bots" => self.bots = match num(key, value)?
+            {
+                bots if bots <= MAX_BOTS => bots,
+                _ => return Err(ScenarioError::Parse(format!("invalid value '{}' for {}, at most {} bots", value, key, MAX_BOTS))),
+            },
+            "seed" => self.seed = num(key, value)?,
+            "tx_period" => self.tx_period = num(key, value)?,
+            "tx_radius" => self.tx_radius = match num(key, value)?
+            {
+                radius if radius >= 0.0 && f64::is_finite(radius) => radius,
+                _ => return Err(ScenarioError::Parse(format!("invalid value '{}' for {}", value, key))),
+            },
+            "ticks" => self.ticks = num(key, value)?,
+            "program" => self.program = value.to_string(),
+            "placement" => match value
+            {
+                "random" | "hex" | "half_disc" => self.placement = value.to_string(),
+                _ => return Err(ScenarioError::Parse(format!("invalid value '{}' for {}", value, key))),
+            },
+            "light" => self.light = match value
+            {
+                "none" => None,
+                _ => match value.split_once(' ')
+                {
+                    Some((x, y)) => Some((num(key, x.trim())?, num(key, y.trim())?)),
+                    None => return Err(ScenarioError::Parse(format!("invalid value '{}' for {}", value, key))),
+                },
+            },
+            "upload" => self.upload = match value
+            {
+                "none" => None,
+                _ => Some(value.to_string()),
+            },
+            "upload_rounds" => self.upload_rounds = num(key, value)?,
+            "contention" => self.contention = match value
+            {
+                "on" => true,
+                "off" => false,
+                _ => return Err(ScenarioError::Parse(format!("invalid value '{}' for {}", value, key))),
+            },
+            "cpu_budget" => self.cpu_budget = match value
+            {
+                "none" => None,
+                "kilobot" => Some(kilobot::CYCLES_PER_TICK),
+                _ => Some(num(key, value)?),
+            },
+            "memory_limit" => self.memory_limit = match value
+            {
+                "none" => None,
+                "kilobot" => Some(kilobot::SRAM_BYTES),
+                _ => Some(num(key, value)?),
+            },
+            "environment" => self.environment = match value
+            {
+                "none" => None,
+                _ => {
+                    RuleEnvironment::parse(value).map_err(ScenarioError::Parse)?;
+                    Some(value.to_string())
+                },
+            },
+            "gps" => self.gps = match value
+            {
+                "off" => None,
+                _ => match num(key, value)?
+                {
+                    0 => return Err(ScenarioError::Parse(format!("invalid value '{}' for {}", value, key))),
+                    period => Some(period),
+                },
+            },
+            "gps_noise" => self.gps_noise = num(key, value)?,
+            "gps_heading_noise" => self.gps_heading_noise = num(key, value)?,
+            "gps_dropout" => self.gps_dropout = fraction(key, value)?,
+            "evaporation" => self.evaporation = fraction(key, value)?,
+            "diffusion" => self.diffusion = fraction(key, value)?,
+            _ => return Err(ScenarioError::Parse(format!("unknown key '{}'", key))),
+        }
+        Ok(())
+    }
+
+    /// Get one of the scenario's values as text
+    /// # Arguments
+    /// * 'key' - One of SCENARIO_KEYS
+    pub fn get(&self, key: &str) -> Option<String>
+    {
+        Some(match key
+        {
+            "width" => self.width.to_string(),
+            "height" => self.height.to_string(),
+            "bots" => self.bots.to_string(),
+            "seed" => self.seed.to_string(),
+            "tx_period" => self.tx_period.to_string(),
+            "tx_radius" => self.tx_radius.to_string(),
+            "ticks" => self.ticks.to_string(),
+            "program" => self.program.clone(),
+            "placement" => self.placement.clone(),
+            "light" => match self.light
+            {
+                Some((x, y)) => format!("{} {}", x, y),
+                None => "none".to_string(),
+            },
+            "upload" => self.upload.clone().unwrap_or_else(|| "none".to_string()),
+            "upload_rounds" => self.upload_rounds.to_string(),
+            "contention" => if self.contention { "on" } else { "off" }.to_string(),
+            "cpu_budget" => self.cpu_budget.map(|b| b.to_string()).unwrap_or_else(|| "none".to_string()),
+            "memory_limit" => self.memory_limit.map(|l| l.to_string()).unwrap_or_else(|| "none".to_string()),
+            "environment" => self.environment.clone().unwrap_or_else(|| "none".to_string()),
+            "gps" => self.gps.map(|p| p.to_string()).unwrap_or_else(|| "off".to_string()),
+            "gps_noise" => self.gps_noise.to_string(),
+            "gps_heading_noise" => self.gps_heading_noise.to_string(),
+            "gps_dropout" => self.gps_dropout.to_string(),
+            "evaporation" => self.evaporation.to_string(),
+            "diffusion" => self.diffusion.to_string(),
+            _ => return None,
+        })
+    }
+
+    /// Work out which program each bot runs from the program groups
+    /// # Returns
+    /// * The name of the program of every bot in uid order, or ScenarioError if the groups are
+    ///   invalid or aren't for exactly the scenario's number of bots
+    pub fn bot_programs(&self) -> Result<Vec<&str>, ScenarioError>
+    {
+        let groups: Vec<&str> = self.program.split_whitespace().collect();
+        let mut names = Vec::with_capacity(self.bots);
+        for (i, group) in groups.iter().enumerate()
+        {
+            let (name, count) = match group.split_once(':')
+            {
+                Some((name, count)) => (name, count.parse()
+                    .map_err(|_| ScenarioError::Parse(format!("invalid bot count in program group '{}'", group)))?),
+                None if i + 1 == groups.len() => (*group, self.bots.saturating_sub(names.len())),
+                None => return Err(ScenarioError::Parse(format!("program group '{}' needs a bot count", group))),
+            };
+            names.extend(std::iter::repeat_n(name, count));
+        }
+        if names.len() != self.bots
+        {
+            return Err(ScenarioError::Parse(format!("program groups are for {} bots, not {}", names.len(), self.bots)));
+        }
+        Ok(names)
+    }
+
+    /// Build a board with the scenario's bots on it, ready to be stepped
+    /// # Returns
+    /// * A BoardController for the new board, or ScenarioError if the program is unknown, its state
+    ///   doesn't fit in the memory limit or the bots don't fit on the board
+    pub fn build(&self) -> Result<BoardController, ScenarioError>
+    {
+        let mut board = Board::new(self.width, self.height);
+        board.set_light(self.light.map(|(x, y)| CoordinatePair::new(x, y)));
+        board.pheromone_map_mut().set_evaporation(self.evaporation);
+        board.pheromone_map_mut().set_diffusion(self.diffusion);
+        if let Some(rules) = &self.environment
+        {
+            board.set_environment(Some(Box::new(RuleEnvironment::parse(rules).map_err(ScenarioError::Parse)?)));
+        }
+        if self.bots > board.len()
+        {
+            return Err(ScenarioError::TooManyBots);
+        }
+        if self.bots > MAX_BOTS
+        {
+            return Err(ScenarioError::Parse(format!("at most {} bots", MAX_BOTS)));
+        }
+        let names = self.bot_programs()?;
+        let mut rng = Rng::new(self.seed);
+        let hex_indices = match self.placement.as_str()
+        {
+            "hex" | "half_disc" => {
+                let indices = match self.placement.as_str()
+                {
+                    "hex" => Scenario::lattice_indices(&board, HEX_SPACING, false),
+                    _ => Scenario::lattice_indices(&board, 1, true),
+                };
+                if self.bots > indices.len()
+                {
+                    return Err(ScenarioError::TooManyBots);
+                }
+                indices
+            },
+            _ => Vec::new(),
+        };
+        for uid in 0..self.bots
+        {
+            let uid = u16::try_from(uid).map_err(|_| ScenarioError::Parse(format!("at most {} bots", MAX_BOTS)))?;
+            let mut bot = kilobot::new_kilobot(uid);
+            bot.seed_rand(self.seed.wrapping_mul(65_537) ^ uid as u32);
+            bot.set_tx_period(self.tx_period);
+            bot.set_cpu_budget(self.cpu_budget);
+            bot.set_memory_limit(self.memory_limit);
+            bot.transceiver_mut().set_tx_radius(self.tx_radius);
+            let name = names[uid as usize];
+            let program = load_program(name)?;
+            bot.set_program(program);
+            if let Some(size) = bot.get_memory_violation()
+            {
+                return Err(ScenarioError::StateTooLarge(name.to_string(), size));
+            }
+            if self.memory_limit.is_some() && bot.get_state_size().is_none()
+            {
+                return Err(ScenarioError::StateUnmeasured(name.to_string()));
+            }
+            // Random spaces are drawn until a free one comes up
+            loop
+            {
+                let index = match hex_indices.get(uid as usize)
+                {
+                    Some(&index) => index,
+                    None => rng.below(board.len() as u32) as usize,
+                };
+                let facing = rng.below(360 / TURN_STEP as u32) as u16 * TURN_STEP;
+                if !board.index_has_bot(index).unwrap_or(true)
+                {
+                    board.add_new_bot_at_index(bot, index, facing);
+                    break;
+                }
+            }
+        }
+        let mut controller = BoardController::new(board);
+        controller.set_contention(self.contention);
+        let gps = self.gps.map(|period| GpsSettings { period, noise: self.gps_noise, heading_noise: self.gps_heading_noise,
+                                                       dropout: self.gps_dropout });
+        controller.overhead_mut().set_gps(gps, self.seed);
+        if let Some(image) = self.upload_image()?
+        {
+            controller.overhead_mut().upload(&image, self.upload_rounds);
+        }
+        Ok(controller)
+    }
+
+    /// Get the image to upload to the bots
+    /// # Returns
+    /// * The bytes of the upload file if it is a file, otherwise the name of the program, or None if
+    ///   there is nothing to upload. ScenarioError if it is neither a file nor a program name, or the
+    ///   image is too large for the flash
+    fn upload_image(&self) -> Result<Option<Vec<u8>>, ScenarioError>
+    {
+        let upload = match &self.upload
+        {
+            Some(upload) => upload,
+            None => return Ok(None),
+        };
+        let image = match fs::read(upload)
+        {
+            Ok(bytes) => bytes,
+            Err(_) if programs::from_name(upload).is_ok() => upload.clone().into_bytes(),
+            Err(e) => return Err(ScenarioError::Io(e)),
+        };
+        if image.len() > MAX_IMAGE_LEN
+        {
+            return Err(ScenarioError::ImageTooLarge(image.len()));
+        }
+        Ok(Some(image))
+    }
+
+    /// Get the spaces of a lattice covering the board, nearest to the centre first
+    /// # Arguments
+    /// * 'board' - Board to cover
+    /// * 'spacing' - Spaces between neighbouring points. Rows are this far apart and every other
+    ///   row is shifted by half a step, so with a spacing of 2 every point has six neighbours at a
+    ///   distance of about 2, like a hexagonal lattice. A spacing of 1 is every space
+    /// * 'half' - Only include spaces in the centre row and below
+    fn lattice_indices(board: &Board, spacing: usize, half: bool) -> Vec<usize>
+    {
+        let (cx, cy) = ((board.get_width() / 2) as f64, (board.get_height() / 2) as f64);
+        let mut spaces = Vec::new();
+        // Start from the centre row so the centre space is always part of the lattice
+        let first_row = if half { board.get_height() / 2 } else { (board.get_height() / 2) % spacing };
+        for y in (first_row..board.get_height()).step_by(spacing)
+        {
+            let shift = if (y / spacing) % 2 == (board.get_height() / 2 / spacing) % 2 { 0 } else { spacing / 2 };
+            let first_col = ((board.get_width() / 2) + shift) % spacing;
+            for x in (first_col..board.get_width()).step_by(spacing)
+            {
+                let dist = (x as f64 - cx).powi(2) + (y as f64 - cy).powi(2);
+                spaces.push((dist, board.get_index_from_coord(&CoordinatePair::new(x, y)).ok().unwrap()));
+            }
+        }
+        spaces.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap().then(a.1.cmp(&b.1)));
+        spaces.into_iter().map(|s| s.1).collect()
+    }
+}
@@ -0,0 +1,266 @@
+/*
+ * scripting
+ * Purpose: Bot programs written as Lua scripts, for anyone who would rather not write Rust
+ *
+ * A script defines any of these functions, which are called like the callbacks of a Program:
+ *
+ *      function setup() end                -- once, before the first loop
+ *      function loop() end                 -- every tick
+ *      function message_tx() end           -- returns the payload to broadcast, a list of up to
+ *                                          -- 9 bytes, or nil to stay quiet
+ *      function on_message(msg, dist, msg_type) end
+ *                                          -- msg is the payload of a received message, dist the
+ *                                          -- distance to its sender in mm and msg_type its type,
+ *                                          -- 0 for messages from other bots, see MessageType
+ *
+ * and can call these to drive the bot:
+ *
+ *      move_forward(), turn_left(), turn_right(), stop(), set_motors(left, right)
+ *      set_led(r, g, b)
+ *      uid(), ticks(), light(), battery(), rand()
+ *      pheromone(), deposit_pheromone(amount)  -- virtual pheromone, see board::pheromone_map
+ *      delay(ms)                           -- same as kilolib's, see kilolib.rs
+ *      print(...)                          -- writes to the bot's debug serial line
+ *
+ * Every bot runs its own copy of the script, so globals are per bot. Anything a script wants to
+ * be visible from outside goes in the global table 'store', see Script::get_stored. A script that
+ * raises an error, or runs for too long in one call, is stopped along with the bot and the error
+ * is kept, see Script::error.
+ *
+ * Scripts are chosen like any other program, by giving the path of a .lua file, and a scenario can
+ * give different groups of bots different scripts, see scenario.rs.
+ *
+ */
+use std::{fmt, fs, io};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use mlua::{FromLuaMulti, Function, HookTriggers, IntoLuaMulti, Lua, Table, Value};
+use crate::kilobot::messages::Message;
+use crate::kilolib::{self, DistanceMeasurement, Kilolib};
+
+/// Lua instructions between checks of how long a call has run for
+const INSTRUCTIONS_PER_CHECK: u32 = 1000;
+/// Checks a call may pass before it is stopped, about a million instructions
+const MAX_CHECKS: u32 = 1000;
+/// Rough processor cycles a kilobot would take to run one Lua instruction, charged to bots with a
+/// CPU budget, see Kilobot::set_cpu_budget
+const CYCLES_PER_INSTRUCTION: u32 = 100;
+
+/// Errors that can occur while loading a script
+pub enum ScriptError
+{
+    Io(io::Error),
+    /// The script couldn't be compiled
+    Lua(mlua::Error),
+}
+
+impl fmt::Display for ScriptError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            ScriptError::Io(e) => write!(f, "{}", e),
+            ScriptError::Lua(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// One bot's copy of a script
+/// # Fields
+/// * 'lua' - The bot's own Lua state
+/// * 'source' - Text of the script, run on the first setup
+/// * 'name' - Name of the script in error messages
+/// * 'checks' - Instruction checks passed by the running call
+/// * 'error' - The error that stopped the script, if it has been stopped
+/// * 'base_memory' - Memory the Lua state takes up before the script has run, see Script::state_size
+pub struct Script
+{
+    lua: Lua,
+    source: String,
+    name: String,
+    checks: Arc<AtomicU32>,
+    error: Option<String>,
+    base_memory: usize,
+}
+
+impl Script
+{
+    /// Get the error that stopped the script, if it has been stopped
+    pub fn error(&self) -> Option<&str>
+    {
+        self.error.as_deref()
+    }
+
+    /// Get a value the script has put in its store
+    /// # Arguments
+    /// * 'key' - Key of the value
+    /// # Returns
+    /// * The value as text, or None if there is nothing stored under the key
+    pub fn get_stored(&self, key: &str) -> Option<String>
+    {
+        let store: Table = self.lua.globals().get("store").ok()?;
+        match store.get(key).ok()?
+        {
+            Value::Nil => None,
+            Value::Boolean(b) => Some(b.to_string()),
+            Value::Integer(i) => Some(i.to_string()),
+            Value::Number(n) => Some(n.to_string()),
+            Value::String(s) => Some(s.to_string_lossy().into_owned()),
+            other => Some(other.type_name().to_string()),
+        }
+    }
+
+    /// Call a function of the script, if it defines it. An error stops the script and the bot
+    /// # Arguments
+    /// * 'name' - Name of the function
+    /// * 'args' - Arguments to call it with
+    /// # Returns
+    /// * What the function returned, or None if the script doesn't define it or has stopped
+    fn call<A, R>(&mut self, name: &str, args: A) -> Option<R> where A: for<'lua> IntoLuaMulti<'lua>, R: for<'lua> FromLuaMulti<'lua>
+    {
+        if self.error.is_some()
+        {
+            return None;
+        }
+        self.checks.store(0, Ordering::Relaxed);
+        let result = match self.lua.globals().get::<_, Option<Function>>(name)
+        {
+            Ok(Some(func)) => func.call(args),
+            Ok(None) => return None,
+            Err(e) => Err(e),
+        };
+        match result
+        {
+            Ok(result) => Some(result),
+            Err(e) => self.stop(e),
+        }
+    }
+
+    fn stop<R>(&mut self, error: mlua::Error) -> Option<R>
+    {
+        self.error = Some(format!("{}: {}", self.name, error));
+        kilolib::with_bot(|bot| bot.stop());
+        None
+    }
+
+    /// Get the memory the script's functions and data take up, see Program::state_size. Garbage is
+    /// collected first, so only what the script still holds on to counts, and the Lua runtime itself
+    /// doesn't count
+    fn state_size(&self) -> Option<usize>
+    {
+        self.lua.gc_collect().ok()?;
+        Some(self.lua.used_memory().saturating_sub(self.base_memory))
+    }
+
+    fn setup(&mut self)
+    {
+        // Running the script defines its functions. It is run here rather than when loading so
+        // the script can drive the bot from its top level too
+        self.checks.store(0, Ordering::Relaxed);
+        if let Err(e) = self.lua.load(self.source.as_str()).set_name(self.name.as_str()).exec()
+        {
+            self.stop::<()>(e);
+        }
+        self.call::<_, ()>("setup", ());
+    }
+
+    fn run(&mut self)
+    {
+        self.call::<_, ()>("loop", ());
+    }
+
+    fn message_tx(&mut self) -> Option<Message>
+    {
+        let payload: Vec<u8> = self.call::<_, Option<Vec<u8>>>("message_tx", ())??;
+        if payload.len() > 9
+        {
+            return self.stop(mlua::Error::RuntimeError("message_tx returned more than 9 bytes".to_string()));
+        }
+        let mut data = [0; 9];
+        data[..payload.len()].copy_from_slice(&payload);
+        Some(Message::new(0, data))
+    }
+
+    fn message_rx(&mut self, msg: &Message, d: &DistanceMeasurement)
+    {
+        self.call::<_, ()>("on_message", (msg.data().to_vec(), kilolib::estimate_distance(d), msg.msg_type()));
+    }
+}
+
+/// Make the functions scripts use to drive the bot available to a Lua state
+fn register_api(lua: &Lua) -> mlua::Result<()>
+{
+    let globals = lua.globals();
+    globals.set("move_forward", lua.create_function(|_, ()| { kilolib::with_bot(|bot| bot.move_forward()); Ok(()) })?)?;
+    globals.set("turn_left", lua.create_function(|_, ()| { kilolib::with_bot(|bot| bot.turn_left()); Ok(()) })?)?;
+    globals.set("turn_right", lua.create_function(|_, ()| { kilolib::with_bot(|bot| bot.turn_right()); Ok(()) })?)?;
+    globals.set("stop", lua.create_function(|_, ()| { kilolib::with_bot(|bot| bot.stop()); Ok(()) })?)?;
+    globals.set("set_motors", lua.create_function(|_, (left, right): (u8, u8)| {
+        kilolib::with_bot(|bot| bot.set_motors(left, right));
+        Ok(())
+    })?)?;
+    globals.set("set_led", lua.create_function(|_, (r, g, b): (u8, u8, u8)| {
+        kilolib::with_bot(|bot| bot.set_led(r, g, b));
+        Ok(())
+    })?)?;
+    globals.set("uid", lua.create_function(|_, ()| Ok(kilolib::kilo_uid()))?)?;
+    globals.set("ticks", lua.create_function(|_, ()| Ok(kilolib::kilo_ticks()))?)?;
+    globals.set("light", lua.create_function(|_, ()| Ok(kilolib::get_ambientlight()))?)?;
+    globals.set("pheromone", lua.create_function(|_, ()| Ok(kilolib::get_pheromone()))?)?;
+    globals.set("deposit_pheromone", lua.create_function(|_, amount: u16| { kilolib::deposit_pheromone(amount); Ok(()) })?)?;
+    globals.set("battery", lua.create_function(|_, ()| Ok(kilolib::with_bot(|bot| bot.get_battery_voltage())))?)?;
+    globals.set("rand", lua.create_function(|_, ()| Ok(kilolib::rand_soft()))?)?;
+    globals.set("delay", lua.create_function(|_, ms: u16| { kilolib::delay(ms); Ok(()) })?)?;
+    // print writes to the bot's debug serial line rather than the simulator's console
+    globals.set("print", lua.create_function(|lua, args: mlua::Variadic<Value>| {
+        let tostring: Function = lua.globals().get("tostring")?;
+        let mut text = Vec::with_capacity(args.len());
+        for arg in args
+        {
+            text.push(tostring.call::<_, String>(arg)?);
+        }
+        kilolib::debug_print(&text.join("\t"));
+        Ok(())
+    })?)?;
+    globals.set("store", lua.create_table()?)?;
+    Ok(())
+}
+
+/// Load a script for one bot
+/// # Arguments
+/// * 'path' - Path of the script
+/// # Returns
+/// * The program, or ScriptError if the script couldn't be read or doesn't compile
+pub fn load(path: &str) -> Result<Kilolib<Script>, ScriptError>
+{
+    let source = fs::read_to_string(path).map_err(ScriptError::Io)?;
+    let lua = Lua::new();
+    register_api(&lua).map_err(ScriptError::Lua)?;
+    // Catch syntax errors now rather than on the first tick
+    lua.load(source.as_str()).set_name(path).into_function().map_err(ScriptError::Lua)?;
+
+    // Stop calls that run for too long, e.g. a loop that never ends, and charge the bot for the
+    // instructions run
+    let checks = Arc::new(AtomicU32::new(0));
+    let hook_checks = checks.clone();
+    lua.set_hook(HookTriggers::new().every_nth_instruction(INSTRUCTIONS_PER_CHECK), move |_, _| {
+        kilolib::with_bot(|bot| bot.spend_cycles(INSTRUCTIONS_PER_CHECK * CYCLES_PER_INSTRUCTION));
+        if hook_checks.fetch_add(1, Ordering::Relaxed) >= MAX_CHECKS
+        {
+            Err(mlua::Error::RuntimeError("script ran for too long".to_string()))
+        } else {
+            Ok(())
+        }
+    });
+
+    // Only what the script adds counts towards its state, not the runtime
+    lua.gc_collect().map_err(ScriptError::Lua)?;
+    let base_memory = lua.used_memory();
+    let script = Script { lua, source, name: path.to_string(), checks, error: None, base_memory };
+    let mut kilo = kilolib::kilo_init(script);
+    kilo.kilo_message_tx = Script::message_tx;
+    kilo.kilo_message_rx = Script::message_rx;
+    kilo.state_size = Script::state_size;
+    Ok(kilolib::kilo_start(kilo, Script::setup, Script::run))
+}
@@ -0,0 +1,375 @@
+/*
+ * tui
+ * Purpose: Live terminal view of a running simulation. Works over ssh, no GUI needed
+ *
+ * Controls:
+ *  space       - pause/resume
+ *  n / .       - step a single tick (while paused)
+ *  + / -       - double/halve the simulation speed
+ *  arrow keys  - move the cursor, the bot under the cursor is inspected in the side panel
+ *  tab         - jump the cursor to the next bot
+ *  left click  - move the cursor to the clicked space
+ *  q / esc     - quit
+ *
+ * Spaces with virtual pheromone on them are shaded from dark to bright amber, relative to the
+ * space with the most.
+ *
+ */
+use std::io::{self, Write, BufWriter};
+use std::panic;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crossterm::{cursor, execute, queue, terminal};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, MouseButton, MouseEventKind};
+use crossterm::style::{Color, Print, ResetColor, SetAttribute, Attribute, SetBackgroundColor, SetForegroundColor};
+use crossterm::terminal::ClearType;
+use crate::hal::Hal;
+use crate::board::CoordinatePair;
+use crate::board::board_map::BoardMap;
+use crate::board_controller::BoardController;
+use crate::kilobot::TICKS_PER_SECOND;
+use crate::kilobot::rgb::RGB;
+use crate::metrics::SwarmMetrics;
+
+/// Time between redraws
+const FRAME_TIME: Duration = Duration::from_millis(33);
+/// Time between updates of the swarm metrics in the status line, which take a pass over the whole swarm
+const METRICS_TIME: Duration = Duration::from_millis(500);
+/// Slowest and fastest allowed simulation speeds, in ticks per second
+const MIN_SPEED: u32 = 1;
+const MAX_SPEED: u32 = 4096;
+/// Width of the side panel in columns
+const PANEL_WIDTH: usize = 44;
+/// Debug lines of the inspected bot shown in the side panel
+const PANEL_DEBUG_LINES: usize = 4;
+/// Background colours of the pheromone heatmap, from the least pheromone to the most
+const HEAT_COLORS: [u8; 6] = [52, 94, 130, 166, 172, 214];
+/// Glyphs used for a bot facing each of the 8 directions, starting at north and going clockwise
+const FACING_GLYPHS: [char; 8] = ['↑', '↗', '→', '↘', '↓', '↙', '←', '↖'];
+
+/// State of the viewer
+/// # Fields
+/// * 'paused' - Whether the simulation is paused
+/// * 'speed' - Simulation speed in ticks per second
+/// * 'cursor' - Coordinates of the space under the cursor
+/// * 'offset' - Coordinates of the top left space that is on screen
+/// * 'pending_ticks' - Fractional ticks owed to the simulation since the last frame
+/// * 'metrics' - Swarm metrics shown in the status line and when they were computed, see
+///   METRICS_TIME
+struct Viewer
+{
+    paused: bool,
+    speed: u32,
+    cursor: (usize, usize),
+    offset: (usize, usize),
+    pending_ticks: f64,
+    metrics: Option<(Instant, SwarmMetrics)>,
+}
+
+/// Restores the terminal when dropped, so it is restored however the viewer exits
+struct TerminalGuard;
+
+impl Drop for TerminalGuard
+{
+    fn drop(&mut self)
+    {
+        restore_terminal();
+    }
+}
+
+/// Leave the alternate screen and raw mode, if the viewer hasn't already
+fn restore_terminal()
+{
+    if terminal::is_raw_mode_enabled().unwrap_or(false)
+    {
+        let _ = execute!(io::stdout(), cursor::Show, event::DisableMouseCapture, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Run the viewer until the user quits. The terminal is restored even if drawing fails or the
+/// simulation panics
+/// # Arguments
+/// * 'controller' - Controller of the simulation to show
+pub fn run(controller: &mut BoardController) -> io::Result<()>
+{
+    terminal::enable_raw_mode()?;
+    let _guard = TerminalGuard;
+    // A panic message is printed before unwinding reaches the guard, and would be lost with the
+    // alternate screen, so restore the terminal first
+    let previous_hook = Arc::new(panic::take_hook());
+    let hook = previous_hook.clone();
+    panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        hook(info);
+    }));
+    let result = execute!(io::stdout(), terminal::EnterAlternateScreen, event::EnableMouseCapture, cursor::Hide)
+        .and_then(|_| event_loop(controller));
+    // Dropping our hook drops its copy of the previous one, which can then go back in place
+    drop(panic::take_hook());
+    if let Ok(previous_hook) = Arc::try_unwrap(previous_hook)
+    {
+        panic::set_hook(previous_hook);
+    }
+    result
+}
+
+/// Draw, handle input and step the simulation until the user quits
+fn event_loop(controller: &mut BoardController) -> io::Result<()>
+{
+    let mut viewer = Viewer { paused: true, speed: TICKS_PER_SECOND, cursor: (0, 0), offset: (0, 0), pending_ticks: 0.0, metrics: None };
+    let mut out = BufWriter::new(io::stdout());
+    let mut last_frame = Instant::now();
+    loop
+    {
+        draw(&mut out, controller, &mut viewer)?;
+        if event::poll(FRAME_TIME)?
+        {
+            match event::read()?
+            {
+                Event::Key(key) if key.kind == KeyEventKind::Press => {
+                    match key.code
+                    {
+                        KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                        KeyCode::Char(' ') => viewer.paused = !viewer.paused,
+                        KeyCode::Char('n') | KeyCode::Char('.') if viewer.paused => controller.step(),
+                        KeyCode::Char('+') | KeyCode::Char('=') => viewer.speed = (viewer.speed * 2).min(MAX_SPEED),
+                        KeyCode::Char('-') => viewer.speed = (viewer.speed / 2).max(MIN_SPEED),
+                        KeyCode::Tab => viewer.cursor = next_bot(controller, viewer.cursor),
+                        KeyCode::Up => viewer.cursor.1 = viewer.cursor.1.saturating_sub(1),
+                        KeyCode::Left => viewer.cursor.0 = viewer.cursor.0.saturating_sub(1),
+                        KeyCode::Down => viewer.cursor.1 = (viewer.cursor.1 + 1).min(controller.board.get_height().saturating_sub(1)),
+                        KeyCode::Right => viewer.cursor.0 = (viewer.cursor.0 + 1).min(controller.board.get_width().saturating_sub(1)),
+                        _ => {},
+                    }
+                },
+                // Arena starts on the second row and every space is two columns wide
+                Event::Mouse(mouse) if mouse.kind == MouseEventKind::Down(MouseButton::Left) && mouse.row >= 1 => {
+                    let x = mouse.column as usize / 2 + viewer.offset.0;
+                    let y = (mouse.row - 1) as usize + viewer.offset.1;
+                    if x < controller.board.get_width() && y < controller.board.get_height()
+                    {
+                        viewer.cursor = (x, y);
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        let now = Instant::now();
+        if !viewer.paused
+        {
+            viewer.pending_ticks += now.duration_since(last_frame).as_secs_f64() * viewer.speed as f64;
+            // Never fall further behind than one frame worth of ticks
+            viewer.pending_ticks = viewer.pending_ticks.min(viewer.speed as f64 * FRAME_TIME.as_secs_f64() + 1.0);
+            while viewer.pending_ticks >= 1.0
+            {
+                controller.step();
+                viewer.pending_ticks -= 1.0;
+            }
+        }
+        last_frame = now;
+    }
+}
+
+/// Find the next bot after the given coordinates, wrapping around the board
+/// # Returns
+/// * Coordinates of the next bot, or the given coordinates if there are no bots
+fn next_bot(controller: &BoardController, from: (usize, usize)) -> (usize, usize)
+{
+    let board = &controller.board;
+    let start = board.get_index_from_coord(&CoordinatePair::new(from.0, from.1)).unwrap_or(0);
+    let len = board.len();
+    for i in 1..=len
+    {
+        let index = (start + i) % len;
+        if let Ok(true) = board.index_has_bot(index)
+        {
+            return board.get_coord_from_index(&index).ok().unwrap().as_usize_tuple();
+        }
+    }
+    from
+}
+
+/// Approximate an LED color with the 6x6x6 color cube of a 256 color terminal
+/// An LED that is off is drawn dark grey so the bot is still visible
+fn led_color(led: &RGB) -> Color
+{
+    if led.r == 0 && led.g == 0 && led.b == 0
+    {
+        return Color::AnsiValue(240);
+    }
+    let scale = |v: u8| (v as u16 * 5 + 127) / 255;
+    Color::AnsiValue((16 + 36 * scale(led.r) + 6 * scale(led.g) + scale(led.b)) as u8)
+}
+
+/// Get the heatmap colour of a space
+/// # Arguments
+/// * 'level' - Level of pheromone on the space
+/// * 'max' - Highest level of pheromone on the board
+/// # Returns
+/// * The background colour of the space, or None if there is too little pheromone to show
+fn heat_color(level: f64, max: f64) -> Option<Color>
+{
+    if level < 0.5
+    {
+        return None;
+    }
+    let step = ((level / max) * HEAT_COLORS.len() as f64).ceil() as usize;
+    Some(Color::AnsiValue(HEAT_COLORS[step.clamp(1, HEAT_COLORS.len()) - 1]))
+}
+
+/// Draw one frame: a status line, the arena and the side panel
+fn draw<W: Write>(out: &mut W, controller: &BoardController, viewer: &mut Viewer) -> io::Result<()>
+{
+    let board = &controller.board;
+    let (cols, rows) = terminal::size()?;
+    let view_w = ((cols as usize).saturating_sub(PANEL_WIDTH) / 2).max(1).min(board.get_width());
+    let view_h = (rows as usize).saturating_sub(2).max(1).min(board.get_height());
+
+    // Scroll the arena so the cursor stays on screen
+    if viewer.cursor.0 < viewer.offset.0 { viewer.offset.0 = viewer.cursor.0 }
+    if viewer.cursor.0 >= viewer.offset.0 + view_w { viewer.offset.0 = viewer.cursor.0 + 1 - view_w }
+    if viewer.cursor.1 < viewer.offset.1 { viewer.offset.1 = viewer.cursor.1 }
+    if viewer.cursor.1 >= viewer.offset.1 + view_h { viewer.offset.1 = viewer.cursor.1 + 1 - view_h }
+
+    if viewer.metrics.as_ref().is_none_or(|(time, _)| time.elapsed() >= METRICS_TIME)
+    {
+        viewer.metrics = Some((Instant::now(), SwarmMetrics::compute(board)));
+    }
+    let metrics = &viewer.metrics.as_ref().unwrap().1;
+    let mut status = format!("Kilobot | tick {} | {} ticks/s | {} | clusters {} (largest {}) | coverage {:.0}%",
+                             controller.get_tick(), viewer.speed, if viewer.paused { "PAUSED" } else { "running" },
+                             metrics.components, metrics.largest_cluster, metrics.coverage * 100.0);
+    if let Some(spread) = metrics.phase_spread
+    {
+        status.push_str(&format!(" | phase spread {:.2}", spread));
+    }
+    let max_level = board.pheromone_map().get_max_level();
+    if !board.pheromone_map().is_clean()
+    {
+        status.push_str(&format!(" | pheromone up to {:.0}", max_level));
+    }
+    queue!(out, cursor::MoveTo(0, 0), SetAttribute(Attribute::Bold),
+        Print(status),
+        SetAttribute(Attribute::Reset), terminal::Clear(ClearType::UntilNewLine))?;
+
+    let panel = panel_lines(controller, viewer.cursor);
+    for row in 0..view_h
+    {
+        queue!(out, cursor::MoveTo(0, row as u16 + 1))?;
+        let y = row + viewer.offset.1;
+        for x in viewer.offset.0..viewer.offset.0 + view_w
+        {
+            if (x, y) == viewer.cursor
+            {
+                queue!(out, SetAttribute(Attribute::Reverse))?;
+            }
+            let index = board.get_index_from_coord(&CoordinatePair::new(x, y)).ok().unwrap();
+            if let Some(color) = heat_color(board.pheromone_map().get_level_at_index(index), max_level)
+            {
+                queue!(out, SetBackgroundColor(color))?;
+            }
+            match board.get_bot_location_at_index(index)
+            {
+                Ok(loc) => {
+                    let glyph = FACING_GLYPHS[((loc.get_facing() as usize + 22) % 360) / 45];
+                    queue!(out, SetForegroundColor(led_color(loc.bot().get_led())), Print(glyph))?;
+                },
+                Err(_) if board.get_light().is_some_and(|l| (l.x, l.y) == (x, y)) =>
+                    queue!(out, SetForegroundColor(Color::Yellow), Print('*'))?,
+                Err(_) => queue!(out, SetForegroundColor(Color::AnsiValue(236)), Print('·'))?,
+            }
+            queue!(out, Print(' '), ResetColor, SetAttribute(Attribute::NoReverse))?;
+        }
+        queue!(out, Print("  "), Print(panel.get(row).map(|s| s.as_str()).unwrap_or("")),
+            terminal::Clear(ClearType::UntilNewLine))?;
+    }
+    queue!(out, cursor::MoveTo(0, view_h as u16 + 1), terminal::Clear(ClearType::FromCursorDown),
+        SetForegroundColor(Color::AnsiValue(244)),
+        Print("space pause  n step  +/- speed  arrows/tab/click select  q quit"), ResetColor)?;
+    out.flush()
+}
+
+/// Build the lines of the side panel describing the space under the cursor
+fn panel_lines(controller: &BoardController, coord: (usize, usize)) -> Vec<String>
+{
+    let board = &controller.board;
+    let mut lines = Vec::new();
+    let index = board.get_index_from_coord(&CoordinatePair::new(coord.0, coord.1)).ok().unwrap();
+    let loc = match board.get_bot_location_at_index(index)
+    {
+        Ok(loc) => loc,
+        Err(_) => {
+            lines.push(format!("({},{}) empty", coord.0, coord.1));
+            if !board.pheromone_map().is_clean()
+            {
+                lines.push(format!("Pheromone: {}", board.pheromone_map().get_reading_at_index(index)));
+            }
+            return lines;
+        }
+    };
+    let bot = loc.bot();
+    let led = bot.get_led();
+    let (left, right) = bot.get_motor_values();
+    let transceiver = bot.transceiver();
+    lines.push(format!("Bot {} at ({},{})", bot.get_uid(), coord.0, coord.1));
+    lines.push(format!("Facing:  {} deg", loc.get_facing()));
+    lines.push(format!("Motors:  L {:3}  R {:3}", left, right));
+    lines.push(format!("LED:     ({}, {}, {})", led.r, led.g, led.b));
+    lines.push(format!("Battery: {} mV", bot.get_battery_voltage()));
+    lines.push(format!("Ticks:   {}", bot.get_ticks()));
+    if let Some(budget) = bot.get_cpu_budget()
+    {
+        lines.push(format!("CPU:     {} cycles/tick, {} overruns", budget, bot.get_overruns()));
+    }
+    // Only sizes the memory check has already measured, measuring a script collects its garbage
+    match (bot.get_memory_violation(), bot.get_checked_state_size(), bot.get_memory_limit())
+    {
+        (Some(size), _, _) => lines.push(format!("Memory:  crashed, state grew to {} bytes", size)),
+        (None, Some(size), Some(limit)) => lines.push(format!("Memory:  {} of {} bytes", size, limit)),
+        _ => {},
+    }
+    if let Some(bootloader) = bot.get_bootloader()
+    {
+        match bootloader.progress()
+        {
+            Some((received, len)) => lines.push(format!("Boot:    {}/{} bytes", received, len)),
+            None => lines.push("Boot:    waiting for image".to_string()),
+        }
+    }
+    if board.get_light().is_some()
+    {
+        lines.push(format!("Light:   {}", bot.get_ambient_light()));
+    }
+    if !board.pheromone_map().is_clean()
+    {
+        lines.push(format!("Pheromone: {}", bot.get_pheromone()));
+    }
+    if let Some((x, y)) = bot.get_position_estimate()
+    {
+        lines.push(format!("Thinks it is at ({:.0}, {:.0}) mm", x, y));
+    }
+    lines.push(format!("TX:      every {} ticks, radius {}", transceiver.get_tx_period(), transceiver.get_tx_radius()));
+    lines.push(format!("Sent {}  Received {}", transceiver.get_tx_count(), transceiver.get_rx_count()));
+    let uid = bot.get_uid();
+    let debug: Vec<_> = controller.debug_log().iter().rev().filter(|l| l.uid == uid).take(PANEL_DEBUG_LINES).collect();
+    if !debug.is_empty()
+    {
+        lines.push(String::new());
+        lines.push("Debug:".to_string());
+        for line in debug.iter().rev()
+        {
+            lines.push(format!(" t{} {}", line.tick, line.text));
+        }
+    }
+    lines.push(String::new());
+    lines.push("Last messages:".to_string());
+    for rx in transceiver.last_messages().iter().rev()
+    {
+        let data: Vec<String> = rx.msg.data().iter().map(|b| format!("{:02x}", b)).collect();
+        lines.push(format!(" t{} type {} {}mm", rx.tick, rx.msg.msg_type(), rx.dist));
+        lines.push(format!("   {}", data.join(" ")));
+    }
+    lines
+}
@@ -0,0 +1,353 @@
+/*
+ * wasm
+ * Purpose: Run bot programs compiled to WebAssembly, sandboxed so untrusted programs, e.g.
+ * competition entries, can't touch anything but their own bot
+ *
+ * A module exports the callbacks of a kilolib program:
+ *
+ *      loop()                          -- every tick, required
+ *      setup()                         -- once, before the first loop
+ *      message_tx() -> i32             -- address of the message_t to send, or 0 to stay quiet
+ *      message_tx_success()            -- after a message was sent
+ *      message_rx_buffer() -> i32      -- address of a message_t for received messages
+ *      message_rx(msg: i32, dist: i32) -- a message was written to msg, dist is the distance in mm
+ *      memory                          -- the module's memory, needed for messages
+ *      __heap_base                     -- i32 global, where the heap starts, optional, see below
+ *
+ * where a message_t is 9 bytes of payload followed by a byte of message type. The module can import
+ * these functions from "env", which behave like the kilolib functions of the same name, delay()
+ * included, see kilolib.rs:
+ *
+ *      set_motors(left: i32, right: i32), spinup_motors(), set_color(color: i32), delay(ms: i32),
+ *      kilo_uid() -> i32, kilo_ticks() -> i32, get_ambientlight() -> i32, get_voltage() -> i32,
+ *      rand_soft() -> i32, rand_hard() -> i32, rand_seed(seed: i32)
+ *      debug_print(text: i32, len: i32)    -- writes len bytes of UTF-8 at text to the debug serial line
+ *      get_pheromone() -> i32, deposit_pheromone(amount: i32)  -- virtual pheromone, not in kilolib
+ *
+ * Any language that compiles to WebAssembly works, and the text format (.wat) can be loaded
+ * directly. Every bot has its own instance with its own memory. Each call into a module gets
+ * FUEL_PER_CALL fuel, roughly one unit per instruction, and memory can't grow past MAX_MEMORY_BYTES.
+ * A module that traps or runs out of fuel is stopped along with its bot, and the error is kept, see
+ * WasmProgram::error.
+ *
+ * The size of a module's state, for the memory limit, is everything in its memory below __heap_base,
+ * i.e. its static data and stack, which LLVM based compilers export with -Wl,--export=__heap_base.
+ * Without it all of the module's memory counts, and memory comes in 64 KB pages.
+ *
+ */
+use std::{fmt, fs, io};
+use wasmi::{Caller, Config, Engine, Global, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc,
+            Val, WasmParams, WasmResults};
+use crate::kilobot::messages::Message;
+use crate::kilolib::{self, DistanceMeasurement, Kilolib};
+
+/// Fuel given to every call into a module
+pub const FUEL_PER_CALL: u64 = 1_000_000;
+/// Rough processor cycles a kilobot would take per unit of fuel, charged to bots with a CPU budget,
+/// see Kilobot::set_cpu_budget. Most WebAssembly instructions work on 32 bits, which takes an 8 bit
+/// AVR several instructions
+const CYCLES_PER_FUEL: u64 = 4;
+/// Largest a module's memory can grow to, 16 pages
+pub const MAX_MEMORY_BYTES: usize = 16 * 65_536;
+/// Size of a message_t, 9 bytes of payload and the message type
+const MESSAGE_LEN: usize = 10;
+
+/// Errors that can occur while loading a module
+pub enum WasmError
+{
+    Io(io::Error),
+    /// The file isn't valid WebAssembly or WebAssembly text
+    Parse(wat::Error),
+    /// The module couldn't be compiled or instantiated, e.g. it imports a function that doesn't
+    /// exist or doesn't export loop
+    Wasm(wasmi::Error),
+}
+
+impl fmt::Display for WasmError
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result
+    {
+        match self
+        {
+            WasmError::Io(e) => write!(f, "{}", e),
+            WasmError::Parse(e) => write!(f, "{}", e),
+            WasmError::Wasm(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// One bot's instance of a module
+/// # Fields
+/// * 'store' - The instance's state, limited to MAX_MEMORY_BYTES of memory
+/// * 'memory' - The memory exported by the module, if any
+/// * 'heap_base' - The __heap_base global exported by the module, if any
+/// * 'setup' - The module's setup, if it exports one
+/// * 'loop_' - The module's loop
+/// * 'message_tx' - The module's message_tx, if it exports one
+/// * 'message_tx_success' - The module's message_tx_success, if it exports one
+/// * 'message_rx_buffer' - The module's message_rx_buffer, if it exports one
+/// * 'message_rx' - The module's message_rx, if it exports one
+/// * 'name' - Name of the module in error messages
+/// * 'error' - The error that stopped the module, if it has been stopped
+pub struct WasmProgram
+{
+    store: Store<StoreLimits>,
+    memory: Option<Memory>,
+    heap_base: Option<Global>,
+    setup: Option<TypedFunc<(), ()>>,
+    loop_: TypedFunc<(), ()>,
+    message_tx: Option<TypedFunc<(), i32>>,
+    message_tx_success: Option<TypedFunc<(), ()>>,
+    message_rx_buffer: Option<TypedFunc<(), i32>>,
+    message_rx: Option<TypedFunc<(i32, i32), ()>>,
+    name: String,
+    error: Option<String>,
+}
+
+impl WasmProgram
+{
+    /// Get the error that stopped the module, if it has been stopped
+    pub fn error(&self) -> Option<&str>
+    {
+        self.error.as_deref()
+    }
+
+    /// Get the size of the module's state, see Program::state_size and the top of this file
+    fn state_size(&self) -> Option<usize>
+    {
+        let size = self.memory.map_or(0, |memory| memory.data(&self.store).len());
+        match self.heap_base.map(|global| global.get(&self.store))
+        {
+            Some(Val::I32(heap_base)) => Some((heap_base as u32 as usize).min(size)),
+            _ => Some(size),
+        }
+    }
+
+    /// Call a function of the module with a fresh FUEL_PER_CALL. An error stops the module and the bot
+    /// # Arguments
+    /// * 'func' - The function, or None if the module doesn't export it
+    /// * 'params' - Arguments to call it with
+    /// # Returns
+    /// * What the function returned, or None if there is no function or the module has stopped
+    fn call<P: WasmParams, R: WasmResults>(&mut self, func: Option<TypedFunc<P, R>>, params: P) -> Option<R>
+    {
+        if self.error.is_some()
+        {
+            return None;
+        }
+        let func = func?;
+        // Fuel metering is always enabled, see load
+        self.store.set_fuel(FUEL_PER_CALL).expect("fuel metering is enabled");
+        let result = func.call(&mut self.store, params);
+        let used = FUEL_PER_CALL - self.store.get_fuel().unwrap_or(0);
+        kilolib::with_bot(|bot| bot.spend_cycles((used * CYCLES_PER_FUEL).min(u32::MAX as u64) as u32));
+        match result
+        {
+            Ok(result) => Some(result),
+            Err(e) => self.stop(e.to_string()),
+        }
+    }
+
+    fn stop<R>(&mut self, error: String) -> Option<R>
+    {
+        self.error = Some(format!("{}: {}", self.name, error));
+        kilolib::with_bot(|bot| bot.stop());
+        None
+    }
+
+    fn setup(&mut self)
+    {
+        self.call(self.setup, ());
+    }
+
+    fn run(&mut self)
+    {
+        self.call(Some(self.loop_), ());
+    }
+
+    fn message_tx(&mut self) -> Option<Message>
+    {
+        let address = self.call(self.message_tx, ())?;
+        if address == 0
+        {
+            return None;
+        }
+        let mut bytes = [0; MESSAGE_LEN];
+        match self.memory.map(|memory| memory.read(&self.store, address as u32 as usize, &mut bytes))
+        {
+            Some(Ok(())) => {
+                let mut data = [0; 9];
+                data.copy_from_slice(&bytes[..9]);
+                Some(Message::new(bytes[9], data))
+            },
+            Some(Err(e)) => self.stop(format!("message_tx returned a bad address: {}", e)),
+            None => self.stop("message_tx needs the module to export its memory".to_string()),
+        }
+    }
+
+    fn message_tx_success(&mut self)
+    {
+        self.call(self.message_tx_success, ());
+    }
+
+    fn message_rx(&mut self, msg: &Message, d: &DistanceMeasurement)
+    {
+        if self.message_rx.is_none()
+        {
+            return;
+        }
+        let address = match self.call(self.message_rx_buffer, ())
+        {
+            Some(address) => address,
+            None => {
+                if self.error.is_none()
+                {
+                    self.stop::<()>("message_rx needs message_rx_buffer".to_string());
+                }
+                return;
+            },
+        };
+        let mut bytes = [0; MESSAGE_LEN];
+        bytes[..9].copy_from_slice(msg.data());
+        bytes[9] = msg.msg_type();
+        match self.memory.map(|memory| memory.write(&mut self.store, address as u32 as usize, &bytes))
+        {
+            Some(Ok(())) => {
+                self.call(self.message_rx, (address, kilolib::estimate_distance(d) as i32));
+            },
+            Some(Err(e)) => {
+                self.stop::<()>(format!("message_rx_buffer returned a bad address: {}", e));
+            },
+            None => {
+                self.stop::<()>("message_rx needs the module to export its memory".to_string());
+            },
+        }
+    }
+}
+
+/// Check that an import was called from one of the module's callbacks. The start function runs
+/// while the module is loaded, before there is a bot to act on
+/// # Returns
+/// * An error that traps the module if there is no bot
+fn in_callback() -> Result<(), wasmi::Error>
+{
+    if kilolib::in_callback()
+    {
+        Ok(())
+    } else {
+        Err(wasmi::Error::new("imports can't be called from the start function"))
+    }
+}
+
+/// Make the functions modules use to drive the bot available to them. Every import traps instead of
+/// panicking when it is called outside a callback, see in_callback
+fn link(linker: &mut Linker<StoreLimits>) -> Result<(), wasmi::Error>
+{
+    type Trap = wasmi::Error;
+    linker.func_wrap("env", "set_motors", |left: i32, right: i32| -> Result<(), Trap> {
+        in_callback().map(|_| kilolib::set_motors(left as u8, right as u8))
+    })?;
+    linker.func_wrap("env", "spinup_motors", || -> Result<(), Trap> { in_callback().map(|_| kilolib::spinup_motors()) })?;
+    linker.func_wrap("env", "set_color", |color: i32| -> Result<(), Trap> { in_callback().map(|_| kilolib::set_color(color as u8)) })?;
+    linker.func_wrap("env", "delay", |ms: i32| -> Result<(), Trap> {
+        in_callback().map(|_| kilolib::delay(ms.clamp(0, u16::MAX as i32) as u16))
+    })?;
+    linker.func_wrap("env", "kilo_uid", || -> Result<i32, Trap> { in_callback().map(|_| kilolib::kilo_uid() as i32) })?;
+    linker.func_wrap("env", "kilo_ticks", || -> Result<i32, Trap> { in_callback().map(|_| kilolib::kilo_ticks() as i32) })?;
+    linker.func_wrap("env", "get_ambientlight", || -> Result<i32, Trap> { in_callback().map(|_| kilolib::get_ambientlight() as i32) })?;
+    linker.func_wrap("env", "get_pheromone", || -> Result<i32, Trap> { in_callback().map(|_| kilolib::get_pheromone() as i32) })?;
+    linker.func_wrap("env", "deposit_pheromone", |amount: i32| -> Result<(), Trap> {
+        in_callback().map(|_| kilolib::deposit_pheromone(amount.clamp(0, u16::MAX as i32) as u16))
+    })?;
+    linker.func_wrap("env", "get_voltage", || -> Result<i32, Trap> {
+        in_callback().map(|_| kilolib::with_bot(|bot| bot.get_battery_voltage()) as i32)
+    })?;
+    linker.func_wrap("env", "rand_soft", || -> Result<i32, Trap> { in_callback().map(|_| kilolib::rand_soft() as i32) })?;
+    linker.func_wrap("env", "rand_hard", || -> Result<i32, Trap> { in_callback().map(|_| kilolib::rand_hard() as i32) })?;
+    linker.func_wrap("env", "rand_seed", |seed: i32| -> Result<(), Trap> { in_callback().map(|_| kilolib::rand_seed(seed as u8)) })?;
+    linker.func_wrap("env", "debug_print", |caller: Caller<'_, StoreLimits>, text: i32, len: i32| -> Result<(), Trap> {
+        in_callback()?;
+        let memory = caller.get_export("memory").and_then(|e| e.into_memory())
+            .ok_or_else(|| Trap::new("debug_print needs the module to export its memory"))?;
+        // Check the text is inside the module's memory before touching it, a bad length mustn't
+        // make the host allocate
+        let start = text as u32 as usize;
+        let end = start.saturating_add(len.max(0) as usize);
+        let bytes = memory.data(&caller).get(start..end)
+            .ok_or_else(|| Trap::new(format!("debug_print was given a bad address: {} bytes at {}", len, start)))?;
+        kilolib::debug_print(&String::from_utf8_lossy(bytes));
+        Ok(())
+    })?;
+    Ok(())
+}
+
+/// Get a function the module exports
+/// # Arguments
+/// * 'store' - The module's store
+/// * 'instance' - The module's instance
+/// * 'name' - Name of the function
+/// # Returns
+/// * The function, None if the module doesn't export it, or an error if it has the wrong signature
+fn export<P: WasmParams, R: WasmResults>(store: &Store<StoreLimits>, instance: &Instance, name: &str)
+                                         -> Result<Option<TypedFunc<P, R>>, wasmi::Error>
+{
+    match instance.get_export(store, name)
+    {
+        Some(_) => instance.get_typed_func(store, name).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Load a module for one bot
+/// # Arguments
+/// * 'path' - Path of a WebAssembly module, in the binary or text format
+/// # Returns
+/// * The program, or WasmError if the module couldn't be loaded
+pub fn load(path: &str) -> Result<Kilolib<WasmProgram>, WasmError>
+{
+    let bytes = fs::read(path).map_err(WasmError::Io)?;
+    load_bytes(&bytes, path)
+}
+
+/// Load a module for one bot from memory, e.g. one uploaded over the air
+/// # Arguments
+/// * 'bytes' - The module, in the binary or text format
+/// * 'name' - Name of the module in error messages
+/// # Returns
+/// * The program, or WasmError if the module couldn't be loaded
+pub fn load_bytes(bytes: &[u8], name: &str) -> Result<Kilolib<WasmProgram>, WasmError>
+{
+    let wasm = wat::parse_bytes(bytes).map_err(WasmError::Parse)?;
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module = Module::new(&engine, &wasm).map_err(WasmError::Wasm)?;
+
+    let mut store = Store::new(&engine, StoreLimitsBuilder::new().memory_size(MAX_MEMORY_BYTES).build());
+    store.limiter(|limits| limits);
+    let mut linker = Linker::new(&engine);
+    link(&mut linker).map_err(WasmError::Wasm)?;
+    // The start function, if any, runs here, outside of any callback, so the imports trap if it uses them
+    store.set_fuel(FUEL_PER_CALL).map_err(|e| WasmError::Wasm(e.into()))?;
+    let instance = linker.instantiate(&mut store, &module).and_then(|pre| pre.start(&mut store)).map_err(WasmError::Wasm)?;
+
+    let program = WasmProgram {
+        memory: instance.get_memory(&store, "memory"),
+        heap_base: instance.get_global(&store, "__heap_base"),
+        setup: export(&store, &instance, "setup").map_err(WasmError::Wasm)?,
+        loop_: instance.get_typed_func(&store, "loop").map_err(WasmError::Wasm)?,
+        message_tx: export(&store, &instance, "message_tx").map_err(WasmError::Wasm)?,
+        message_tx_success: export(&store, &instance, "message_tx_success").map_err(WasmError::Wasm)?,
+        message_rx_buffer: export(&store, &instance, "message_rx_buffer").map_err(WasmError::Wasm)?,
+        message_rx: export(&store, &instance, "message_rx").map_err(WasmError::Wasm)?,
+        store,
+        name: name.to_string(),
+        error: None,
+    };
+    let mut kilo = kilolib::kilo_init(program);
+    kilo.kilo_message_tx = WasmProgram::message_tx;
+    kilo.kilo_message_tx_success = WasmProgram::message_tx_success;
+    kilo.kilo_message_rx = WasmProgram::message_rx;
+    kilo.state_size = WasmProgram::state_size;
+    Ok(kilolib::kilo_start(kilo, WasmProgram::setup, WasmProgram::run))
+}
@@ -0,0 +1,43 @@
+;; simple_movement.wat
+;; Purpose: The kilolib simple_movement example as a WebAssembly module, in the text format so it
+;; can be read and run without a compiler. See src/wasm.rs
+(module
+  (import "env" "set_motors" (func $set_motors (param i32 i32)))
+  (import "env" "spinup_motors" (func $spinup_motors))
+  (import "env" "set_color" (func $set_color (param i32)))
+  (import "env" "delay" (func $delay (param i32)))
+
+  (func (export "setup"))
+
+  (func (export "loop")
+    ;; Set the LED green, RGB(0, 1, 0).
+    (call $set_color (i32.const 4))
+    ;; Spinup the motors to overcome friction.
+    (call $spinup_motors)
+    ;; Move straight for 2 seconds (2000 ms).
+    (call $set_motors (i32.const 255) (i32.const 255))
+    (call $delay (i32.const 2000))
+
+    ;; Set the LED red, RGB(1, 0, 0).
+    (call $set_color (i32.const 1))
+    ;; Spinup the motors to overcome friction.
+    (call $spinup_motors)
+    ;; Turn left for 2 seconds (2000 ms).
+    (call $set_motors (i32.const 255) (i32.const 0))
+    (call $delay (i32.const 2000))
+
+    ;; Set the LED blue, RGB(0, 0, 1).
+    (call $set_color (i32.const 16))
+    ;; Spinup the motors to overcome friction.
+    (call $spinup_motors)
+    ;; Turn right for 2 seconds (2000 ms).
+    (call $set_motors (i32.const 0) (i32.const 255))
+    (call $delay (i32.const 2000))
+
+    ;; Set the LED off.
+    (call $set_color (i32.const 0))
+    ;; Stop for half a second (500 ms).
+    (call $set_motors (i32.const 0) (i32.const 0))
+    (call $delay (i32.const 500))
+  )
+)